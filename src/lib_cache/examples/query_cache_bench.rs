@@ -0,0 +1,91 @@
+//! Multi-threaded QueryCache throughput.
+//!
+//!     cargo run --release --example query_cache_bench [threads...]
+//!
+//! Every thread runs the same mix of hits on a hot working set with a small
+//! share of writes, as the query cache sees from many Tokio workers.
+
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use lib_cache::{CacheScope, CachedResult, QueryCache, QueryCacheConfig, QueryKey};
+
+const KEYS: usize = 10_000;
+const ROWS: usize = 20;
+const ROW_BYTES: usize = 100;
+const OPS_PER_THREAD: usize = 500_000;
+/// One operation in this many is a write.
+const WRITE_EVERY: u64 = 20;
+
+fn key(i: usize) -> QueryKey {
+    QueryKey {
+        query: format!("select * from orders where customer_id = {}", i),
+        params: Vec::new(),
+        scope: CacheScope {
+            database: "postgres".to_string(),
+            role: "app".to_string(),
+            settings: Vec::new(),
+        },
+    }
+}
+
+fn result() -> CachedResult {
+    CachedResult {
+        row_description: Some(vec![0; 64]),
+        data_rows: vec![vec![7; ROW_BYTES]; ROWS],
+        command_tag: format!("SELECT {}", ROWS),
+    }
+}
+
+fn run(cache: &Arc<QueryCache>, keys: &Arc<Vec<QueryKey>>, threads: usize) -> f64 {
+    let started = Instant::now();
+    let workers: Vec<_> = (0..threads)
+        .map(|seed| {
+            let cache = Arc::clone(cache);
+            let keys = Arc::clone(keys);
+            thread::spawn(move || {
+                let mut state = 0x9E37_79B9_7F4A_7C15u64 ^ (seed as u64 + 1);
+                let mut hits = 0usize;
+                for _ in 0..OPS_PER_THREAD {
+                    // xorshift64
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    let key = &keys[state as usize % keys.len()];
+                    if state.is_multiple_of(WRITE_EVERY) {
+                        cache.set(key.clone(), result(), vec!["orders".to_string()]);
+                    } else if cache.get(key).is_some() {
+                        hits += 1;
+                    }
+                }
+                hits
+            })
+        })
+        .collect();
+    let hits: usize = workers.into_iter().map(|worker| worker.join().unwrap()).sum();
+    assert!(hits > 0);
+    (threads * OPS_PER_THREAD) as f64 / started.elapsed().as_secs_f64()
+}
+
+fn main() {
+    let threads: Vec<usize> = std::env::args().skip(1).filter_map(|arg| arg.parse().ok()).collect();
+    let threads = if threads.is_empty() { vec![1, 2, 4, 8, 16] } else { threads };
+    let keys: Arc<Vec<QueryKey>> = Arc::new((0..KEYS).map(key).collect());
+
+    println!("{} cores, {} keys, {} byte results", thread::available_parallelism().map_or(1, |n| n.get()), KEYS, result().size());
+    for shards in [1, QueryCacheConfig::default().shards] {
+        let cache = Arc::new(QueryCache::with_config(QueryCacheConfig {
+            ttl: Duration::from_secs(600),
+            shards,
+            ..QueryCacheConfig::default()
+        }));
+        for key in keys.iter() {
+            cache.set(key.clone(), result(), vec!["orders".to_string()]);
+        }
+
+        for &threads in &threads {
+            println!("{:>3} shards, {:>3} threads: {:>6.2} M ops/s", shards, threads, run(&cache, &keys, threads) / 1e6);
+        }
+    }
+}
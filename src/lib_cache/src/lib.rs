@@ -1,11 +1,46 @@
 // cache.rs
 use log;
-use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::BuildHasher;
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
 
+use lib_pgsqlcli::config::ConnectionConfig;
+use lib_pgsqlcli::{Connection, PostgresError};
+
+/// The backend a connection was authenticated against. Connections are only
+/// reused for the same host, database and user.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ConnectionKey {
+    /// `host:port` of the server.
+    pub host: String,
+    pub database: String,
+    pub user: String,
+}
+
+impl ConnectionKey {
+    pub fn from_config(config: &ConnectionConfig) -> Self {
+        ConnectionKey {
+            host: format!("{}:{}", config.host, config.port),
+            database: config.database.clone(),
+            user: config.user.clone(),
+        }
+    }
+}
+
+struct IdleConnection {
+    connection: Connection,
+    since: Instant,
+}
+
+/// Cache of authenticated backend connections. A checked out connection is
+/// removed from the cache, so it has exactly one user until it is checked in
+/// again; connections idle for longer than the TTL are closed.
 pub struct Cache {
-    cache: Arc<Mutex<HashMap<String, (std::net::TcpStream, Instant)>>>,
+    cache: Arc<Mutex<HashMap<ConnectionKey, Vec<IdleConnection>>>>,
     ttl: Duration,
 }
 
@@ -17,71 +52,933 @@ impl Cache {
         }
     }
 
-    pub fn get(&self, key: &str) -> Option<std::net::TcpStream> {
-        let cache = self.cache.lock().unwrap();
-        if let Some((conn, last_used)) = cache.get(key) {
-            if last_used.elapsed() < self.ttl {
-                return Some(conn.try_clone().unwrap());
+    /// Takes the most recently used idle connection for `key`, closing any
+    /// that outlived the TTL on the way.
+    pub fn checkout(&self, key: &ConnectionKey) -> Option<Connection> {
+        let mut cache = self.cache.lock().unwrap();
+        let idle = cache.get_mut(key)?;
+        while let Some(entry) = idle.pop() {
+            if entry.since.elapsed() < self.ttl {
+                return Some(entry.connection);
             }
+            log::debug!("Closing expired cached connection for {:?}", key);
         }
+        cache.remove(key);
         None
     }
 
-    pub fn set(&self, key: String, conn: std::net::TcpStream) {
+    /// Checks out a cached connection, or opens and authenticates a new one.
+    pub async fn checkout_or_connect(&self, config: &ConnectionConfig) -> Result<Connection, PostgresError> {
+        match self.checkout(&ConnectionKey::from_config(config)) {
+            Some(connection) => Ok(connection),
+            None => Connection::new(config).await,
+        }
+    }
+
+    /// Returns a connection to the cache. Only check in connections that are
+    /// idle between statements; drop connections that failed instead.
+    pub fn checkin(&self, key: ConnectionKey, connection: Connection) {
         let mut cache = self.cache.lock().unwrap();
-        cache.insert(key.clone(), (conn, Instant::now()));
-        log::info!("Cached connection for key {}", key);
+        cache.entry(key).or_default().push(IdleConnection {
+            connection,
+            since: Instant::now(),
+        });
+    }
+
+    /// Number of idle connections held for all keys.
+    pub fn idle_count(&self) -> usize {
+        self.cache.lock().unwrap().values().map(Vec::len).sum()
     }
 
     pub fn cleanup(&self) {
         let mut cache = self.cache.lock().unwrap();
-        cache.retain(|key, (_conn, last_used)| {
-            let retain = last_used.elapsed() < self.ttl;
-            if!retain {
-                log::info!("Cleaned up cached connection for key {}", key);
+        cache.retain(|key, idle| {
+            let before = idle.len();
+            idle.retain(|entry| entry.since.elapsed() < self.ttl);
+            if idle.len() < before {
+                log::info!("Cleaned up {} cached connections for {:?}", before - idle.len(), key);
+            }
+            !idle.is_empty()
+        });
+    }
+
+    /// Spawns a task evicting expired connections once per TTL.
+    pub fn start_cleanup(&self) -> JoinHandle<()> {
+        let cache = Cache {
+            cache: Arc::clone(&self.cache),
+            ttl: self.ttl,
+        };
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(cache.ttl.max(Duration::from_secs(1)));
+            loop {
+                interval.tick().await;
+                cache.cleanup();
+            }
+        })
+    }
+}
+
+/// A statement's result as the server sent it: the bodies of its
+/// RowDescription and DataRow messages and its CommandComplete tag. Column
+/// values stay in whatever format, text or binary, the server used.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CachedResult {
+    /// None for statements that return no rows.
+    pub row_description: Option<Vec<u8>>,
+    pub data_rows: Vec<Vec<u8>>,
+    pub command_tag: String,
+}
+
+impl CachedResult {
+    /// Collects the result of one statement from the backend messages answering
+    /// it. Returns None for responses that cannot be replayed from cache:
+    /// errors, COPY, several result sets, changed session parameters, or a portal
+    /// suspended before its last row.
+    pub fn from_messages<'a>(messages: impl IntoIterator<Item = (u8, &'a [u8])>) -> Option<Self> {
+        let mut result = CachedResult {
+            row_description: None,
+            data_rows: Vec::new(),
+            command_tag: String::new(),
+        };
+        let mut complete = false;
+
+        for (message_type, body) in messages {
+            match message_type {
+                b'T' if result.row_description.is_none() && !complete => result.row_description = Some(body.to_vec()),
+                b'D' if !complete => result.data_rows.push(body.to_vec()),
+                b'C' if !complete => {
+                    let end = body.iter().position(|b| *b == 0).unwrap_or(body.len());
+                    result.command_tag = String::from_utf8_lossy(&body[..end]).into_owned();
+                    complete = true;
+                }
+                // ParseComplete, BindComplete, NoData and notices carry no result data
+                b'1' | b'2' | b'n' | b'N' => {}
+                b'Z' => break,
+                _ => return None,
+            }
+        }
+
+        complete.then_some(result)
+    }
+
+    /// Appends the result to `buf` as RowDescription, DataRow and CommandComplete
+    /// messages, byte for byte as the server sent them. ReadyForQuery is left to
+    /// the caller.
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        let mut put = |message_type: u8, body: &[u8], terminated: bool| {
+            buf.push(message_type);
+            buf.extend_from_slice(&((body.len() + 4 + terminated as usize) as u32).to_be_bytes());
+            buf.extend_from_slice(body);
+            if terminated {
+                buf.push(0);
+            }
+        };
+
+        if let Some(description) = &self.row_description {
+            put(b'T', description, false);
+        }
+        for row in &self.data_rows {
+            put(b'D', row, false);
+        }
+        put(b'C', self.command_tag.as_bytes(), true);
+    }
+
+    /// Bytes of memory held by the result.
+    pub fn size(&self) -> usize {
+        self.row_description.as_ref().map_or(0, Vec::len)
+            + self.data_rows.iter().map(|row| row.len() + ROW_OVERHEAD).sum::<usize>()
+            + self.command_tag.len()
+    }
+}
+
+/// What besides the data decides which rows a query returns: the database, the
+/// role it runs as and the session settings in effect.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct CacheScope {
+    pub database: String,
+    pub role: String,
+    /// Settings changed from their defaults, sorted by name.
+    pub settings: Vec<(String, String)>,
+}
+
+/// A query cache key. The whole key is stored with each entry, so results are
+/// only shared between identical queries in identical scopes.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct QueryKey {
+    /// Canonical query text.
+    pub query: String,
+    /// Bound parameter values.
+    pub params: Vec<String>,
+    pub scope: CacheScope,
+}
+
+impl QueryKey {
+    fn size(&self) -> usize {
+        self.query.len()
+            + self.params.iter().map(String::len).sum::<usize>()
+            + self.scope.database.len()
+            + self.scope.role.len()
+            + self.scope.settings.iter().map(|(name, value)| name.len() + value.len()).sum::<usize>()
+    }
+}
+
+const DEFAULT_MAX_BYTES: usize = 64 * 1024 * 1024;
+const DEFAULT_MAX_ENTRY_BYTES: usize = 1024 * 1024;
+/// Allocation overhead counted per entry and per row on top of their data.
+const ENTRY_OVERHEAD: usize = 256;
+const ROW_OVERHEAD: usize = 24;
+
+/// How long an entry is served: fresh for `ttl`, then for `stale_ttl` more as
+/// a stale result while a background query refreshes it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Expiry {
+    pub ttl: Duration,
+    pub stale_ttl: Duration,
+}
+
+impl Expiry {
+    fn lifetime(&self) -> Duration {
+        self.ttl.saturating_add(self.stale_ttl)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct QueryCacheConfig {
+    /// Expiry of entries cached without one of their own.
+    pub ttl: Duration,
+    /// Memory budget for all entries; least recently used entries are evicted
+    /// to stay within it.
+    pub max_bytes: usize,
+    /// Larger results are not cached at all.
+    pub max_entry_bytes: usize,
+    /// Number of independently locked shards. Each gets an equal share of
+    /// `max_bytes`, so fewer are used when a share could not hold a result of
+    /// `max_entry_bytes`.
+    pub shards: usize,
+}
+
+impl Default for QueryCacheConfig {
+    fn default() -> Self {
+        QueryCacheConfig {
+            ttl: Duration::from_secs(600),
+            max_bytes: DEFAULT_MAX_BYTES,
+            max_entry_bytes: DEFAULT_MAX_ENTRY_BYTES,
+            shards: default_shards(),
+        }
+    }
+}
+
+/// Four shards per available core.
+fn default_shards() -> usize {
+    std::thread::available_parallelism().map_or(1, |cores| cores.get()) * 4
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct QueryCacheStats {
+    pub entries: usize,
+    pub bytes: usize,
+    pub max_bytes: usize,
+    /// Entries evicted to make room for new ones.
+    pub evictions: u64,
+    /// Results too large to cache.
+    pub rejected: u64,
+    pub expired: u64,
+    /// Lookups served by a fill another caller was already running.
+    pub coalesced: u64,
+    /// Lookups served a stale result while it was refreshed.
+    pub stale: u64,
+}
+
+struct CacheEntry {
+    result: Arc<CachedResult>,
+    cached_at: Instant,
+    expiry: Expiry,
+    /// Tables the query read, whose writes invalidate the entry.
+    tables: Vec<String>,
+    size: usize,
+    last_used: u64,
+}
+
+enum Found {
+    Fresh(Arc<CachedResult>),
+    /// Past its TTL but within its stale window.
+    Stale(Arc<CachedResult>),
+}
+
+#[derive(Clone)]
+enum FillState {
+    Running,
+    Done(Arc<CachedResult>),
+    Uncacheable,
+}
+
+/// A query being run to fill a missing entry, which other lookups of the same
+/// key wait for instead of running it again.
+struct InFlight {
+    id: u64,
+    tables: Vec<String>,
+    state: watch::Receiver<FillState>,
+}
+
+/// One lock stripe of the cache, with its own share of the memory budget and
+/// its own LRU order.
+#[derive(Default)]
+struct Shard {
+    map: HashMap<QueryKey, CacheEntry>,
+    /// Keys by last use, least recent first.
+    lru: BTreeMap<u64, QueryKey>,
+    in_flight: HashMap<QueryKey, InFlight>,
+    bytes: usize,
+    clock: u64,
+    evictions: u64,
+    rejected: u64,
+    expired: u64,
+    coalesced: u64,
+    stale: u64,
+}
+
+impl Shard {
+    fn remove(&mut self, key: &QueryKey) -> Option<CacheEntry> {
+        let entry = self.map.remove(key)?;
+        self.lru.remove(&entry.last_used);
+        self.bytes -= entry.size;
+        Some(entry)
+    }
+
+    fn retain(&mut self, keep: impl Fn(&QueryKey, &CacheEntry) -> bool) -> usize {
+        let before = self.map.len();
+        let (lru, bytes) = (&mut self.lru, &mut self.bytes);
+        self.map.retain(|key, entry| {
+            if keep(key, entry) {
+                return true;
             }
-            retain
+            lru.remove(&entry.last_used);
+            *bytes -= entry.size;
+            false
+        });
+        before - self.map.len()
+    }
+
+    /// The result for `key` unless it is past its stale window, marked as the
+    /// most recently used entry.
+    fn find(&mut self, key: &QueryKey) -> Option<Found> {
+        let entry = self.map.get(key)?;
+        let age = entry.cached_at.elapsed();
+        if age >= entry.expiry.lifetime() {
+            self.remove(key);
+            self.expired += 1;
+            return None;
+        }
+
+        let entry = self.map.get_mut(key)?;
+        self.clock += 1;
+        if let Some(key) = self.lru.remove(&entry.last_used) {
+            self.lru.insert(self.clock, key);
+        }
+        entry.last_used = self.clock;
+        let result = Arc::clone(&entry.result);
+        Some(if age < entry.expiry.ttl { Found::Fresh(result) } else { Found::Stale(result) })
+    }
+
+    /// Registers a fill of `key` for other lookups to wait on.
+    fn start_fill(&mut self, key: &QueryKey, tables: &[String]) -> (u64, watch::Sender<FillState>) {
+        self.clock += 1;
+        let (sender, state) = watch::channel(FillState::Running);
+        self.in_flight.insert(key.clone(), InFlight {
+            id: self.clock,
+            tables: tables.to_vec(),
+            state,
+        });
+        (self.clock, sender)
+    }
+
+    /// Admits `result` if it fits `limits`, evicting least recently used
+    /// entries to make room.
+    fn insert(&mut self, key: QueryKey, result: Arc<CachedResult>, tables: Vec<String>, expiry: Expiry, limits: Limits) -> bool {
+        let size = ENTRY_OVERHEAD + key.size() + result.size() + tables.iter().map(String::len).sum::<usize>();
+        self.remove(&key);
+        if size > limits.max_entry_bytes.min(limits.budget) {
+            self.rejected += 1;
+            log::debug!("Not caching a {} byte result in database {}", size, key.scope.database);
+            return false;
+        }
+
+        while self.bytes + size > limits.budget {
+            let oldest = match self.lru.first_key_value() {
+                Some((_, oldest)) => oldest.clone(),
+                None => break,
+            };
+            self.remove(&oldest);
+            self.evictions += 1;
+        }
+
+        log::debug!("Cached query result in database {} for role {}", key.scope.database, key.scope.role);
+        self.clock += 1;
+        let last_used = self.clock;
+        self.lru.insert(last_used, key.clone());
+        self.bytes += size;
+        self.map.insert(key, CacheEntry {
+            result,
+            cached_at: Instant::now(),
+            expiry,
+            tables,
+            size,
+            last_used,
         });
+        true
+    }
+
+    /// Ends the fill `id` of `key` and returns whether it is still current,
+    /// that is no invalidation of its key happened while it ran.
+    fn finish(&mut self, key: &QueryKey, id: u64) -> bool {
+        match self.in_flight.get(key) {
+            Some(fill) if fill.id == id => {
+                self.in_flight.remove(key);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Limits {
+    max_entry_bytes: usize,
+    /// Bytes available to one shard.
+    budget: usize,
+}
+
+/// Outcome of `QueryCache::lookup`.
+pub enum Lookup {
+    Hit(Arc<CachedResult>),
+    /// The caller runs the query and completes the fill; other lookups of the
+    /// key wait for it meanwhile.
+    Fill(Fill),
+    /// The query ran for another lookup but its result could not be cached:
+    /// the caller runs it itself.
+    Miss,
+    /// An expired result still within its stale window. `refresh` is set for
+    /// the one lookup that should refresh it, without keeping the caller waiting.
+    Stale {
+        result: Arc<CachedResult>,
+        refresh: Option<Fill>,
+    },
+}
+
+/// The right, and duty, to run a query and publish its result. Dropping the
+/// fill without completing it, as when the caller's task is cancelled, hands
+/// the fill over to one of the waiting lookups.
+pub struct Fill {
+    shards: Arc<[Mutex<Shard>]>,
+    shard: usize,
+    key: QueryKey,
+    id: u64,
+    tables: Vec<String>,
+    expiry: Expiry,
+    limits: Limits,
+    state: Option<watch::Sender<FillState>>,
+}
+
+impl Fill {
+    pub fn key(&self) -> &QueryKey {
+        &self.key
+    }
+
+    /// Caches `result`, unless the key was invalidated while the query ran,
+    /// and hands it to the waiting lookups.
+    pub fn complete(mut self, result: CachedResult) -> Arc<CachedResult> {
+        let result = Arc::new(result);
+        {
+            let mut shard = self.shards[self.shard].lock().unwrap();
+            if shard.finish(&self.key, self.id) {
+                let tables = std::mem::take(&mut self.tables);
+                shard.insert(self.key.clone(), Arc::clone(&result), tables, self.expiry, self.limits);
+            }
+        }
+        if let Some(state) = self.state.take() {
+            state.send_replace(FillState::Done(Arc::clone(&result)));
+        }
+        result
+    }
+
+    /// Gives up on caching, for results that cannot be cached such as errors:
+    /// the waiting lookups run the query themselves.
+    pub fn abandon(mut self) {
+        self.shards[self.shard].lock().unwrap().finish(&self.key, self.id);
+        if let Some(state) = self.state.take() {
+            state.send_replace(FillState::Uncacheable);
+        }
     }
 }
 
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
+impl Drop for Fill {
+    fn drop(&mut self) {
+        if self.state.is_some() {
+            self.shards[self.shard].lock().unwrap().finish(&self.key, self.id);
+        }
+    }
+}
 
+/// Cache of query results, bounded in memory: results over the per-entry
+/// limit are never admitted and the least recently used entries are evicted
+/// once the budget is reached.
+///
+/// Entries are spread over independently locked shards by key hash, and
+/// results are handed out as shared `Arc`s rather than copies. Eviction is
+/// LRU within each shard, which holds an equal share of the budget.
+///
+/// Entries with a stale window keep being served once their TTL has passed
+/// while one lookup refreshes them. Invalidated entries are removed at once and
+/// never served stale.
 pub struct QueryCache {
-    cache: Arc<Mutex<HashMap<u64, (String, Instant)>>>,
-    ttl: Duration,
+    shards: Arc<[Mutex<Shard>]>,
+    hasher: RandomState,
+    config: QueryCacheConfig,
 }
 
 impl QueryCache {
     pub fn new(ttl: Duration) -> Self {
+        Self::with_config(QueryCacheConfig { ttl, ..QueryCacheConfig::default() })
+    }
+
+    pub fn with_config(config: QueryCacheConfig) -> Self {
+        let fitting = (config.max_bytes / config.max_entry_bytes.max(1)).max(1);
+        let shards = config.shards.clamp(1, fitting);
+        if shards < config.shards {
+            log::info!(
+                "Using {} query cache shards instead of {} so each can hold a {} byte result",
+                shards,
+                config.shards,
+                config.max_entry_bytes
+            );
+        }
         QueryCache {
-            cache: Arc::new(Mutex::new(HashMap::new())),
-            ttl,
+            shards: (0..shards).map(|_| Mutex::new(Shard::default())).collect(),
+            hasher: RandomState::new(),
+            config,
+        }
+    }
+
+    fn shard_index(&self, key: &QueryKey) -> usize {
+        self.hasher.hash_one(key) as usize % self.shards.len()
+    }
+
+    fn limits(&self) -> Limits {
+        Limits {
+            max_entry_bytes: self.config.max_entry_bytes,
+            budget: self.config.max_bytes / self.shards.len(),
+        }
+    }
+
+    /// Expiry of entries cached without one of their own.
+    pub fn default_expiry(&self) -> Expiry {
+        Expiry {
+            ttl: self.config.ttl,
+            stale_ttl: Duration::ZERO,
+        }
+    }
+
+    /// The fresh result for `key`, if any.
+    pub fn get(&self, key: &QueryKey) -> Option<Arc<CachedResult>> {
+        match self.shards[self.shard_index(key)].lock().unwrap().find(key)? {
+            Found::Fresh(result) => Some(result),
+            Found::Stale(_) => None,
         }
     }
 
-    pub fn get(&self, query: &str) -> Option<String> {
-        let cache = self.cache.lock().unwrap();
-        let key = self.hash(query);
-        if let Some((result, last_used)) = cache.get(&key) {
-            if last_used.elapsed() < self.ttl {
-                return Some(result.clone());
+    /// Looks `key` up, coalescing concurrent misses: the first lookup to miss
+    /// gets the `Fill` and runs the query, later ones wait for its result.
+    /// `tables` are the tables the query reads and `expiry` applies to the
+    /// result a fill caches.
+    pub async fn lookup(&self, key: &QueryKey, tables: &[String], expiry: Expiry) -> Lookup {
+        let index = self.shard_index(key);
+        let fill = |id, sender| Fill {
+            shards: Arc::clone(&self.shards),
+            shard: index,
+            key: key.clone(),
+            id,
+            tables: tables.to_vec(),
+            expiry,
+            limits: self.limits(),
+            state: Some(sender),
+        };
+
+        loop {
+            let mut state = {
+                let mut shard = self.shards[index].lock().unwrap();
+                match shard.find(key) {
+                    Some(Found::Fresh(result)) => return Lookup::Hit(result),
+                    Some(Found::Stale(result)) => {
+                        shard.stale += 1;
+                        let refresh = if shard.in_flight.contains_key(key) {
+                            None
+                        } else {
+                            let (id, sender) = shard.start_fill(key, tables);
+                            Some(fill(id, sender))
+                        };
+                        return Lookup::Stale { result, refresh };
+                    }
+                    None => {}
+                }
+                match shard.in_flight.get(key) {
+                    Some(in_flight) => in_flight.state.clone(),
+                    None => {
+                        let (id, sender) = shard.start_fill(key, tables);
+                        return Lookup::Fill(fill(id, sender));
+                    }
+                }
+            };
+
+            loop {
+                let current = state.borrow_and_update().clone();
+                match current {
+                    FillState::Done(result) => {
+                        self.shards[index].lock().unwrap().coalesced += 1;
+                        return Lookup::Hit(result);
+                    }
+                    FillState::Uncacheable => return Lookup::Miss,
+                    FillState::Running => {}
+                }
+                if state.changed().await.is_err() {
+                    // The fill was dropped unfinished; race for a new one
+                    break;
+                }
             }
         }
-        None
     }
 
-    pub fn set(&self, query: &str, result: String) {
-        let mut cache = self.cache.lock().unwrap();
-        let key = self.hash(query);
-        cache.insert(key, (result, Instant::now()));
-        log::info!("Cached query result for key {}", key);
+    /// Caches `result` for `key` and returns whether it was admitted. `tables`
+    /// are the tables the query read; an entry without any is only ever
+    /// expired by its TTL.
+    pub fn set(&self, key: QueryKey, result: CachedResult, tables: Vec<String>) -> bool {
+        let (expiry, limits) = (self.default_expiry(), self.limits());
+        self.shards[self.shard_index(&key)]
+            .lock()
+            .unwrap()
+            .insert(key, Arc::new(result), tables, expiry, limits)
+    }
+
+    /// Drops the entries `keep` rejects from every shard, one shard at a time.
+    /// Fills in flight for those keys are forgotten, so their results are not
+    /// cached and later lookups run the query again.
+    fn invalidate(&self, keep: impl Fn(&QueryKey, &[String]) -> bool) -> usize {
+        let mut dropped = 0;
+        for shard in self.shards.iter() {
+            let mut shard = shard.lock().unwrap();
+            dropped += shard.retain(|key, entry| keep(key, &entry.tables));
+            shard.in_flight.retain(|key, fill| keep(key, &fill.tables));
+        }
+        dropped
+    }
+
+    /// Drops every entry of `database` that read from one of `tables` and
+    /// returns how many were dropped. Call once writes to those tables have committed.
+    pub fn invalidate_tables(&self, database: &str, tables: &[String]) -> usize {
+        if tables.is_empty() {
+            return 0;
+        }
+        let dropped = self.invalidate(|key, read| {
+            key.scope.database != database || !read.iter().any(|table| tables.contains(table))
+        });
+        if dropped > 0 {
+            log::debug!("Invalidated {} cached results reading {} in {}", dropped, tables.join(", "), database);
+        }
+        dropped
+    }
+
+    /// Drops the entries of `database` for the canonical query text `query`,
+    /// whatever their parameters and role.
+    pub fn invalidate_query(&self, database: &str, query: &str) -> usize {
+        self.invalidate(|key, _| key.scope.database != database || key.query != query)
+    }
+
+    /// Drops every entry of `database`.
+    pub fn invalidate_database(&self, database: &str) -> usize {
+        self.invalidate(|key, _| key.scope.database != database)
+    }
+
+    /// Drops entries past their stale window.
+    pub fn cleanup(&self) {
+        let mut expired = 0;
+        for shard in self.shards.iter() {
+            let mut shard = shard.lock().unwrap();
+            let dropped = shard.retain(|_, entry| entry.cached_at.elapsed() < entry.expiry.lifetime());
+            shard.expired += dropped as u64;
+            expired += dropped;
+        }
+        if expired > 0 {
+            log::info!("Cleaned up {} expired cached query results", expired);
+        }
+    }
+
+    /// Spawns a task sweeping expired entries once per TTL.
+    pub fn start_cleanup(&self) -> JoinHandle<()> {
+        let cache = QueryCache {
+            shards: Arc::clone(&self.shards),
+            hasher: self.hasher.clone(),
+            config: self.config.clone(),
+        };
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(cache.config.ttl.max(Duration::from_secs(1)));
+            loop {
+                interval.tick().await;
+                cache.cleanup();
+            }
+        })
     }
 
-    fn hash<T: Hash>(&self, t: T) -> u64 {
-        let mut s = DefaultHasher::new();
-        t.hash(&mut s);
-        s.finish()
+    pub fn stats(&self) -> QueryCacheStats {
+        let mut stats = QueryCacheStats {
+            max_bytes: self.config.max_bytes,
+            ..QueryCacheStats::default()
+        };
+        for shard in self.shards.iter() {
+            let shard = shard.lock().unwrap();
+            stats.entries += shard.map.len();
+            stats.bytes += shard.bytes;
+            stats.evictions += shard.evictions;
+            stats.rejected += shard.rejected;
+            stats.expired += shard.expired;
+            stats.coalesced += shard.coalesced;
+            stats.stale += shard.stale;
+        }
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(query: &str) -> QueryKey {
+        QueryKey {
+            query: query.to_string(),
+            params: Vec::new(),
+            scope: CacheScope::default(),
+        }
+    }
+
+    fn result(bytes: usize) -> CachedResult {
+        CachedResult {
+            row_description: None,
+            data_rows: vec![vec![b'x'; bytes]],
+            command_tag: "SELECT 1".to_string(),
+        }
+    }
+
+    /// Splits encoded messages back into their types and bodies.
+    fn split(mut buf: &[u8]) -> Vec<(u8, Vec<u8>)> {
+        let mut messages = Vec::new();
+        while !buf.is_empty() {
+            let length = u32::from_be_bytes(buf[1..5].try_into().unwrap()) as usize;
+            messages.push((buf[0], buf[5..1 + length].to_vec()));
+            buf = &buf[1 + length..];
+        }
+        messages
+    }
+
+    #[test]
+    fn results_round_trip_through_messages() {
+        let result = CachedResult {
+            row_description: Some(b"\x00\x01id\x00".to_vec()),
+            data_rows: vec![b"\x00\x01\x00\x00\x00\x011".to_vec(), b"\x00\x01\xff\xff\xff\xff".to_vec()],
+            command_tag: "SELECT 2".to_string(),
+        };
+        let mut buf = Vec::new();
+        result.encode(&mut buf);
+        let messages = split(&buf);
+        assert_eq!(messages.iter().map(|(message_type, _)| *message_type).collect::<Vec<_>>(), b"TDDC");
+        assert_eq!(messages[3].1, b"SELECT 2\x00");
+        let bodies = messages.iter().map(|(message_type, body)| (*message_type, &body[..]));
+        assert_eq!(CachedResult::from_messages(bodies), Some(result));
+
+        // Statements without rows, with protocol chatter around them
+        let parsed = CachedResult::from_messages([
+            (b'1', &b""[..]),
+            (b'2', &b""[..]),
+            (b'n', &b""[..]),
+            (b'C', &b"UPDATE 0\x00"[..]),
+            (b'Z', &b"I"[..]),
+            (b'E', &b""[..]),
+        ]);
+        let parsed = parsed.unwrap();
+        assert_eq!(parsed.row_description, None);
+        assert_eq!(parsed.command_tag, "UPDATE 0");
+    }
+
+    #[test]
+    fn results_that_cannot_be_replayed() {
+        let description = (b'T', &b"\x00\x00"[..]);
+        let complete = (b'C', &b"SELECT 0\x00"[..]);
+        // An error, no completion, a portal suspended, changed settings, COPY
+        assert!(CachedResult::from_messages([description, (b'E', &b"SERROR\x00\x00"[..])]).is_none());
+        assert!(CachedResult::from_messages([description]).is_none());
+        assert!(CachedResult::from_messages([description, (b's', &b""[..])]).is_none());
+        assert!(CachedResult::from_messages([(b'S', &b"TimeZone\x00UTC\x00"[..]), complete]).is_none());
+        assert!(CachedResult::from_messages([(b'H', &b"\x00\x00\x00"[..])]).is_none());
+        // A second result set
+        assert!(CachedResult::from_messages([description, complete, description, complete]).is_none());
+    }
+
+    #[test]
+    fn shards_hold_the_largest_entry() {
+        let cache = QueryCache::with_config(QueryCacheConfig {
+            max_bytes: 1000,
+            max_entry_bytes: 400,
+            shards: 16,
+            ..QueryCacheConfig::default()
+        });
+        assert_eq!(cache.shards.len(), 2);
+
+        assert!(cache.set(key("SELECT a"), result(100), Vec::new()));
+        assert_eq!(cache.get(&key("SELECT a")), Some(Arc::new(result(100))));
+        assert!(!cache.set(key("SELECT b"), result(400), Vec::new()));
+        assert_eq!(cache.stats().rejected, 1);
+    }
+
+    /// A one-shard cache with room for `entries` results of `result(100)` under `key("SELECT n")`.
+    fn small_cache(entries: usize) -> QueryCache {
+        let size = ENTRY_OVERHEAD + key("SELECT 0").size() + result(100).size();
+        QueryCache::with_config(QueryCacheConfig {
+            max_bytes: size * entries,
+            max_entry_bytes: size,
+            shards: 1,
+            ..QueryCacheConfig::default()
+        })
+    }
+
+    #[test]
+    fn least_recently_used_entries_are_evicted_first() {
+        let cache = small_cache(3);
+        for n in 0..3 {
+            assert!(cache.set(key(&format!("SELECT {}", n)), result(100), Vec::new()));
+        }
+        // Using the oldest entry makes the second one the least recently used
+        assert!(cache.get(&key("SELECT 0")).is_some());
+        assert!(cache.set(key("SELECT 3"), result(100), Vec::new()));
+
+        assert!(cache.get(&key("SELECT 1")).is_none());
+        for n in [0, 2, 3] {
+            assert!(cache.get(&key(&format!("SELECT {}", n))).is_some(), "SELECT {}", n);
+        }
+        assert_eq!(cache.stats().evictions, 1);
+    }
+
+    #[test]
+    fn entries_stay_within_the_budget() {
+        let cache = QueryCache::with_config(QueryCacheConfig {
+            max_bytes: 10_000,
+            max_entry_bytes: 2_000,
+            shards: 4,
+            ..QueryCacheConfig::default()
+        });
+        for n in 0..200 {
+            cache.set(key(&format!("SELECT {}", n)), result(n * 13 % 2500), Vec::new());
+            let stats = cache.stats();
+            assert!(stats.bytes <= stats.max_bytes, "{} bytes after {} results", stats.bytes, n + 1);
+        }
+        let stats = cache.stats();
+        assert!(stats.entries > 0);
+        assert!(stats.evictions > 0);
+        assert!(stats.rejected > 0);
+    }
+
+    #[tokio::test]
+    async fn the_sweeper_removes_expired_entries() {
+        let cache = QueryCache::new(Duration::from_millis(20));
+        let stale = Expiry {
+            ttl: Duration::from_millis(20),
+            stale_ttl: Duration::from_secs(60),
+        };
+        cache.set(key("SELECT 1"), result(10), Vec::new());
+        match cache.lookup(&key("SELECT 2"), &[], stale).await {
+            Lookup::Fill(fill) => drop(fill.complete(result(10))),
+            _ => panic!("the first lookup fills the entry"),
+        }
+        let sweeper = cache.start_cleanup();
+
+        // The sweeper runs once a second at the most frequent
+        tokio::time::sleep(Duration::from_millis(1200)).await;
+        sweeper.abort();
+        let stats = cache.stats();
+        assert_eq!(stats.entries, 1);
+        assert_eq!(stats.expired, 1);
+        // Entries within their stale window are kept
+        assert!(matches!(
+            cache.lookup(&key("SELECT 2"), &[], stale).await,
+            Lookup::Stale { .. }
+        ));
+    }
+
+    #[test]
+    fn lifetimes_saturate() {
+        let expiry = Expiry {
+            ttl: Duration::MAX,
+            stale_ttl: Duration::from_secs(1),
+        };
+        assert_eq!(expiry.lifetime(), Duration::MAX);
+    }
+
+    #[tokio::test]
+    async fn stale_results_are_refreshed_once() {
+        let cache = QueryCache::new(Duration::from_secs(60));
+        let expiry = Expiry {
+            ttl: Duration::from_millis(20),
+            stale_ttl: Duration::from_secs(60),
+        };
+        let tables = vec!["orders".to_string()];
+        let key = key("SELECT * FROM orders");
+
+        match cache.lookup(&key, &tables, expiry).await {
+            Lookup::Fill(fill) => drop(fill.complete(result(10))),
+            _ => panic!("the first lookup fills the entry"),
+        }
+        tokio::time::sleep(Duration::from_millis(40)).await;
+
+        let refresh = match cache.lookup(&key, &tables, expiry).await {
+            Lookup::Stale { result: stale, refresh: Some(refresh) } => {
+                assert_eq!(*stale, result(10));
+                refresh
+            }
+            _ => panic!("the first stale lookup refreshes the entry"),
+        };
+        // Others keep getting the stale result while the refresh runs
+        assert!(matches!(
+            cache.lookup(&key, &tables, expiry).await,
+            Lookup::Stale { refresh: None, .. }
+        ));
+        assert!(cache.get(&key).is_none());
+
+        refresh.complete(result(20));
+        match cache.lookup(&key, &tables, expiry).await {
+            Lookup::Hit(fresh) => assert_eq!(*fresh, result(20)),
+            _ => panic!("a completed refresh is served fresh"),
+        }
+        assert_eq!(cache.stats().stale, 2);
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn abandoned_refreshes_are_taken_over() {
+        let cache = QueryCache::new(Duration::from_secs(60));
+        let expiry = Expiry {
+            ttl: Duration::from_millis(20),
+            stale_ttl: Duration::from_secs(60),
+        };
+        let key = key("SELECT * FROM orders");
+        match cache.lookup(&key, &[], expiry).await {
+            Lookup::Fill(fill) => drop(fill.complete(result(10))),
+            _ => panic!("the first lookup fills the entry"),
+        }
+        tokio::time::sleep(Duration::from_millis(40)).await;
+
+        match cache.lookup(&key, &[], expiry).await {
+            Lookup::Stale { refresh: Some(refresh), .. } => refresh.abandon(),
+            _ => panic!("the first stale lookup refreshes the entry"),
+        }
+        // A failed refresh leaves the stale result for the next lookup to refresh
+        assert!(matches!(
+            cache.lookup(&key, &[], expiry).await,
+            Lookup::Stale { refresh: Some(_), .. }
+        ));
+    }
+}
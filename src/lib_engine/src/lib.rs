@@ -1,6 +1,7 @@
 extern crate lib_logger;
 extern crate lib_config;
 extern crate lib_pool;
+extern crate lib_router;
 extern crate lib_cache;
 extern crate syslog;
 extern crate lib_pgsqlcli;
@@ -8,7 +9,7 @@ extern crate lib_pgsqlcli;
 use syslog::Facility;
 use lib_logger::{LoggerConfig, init_logger};
 use lib_config::Config;
-use lib_pool::Pool;
+use lib_router::Router;
 use lib_cache::Cache;
 use lib_pgsqlcli::PostgresError;
 
@@ -16,7 +17,7 @@ use std::time::Duration;
 
 pub struct Engine {
     config: Config,
-    pool: Pool,
+    router: Router,
     cache: Cache,
 }
 
@@ -38,24 +39,26 @@ impl Engine {
         // Initialize config
         let config = Config::from_file("path/to/config/file")?;
 
-        // Initialize pool
-        let connection_string = format!(
-            "postgresql://{}:{}@{}/{}",
-            config.db_user, config.db_password, config.db_host, config.db_name
-        );
-        let pool = Pool::new(&connection_string, 100).await?;
+        // Initialize one pool per PostgreSQL host behind the router
+        let router = Router::new(&config).await?;
 
         // Initialize cache
         let cache = Cache::new(Duration::from_secs(config.cache_ttl));
 
         Ok(Engine {
             config,
-            pool,
+            router,
             cache,
         })
     }
 
     pub fn start(&self) {
+        self.router.start_health_checks();
+        self.router.start_discovery();
+        self.router.start_mirroring();
+        self.router.start_cache_invalidation();
+        self.cache.start_cleanup();
+        self.router.query_cache().start_cleanup();
         log::info!("pgShield engine started");
     }
 }
\ No newline at end of file
@@ -1,4 +1,6 @@
-use crate::connection::Connection;
+use bytes::BytesMut;
+
+use crate::connection::{Connection, Notification, QueryResult, Row, TransactionStatus};
 use crate::config::ConnectionConfig;
 use crate::error::PostgresError;
 
@@ -13,5 +15,42 @@ impl PostgresClient {
         Ok(Self { connection })
     }
 
-    
+    pub async fn simple_query(&mut self, sql: &str) -> Result<QueryResult, PostgresError> {
+        self.connection.simple_query(sql).await
+    }
+
+    /// Raw response messages of `sql`, for callers relaying them to a client.
+    pub async fn simple_query_messages(&mut self, sql: &str) -> Result<Vec<(u8, BytesMut)>, PostgresError> {
+        self.connection.simple_query_messages(sql).await
+    }
+
+    pub fn transaction_status(&self) -> TransactionStatus {
+        self.connection.transaction_status()
+    }
+
+    /// Subscribes the connection to NOTIFY on `channel`.
+    pub async fn listen(&mut self, channel: &str) -> Result<(), PostgresError> {
+        let sql = format!("LISTEN \"{}\"", channel.replace('"', "\"\""));
+        self.connection.simple_query(&sql).await?;
+        Ok(())
+    }
+
+    /// Waits for the next notification on a channel passed to `listen`.
+    pub async fn notification(&mut self) -> Result<Notification, PostgresError> {
+        self.connection.wait_for_notification().await
+    }
+
+    pub async fn query(&mut self, sql: &str) -> Result<Vec<Row>, PostgresError> {
+        Ok(self.connection.simple_query(sql).await?.rows)
+    }
+
+    /// Runs a statement and returns the row count reported in its command tag.
+    pub async fn execute(&mut self, sql: &str) -> Result<u64, PostgresError> {
+        let result = self.connection.simple_query(sql).await?;
+        Ok(result.command_tag
+            .rsplit(' ')
+            .next()
+            .and_then(|count| count.parse().ok())
+            .unwrap_or(0))
+    }
 }
\ No newline at end of file
@@ -10,29 +10,184 @@ use crate::auth::{handle_authentication, AuthMethod};
 
 const PROTOCOL_VERSION: i32 = 196608; // 3.0
 
-pub enum Connection {
+const BOOL_OID: u32 = 16;
+const BYTEA_OID: u32 = 17;
+const INT8_OID: u32 = 20;
+const INT2_OID: u32 = 21;
+const INT4_OID: u32 = 23;
+const FLOAT4_OID: u32 = 700;
+const FLOAT8_OID: u32 = 701;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PostgresValue {
+    Null,
+    Boolean(bool),
+    Int16(i16),
+    Int32(i32),
+    Int64(i64),
+    Float32(f32),
+    Float64(f64),
+    String(String),
+    Bytes(Vec<u8>),
+}
+
+impl PostgresValue {
+    /// Decodes a text-format column value using the column's type OID, falling
+    /// back to a plain string for types without a dedicated variant.
+    fn from_text(type_oid: u32, text: &str) -> Self {
+        let parsed = match type_oid {
+            BOOL_OID => Some(PostgresValue::Boolean(text == "t")),
+            INT2_OID => text.parse().ok().map(PostgresValue::Int16),
+            INT4_OID => text.parse().ok().map(PostgresValue::Int32),
+            INT8_OID => text.parse().ok().map(PostgresValue::Int64),
+            FLOAT4_OID => text.parse().ok().map(PostgresValue::Float32),
+            FLOAT8_OID => text.parse().ok().map(PostgresValue::Float64),
+            BYTEA_OID => text.strip_prefix("\\x").and_then(|h| hex::decode(h).ok()).map(PostgresValue::Bytes),
+            _ => None,
+        };
+        parsed.unwrap_or_else(|| PostgresValue::String(text.to_string()))
+    }
+
+    /// Text format of the value, as the server sends it; None for NULL.
+    fn to_text(&self) -> Option<String> {
+        let float = |f: f64| match f {
+            f if f == f64::INFINITY => "Infinity".to_string(),
+            f if f == f64::NEG_INFINITY => "-Infinity".to_string(),
+            f => f.to_string(),
+        };
+        Some(match self {
+            PostgresValue::Null => return None,
+            PostgresValue::Boolean(b) => if *b { "t" } else { "f" }.to_string(),
+            PostgresValue::Int16(i) => i.to_string(),
+            PostgresValue::Int32(i) => i.to_string(),
+            PostgresValue::Int64(i) => i.to_string(),
+            PostgresValue::Float32(f) => float(*f as f64),
+            PostgresValue::Float64(f) => float(*f),
+            PostgresValue::String(s) => s.clone(),
+            PostgresValue::Bytes(b) => format!("\\x{}", hex::encode(b)),
+        })
+    }
+}
+
+pub type Row = Vec<(String, PostgresValue)>;
+
+pub struct QueryResult {
+    /// Name and type OID of each column of the result set.
+    pub columns: Vec<(String, u32)>,
+    pub rows: Vec<Row>,
+    pub command_tag: String,
+}
+
+impl QueryResult {
+    /// Collects the rows of the last result set in the response messages of a
+    /// simple query, as returned by `simple_query_messages`.
+    pub fn from_messages<'a>(messages: impl IntoIterator<Item = (u8, &'a [u8])>) -> Result<Self, PostgresError> {
+        let mut result = QueryResult { columns: Vec::new(), rows: Vec::new(), command_tag: String::new() };
+        for (message_type, data) in messages {
+            let mut data = BytesMut::from(data);
+            match message_type {
+                b'T' => {
+                    result.columns = parse_row_description(&mut data)?;
+                    result.rows.clear();
+                }
+                b'D' => result.rows.push(parse_data_row(&mut data, &result.columns)?),
+                b'C' => result.command_tag = read_cstr(&mut data)?,
+                b'E' => return Err(parse_error_response(&data)),
+                _ => {} // EmptyQueryResponse, NoticeResponse, ParameterStatus, NotificationResponse
+            }
+        }
+        Ok(result)
+    }
+
+    /// Response messages for the result in text format, the inverse of
+    /// `from_messages`, for results pgShield assembled itself.
+    pub fn to_messages(&self) -> Vec<(u8, BytesMut)> {
+        let mut messages = Vec::with_capacity(self.rows.len() + 2);
+        if !self.columns.is_empty() {
+            let mut description = BytesMut::new();
+            description.put_i16(self.columns.len() as i16);
+            for (name, type_oid) in &self.columns {
+                description.put_slice(name.as_bytes());
+                description.put_u8(0);
+                // No table or column, variable size, no modifier, text format
+                description.put_u32(0);
+                description.put_i16(0);
+                description.put_u32(*type_oid);
+                description.put_i16(-1);
+                description.put_i32(-1);
+                description.put_i16(0);
+            }
+            messages.push((b'T', description));
+        }
+        for row in &self.rows {
+            let mut data = BytesMut::new();
+            data.put_i16(row.len() as i16);
+            for (_, value) in row {
+                match value.to_text() {
+                    Some(text) => {
+                        data.put_i32(text.len() as i32);
+                        data.put_slice(text.as_bytes());
+                    }
+                    None => data.put_i32(-1),
+                }
+            }
+            messages.push((b'D', data));
+        }
+        let mut tag = BytesMut::new();
+        tag.put_slice(self.command_tag.as_bytes());
+        tag.put_u8(0);
+        messages.push((b'C', tag));
+        messages
+    }
+}
+
+/// Transaction state the server reported in its last ReadyForQuery.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransactionStatus {
+    Idle,
+    InTransaction,
+    /// In a transaction that failed; only ROLLBACK gets it out.
+    Failed,
+}
+
+/// A NOTIFY delivered to a connection LISTENing on `channel`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Notification {
+    /// Backend process that sent the notification.
+    pub process_id: i32,
+    pub channel: String,
+    pub payload: String,
+}
+
+enum Stream {
     Plain(TcpStream),
     Tls(TlsStream<TcpStream>),
 }
 
+pub struct Connection {
+    stream: Stream,
+    transaction_status: TransactionStatus,
+}
+
 impl Connection {
     pub async fn new(config: &ConnectionConfig) -> Result<Self, PostgresError> {
         let stream = TcpStream::connect((config.host.as_str(), config.port)).await?;
         
-        let mut connection = match config.ssl_mode {
-            SslMode::Disable => Connection::Plain(stream),
+        let stream = match config.ssl_mode {
+            SslMode::Disable => Stream::Plain(stream),
             SslMode::Prefer | SslMode::Require => {
                 match Self::try_ssl_connection(stream, &config.host, &config.ssl_mode).await {
-                    Ok(tls_stream) => Connection::Tls(tls_stream),
+                    Ok(tls_stream) => Stream::Tls(tls_stream),
                     Err(e) if config.ssl_mode == SslMode::Prefer => {
                         eprintln!("SSL connection failed, falling back to plain: {}", e);
-                        Connection::Plain(TcpStream::connect((config.host.as_str(), config.port)).await?)
+                        Stream::Plain(TcpStream::connect((config.host.as_str(), config.port)).await?)
                     },
                     Err(e) => return Err(e),
                 }
             }
         };
 
+        let mut connection = Connection { stream, transaction_status: TransactionStatus::Idle };
         connection.startup(config).await?;
         Ok(connection)
     }
@@ -66,6 +221,9 @@ impl Connection {
         buf.put_slice(b"database\0");
         buf.put_slice(config.database.as_bytes());
         buf.put_u8(0);
+        if config.replication {
+            buf.put_slice(b"replication\0database\0");
+        }
         buf.put_u8(0);
 
         self.write_message(None, &buf).await?;
@@ -75,7 +233,7 @@ impl Connection {
             match message_type {
                 Some(b'R') => handle_authentication(self, &data, config).await?,
                 Some(b'Z') => break, // ReadyForQuery
-                Some(b'E') => return Err(PostgresError::Protocol("Error during startup".into())),
+                Some(b'E') => return Err(parse_error_response(&data)),
                 _ => {} // Ignore other messages
             }
         }
@@ -83,6 +241,59 @@ impl Connection {
         Ok(())
     }
 
+    /// Runs `sql` through the simple query protocol and collects every row of
+    /// the last result set, decoded from text format.
+    pub async fn simple_query(&mut self, sql: &str) -> Result<QueryResult, PostgresError> {
+        let messages = self.simple_query_messages(sql).await?;
+        QueryResult::from_messages(messages.iter().map(|(message_type, data)| (*message_type, &data[..])))
+    }
+
+    /// Runs `sql` through the simple query protocol and returns the server's
+    /// response messages, unparsed, up to but not including ReadyForQuery.
+    pub async fn simple_query_messages(&mut self, sql: &str) -> Result<Vec<(u8, BytesMut)>, PostgresError> {
+        let mut buf = BytesMut::with_capacity(sql.len() + 1);
+        buf.put_slice(sql.as_bytes());
+        buf.put_u8(0);
+        self.write_message(Some(b'Q'), &buf).await?;
+
+        let mut messages = Vec::new();
+        let mut error = None;
+        loop {
+            let (message_type, data) = self.read_message().await?;
+            match message_type {
+                Some(b'Z') => break, // ReadyForQuery
+                Some(b'E') => error = Some(parse_error_response(&data)),
+                Some(message_type) => messages.push((message_type, data)),
+                None => {}
+            }
+        }
+
+        match error {
+            Some(e) => Err(e),
+            None => Ok(messages),
+        }
+    }
+
+    /// Waits for the next NotificationResponse on an idle connection that has
+    /// run LISTEN. Notices and parameter changes in between are skipped.
+    /// Not cancel safe: drop the connection if the wait is abandoned.
+    pub async fn wait_for_notification(&mut self) -> Result<Notification, PostgresError> {
+        loop {
+            let (message_type, mut data) = self.read_message().await?;
+            match message_type {
+                Some(b'A') => return parse_notification(&mut data),
+                Some(b'E') => return Err(parse_error_response(&data)),
+                _ => {} // NoticeResponse, ParameterStatus
+            }
+        }
+    }
+
+    /// Transaction state as of the last ReadyForQuery. A connection is only fit
+    /// for reuse by another session while `Idle`.
+    pub fn transaction_status(&self) -> TransactionStatus {
+        self.transaction_status
+    }
+
     pub async fn write_message(&mut self, message_type: Option<u8>, data: &[u8]) -> Result<(), PostgresError> {
         let mut buf = BytesMut::with_capacity(5 + data.len());
         if let Some(mt) = message_type {
@@ -91,9 +302,9 @@ impl Connection {
         buf.put_u32((data.len() + 4) as u32);
         buf.put_slice(data);
 
-        match self {
-            Connection::Plain(stream) => stream.write_all(&buf).await?,
-            Connection::Tls(stream) => stream.write_all(&buf).await?,
+        match &mut self.stream {
+            Stream::Plain(stream) => stream.write_all(&buf).await?,
+            Stream::Tls(stream) => stream.write_all(&buf).await?,
         }
 
         Ok(())
@@ -101,22 +312,172 @@ impl Connection {
 
     pub async fn read_message(&mut self) -> Result<(Option<u8>, BytesMut), PostgresError> {
         let mut header = [0u8; 5];
-        match self {
-            Connection::Plain(stream) => { stream.read_exact(&mut header).await?; },
-            Connection::Tls(stream) => { stream.read_exact(&mut header).await?; },
+        match &mut self.stream {
+            Stream::Plain(stream) => { stream.read_exact(&mut header).await?; },
+            Stream::Tls(stream) => { stream.read_exact(&mut header).await?; },
         }
 
         let message_type = header[0];
-        let length = (&header[1..5]).get_u32() as usize - 4;
+        let length = match ((&header[1..5]).get_u32() as usize).checked_sub(4) {
+            Some(length) => length,
+            None => return Err(PostgresError::Protocol(format!("Invalid length of {} message", message_type as char))),
+        };
         let mut data = BytesMut::with_capacity(length);
         data.resize(length, 0);
 
-        match self {
-            Connection::Plain(stream) => { stream.read_exact(&mut data).await?; },
-            Connection::Tls(stream) => { stream.read_exact(&mut data).await?; },
+        match &mut self.stream {
+            Stream::Plain(stream) => { stream.read_exact(&mut data).await?; },
+            Stream::Tls(stream) => { stream.read_exact(&mut data).await?; },
+        }
+
+        if message_type == b'Z' {
+            self.transaction_status = match data.first() {
+                Some(b'T') => TransactionStatus::InTransaction,
+                Some(b'E') => TransactionStatus::Failed,
+                _ => TransactionStatus::Idle,
+            };
         }
 
         Ok((Some(message_type), data))
     }
 }
 
+pub(crate) fn read_cstr(data: &mut BytesMut) -> Result<String, PostgresError> {
+    let end = data.iter().position(|b| *b == 0)
+        .ok_or_else(|| PostgresError::Protocol("Unterminated string in message".into()))?;
+    let value = String::from_utf8_lossy(&data[..end]).into_owned();
+    data.advance(end + 1);
+    Ok(value)
+}
+
+fn parse_notification(data: &mut BytesMut) -> Result<Notification, PostgresError> {
+    if data.len() < 4 {
+        return Err(PostgresError::Protocol("Truncated NotificationResponse".into()));
+    }
+    let process_id = data.get_i32();
+    let channel = read_cstr(data)?;
+    let payload = read_cstr(data)?;
+    Ok(Notification { process_id, channel, payload })
+}
+
+/// Fails unless `len` more bytes of a `message` are left in `data`.
+pub(crate) fn ensure(data: &BytesMut, len: usize, message: &str) -> Result<(), PostgresError> {
+    if data.remaining() < len {
+        return Err(PostgresError::Protocol(format!("Truncated {} message", message)));
+    }
+    Ok(())
+}
+
+fn parse_row_description(data: &mut BytesMut) -> Result<Vec<(String, u32)>, PostgresError> {
+    ensure(data, 2, "RowDescription")?;
+    let count = data.get_u16() as usize;
+    let mut columns = Vec::with_capacity(count);
+    for _ in 0..count {
+        let name = read_cstr(data)?;
+        ensure(data, 18, "RowDescription")?;
+        // table oid, column attnum, then the type oid we care about
+        data.advance(6);
+        let type_oid = data.get_u32();
+        // type size, type modifier, format code
+        data.advance(8);
+        columns.push((name, type_oid));
+    }
+    Ok(columns)
+}
+
+fn parse_data_row(data: &mut BytesMut, columns: &[(String, u32)]) -> Result<Row, PostgresError> {
+    ensure(data, 2, "DataRow")?;
+    let count = data.get_u16() as usize;
+    if count != columns.len() {
+        return Err(PostgresError::Protocol("DataRow does not match RowDescription".into()));
+    }
+
+    let mut row = Vec::with_capacity(count);
+    for (name, type_oid) in columns {
+        ensure(data, 4, "DataRow")?;
+        let length = data.get_i32();
+        let value = if length < 0 {
+            PostgresValue::Null
+        } else {
+            ensure(data, length as usize, "DataRow")?;
+            let raw = data.split_to(length as usize);
+            PostgresValue::from_text(*type_oid, &String::from_utf8_lossy(&raw))
+        };
+        row.push((name.clone(), value));
+    }
+    Ok(row)
+}
+
+/// Turns an ErrorResponse body into a `PostgresError::Server` carrying the SQLSTATE.
+pub fn parse_error_response(data: &[u8]) -> PostgresError {
+    let mut code = String::new();
+    let mut message = String::new();
+    for field in data.split(|b| *b == 0) {
+        match field.first() {
+            Some(b'C') => code = String::from_utf8_lossy(&field[1..]).into_owned(),
+            Some(b'M') => message = String::from_utf8_lossy(&field[1..]).into_owned(),
+            _ => {}
+        }
+    }
+    PostgresError::Server { code, message }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn results_round_trip_through_messages() {
+        let columns = vec![("id".to_string(), INT4_OID), ("name".to_string(), 25), ("score".to_string(), FLOAT8_OID)];
+        let result = QueryResult {
+            rows: vec![
+                vec![
+                    ("id".to_string(), PostgresValue::Int32(1)),
+                    ("name".to_string(), PostgresValue::String("a".to_string())),
+                    ("score".to_string(), PostgresValue::Float64(f64::NEG_INFINITY)),
+                ],
+                vec![
+                    ("id".to_string(), PostgresValue::Int32(2)),
+                    ("name".to_string(), PostgresValue::Null),
+                    ("score".to_string(), PostgresValue::Float64(0.5)),
+                ],
+            ],
+            command_tag: "SELECT 2".to_string(),
+            columns,
+        };
+        let messages = result.to_messages();
+        let parsed = QueryResult::from_messages(messages.iter().map(|(message_type, body)| (*message_type, &body[..])));
+        let parsed = parsed.unwrap();
+        assert_eq!(parsed.columns, result.columns);
+        assert_eq!(parsed.rows, result.rows);
+        assert_eq!(parsed.command_tag, result.command_tag);
+    }
+
+    #[test]
+    fn truncated_messages_are_protocol_errors() {
+        let result = QueryResult {
+            columns: vec![("id".to_string(), INT4_OID), ("name".to_string(), 25)],
+            rows: vec![vec![
+                ("id".to_string(), PostgresValue::Int32(7)),
+                ("name".to_string(), PostgresValue::String("seven".to_string())),
+            ]],
+            command_tag: "SELECT 1".to_string(),
+        };
+        let messages = result.to_messages();
+        let (description, row) = (&messages[0].1, &messages[1].1);
+
+        for cut in 0..description.len() {
+            let parsed = QueryResult::from_messages([(b'T', &description[..cut])]);
+            assert!(matches!(parsed, Err(PostgresError::Protocol(_))), "RowDescription cut at {}", cut);
+        }
+        for cut in 0..row.len() {
+            let parsed = QueryResult::from_messages([(b'T', &description[..]), (b'D', &row[..cut])]);
+            assert!(matches!(parsed, Err(PostgresError::Protocol(_))), "DataRow cut at {}", cut);
+        }
+        // A value longer than the message, and a negative column count
+        let mut long = row.clone();
+        long[2] = 0x7f;
+        assert!(QueryResult::from_messages([(b'T', &description[..]), (b'D', &long[..])]).is_err());
+        assert!(QueryResult::from_messages([(b'T', &[0xff, 0xff][..])]).is_err());
+    }
+}
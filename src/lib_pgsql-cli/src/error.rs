@@ -7,6 +7,8 @@ pub enum PostgresError {
     Auth(String),
     Parse(String),
     Tls(native_tls::Error),
+    Unavailable(String),
+    Server { code: String, message: String },
 }
 
 impl fmt::Display for PostgresError {
@@ -17,10 +19,29 @@ impl fmt::Display for PostgresError {
             PostgresError::Auth(msg) => write!(f, "Authentication error: {}", msg),
             PostgresError::Parse(msg) => write!(f, "Parse error: {}", msg),
             PostgresError::Tls(err) => write!(f, "TLS error: {}", err),
+            PostgresError::Unavailable(msg) => write!(f, "Host unavailable: {}", msg),
+            PostgresError::Server { code, message } => write!(f, "Server error {}: {}", code, message),
         }
     }
 }
 
+impl PostgresError {
+    /// Whether the statement can safely be retried, possibly on another host:
+    /// the connection was lost, or the server aborted the transaction in a way
+    /// clients are expected to retry.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            PostgresError::Io(_) | PostgresError::Unavailable(_) => true,
+            PostgresError::Server { code, .. } => {
+                code.starts_with("08") || code.starts_with("57P") || code == "40001" || code == "40P01"
+            }
+            _ => false,
+        }
+    }
+}
+
+impl std::error::Error for PostgresError {}
+
 impl From<std::io::Error> for PostgresError {
     fn from(err: std::io::Error) -> Self {
         PostgresError::Io(err)
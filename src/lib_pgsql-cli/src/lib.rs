@@ -3,7 +3,9 @@ pub mod connection;
 pub mod config;
 pub mod error;
 pub mod auth;
+pub mod replication;
 
 pub use client::PostgresClient;
 pub use error::PostgresError;
-pub use connection::Connection;
\ No newline at end of file
+pub use connection::{Connection, Notification, PostgresValue, QueryResult, Row, TransactionStatus};
+pub use replication::{LogicalMessage, ReplicationMessage, ReplicationStream};
\ No newline at end of file
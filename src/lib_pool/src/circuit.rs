@@ -0,0 +1,162 @@
+use log;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+struct BreakerState {
+    state: CircuitState,
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+    /// When the current half-open probe was let through.
+    probe_started: Option<Instant>,
+}
+
+/// Tracks connection failures against a single host and stops new connection
+/// attempts once it looks dead. After `cooldown` a single probe is let through;
+/// its outcome decides whether the circuit closes again or stays open. A probe
+/// that reports neither, because its caller gave up on it, is replaced by a new
+/// one after another `cooldown`.
+pub struct CircuitBreaker {
+    host: String,
+    state: Mutex<BreakerState>,
+    failure_threshold: u32,
+    cooldown: Duration,
+}
+
+impl CircuitBreaker {
+    pub fn new(host: &str, failure_threshold: u32, cooldown: Duration) -> Self {
+        CircuitBreaker {
+            host: host.to_string(),
+            state: Mutex::new(BreakerState {
+                state: CircuitState::Closed,
+                consecutive_failures: 0,
+                opened_at: None,
+                probe_started: None,
+            }),
+            failure_threshold: failure_threshold.max(1),
+            cooldown,
+        }
+    }
+
+    /// Returns whether a new connection attempt may be made right now.
+    pub fn allow(&self) -> bool {
+        let mut breaker = self.state.lock().unwrap();
+        match breaker.state {
+            CircuitState::Closed => true,
+            CircuitState::HalfOpen => {
+                let abandoned = self.probe_abandoned(&breaker);
+                if abandoned {
+                    log::info!("Probe connection to {} never completed, allowing another", self.host);
+                    breaker.probe_started = Some(Instant::now());
+                }
+                abandoned
+            }
+            CircuitState::Open => {
+                let cooled_down = breaker
+                    .opened_at
+                    .map_or(true, |opened_at| opened_at.elapsed() >= self.cooldown);
+                if cooled_down {
+                    log::info!("Circuit breaker for {} half-open, allowing probe connection", self.host);
+                    breaker.state = CircuitState::HalfOpen;
+                    breaker.probe_started = Some(Instant::now());
+                }
+                cooled_down
+            }
+        }
+    }
+
+    fn probe_abandoned(&self, breaker: &BreakerState) -> bool {
+        breaker
+            .probe_started
+            .map_or(true, |started| started.elapsed() >= self.cooldown)
+    }
+
+    /// Like `allow`, but without moving an open circuit into the half-open state.
+    pub fn is_accepting(&self) -> bool {
+        let breaker = self.state.lock().unwrap();
+        match breaker.state {
+            CircuitState::Closed => true,
+            CircuitState::HalfOpen => self.probe_abandoned(&breaker),
+            CircuitState::Open => breaker
+                .opened_at
+                .map_or(true, |opened_at| opened_at.elapsed() >= self.cooldown),
+        }
+    }
+
+    pub fn record_success(&self) {
+        let mut breaker = self.state.lock().unwrap();
+        if breaker.state != CircuitState::Closed {
+            log::info!("Circuit breaker for {} closed", self.host);
+        }
+        breaker.state = CircuitState::Closed;
+        breaker.consecutive_failures = 0;
+        breaker.opened_at = None;
+        breaker.probe_started = None;
+    }
+
+    pub fn record_failure(&self) {
+        let mut breaker = self.state.lock().unwrap();
+        breaker.consecutive_failures += 1;
+
+        let trip = match breaker.state {
+            CircuitState::HalfOpen => true,
+            CircuitState::Closed => breaker.consecutive_failures >= self.failure_threshold,
+            CircuitState::Open => false,
+        };
+
+        if trip {
+            log::warn!(
+                "Circuit breaker for {} opened after {} consecutive failures",
+                self.host, breaker.consecutive_failures
+            );
+            breaker.state = CircuitState::Open;
+            breaker.opened_at = Some(Instant::now());
+            breaker.probe_started = None;
+        }
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    pub fn state(&self) -> CircuitState {
+        self.state.lock().unwrap().state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::sleep;
+
+    #[test]
+    fn abandoned_probe_is_replaced_after_cooldown() {
+        let cooldown = Duration::from_millis(30);
+        let breaker = CircuitBreaker::new("db:5432", 1, cooldown);
+        breaker.record_failure();
+        assert!(!breaker.allow());
+
+        sleep(cooldown);
+        assert!(breaker.allow());
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        // The probe is still running
+        assert!(!breaker.allow());
+        assert!(!breaker.is_accepting());
+
+        // It never reported back
+        sleep(cooldown);
+        assert!(breaker.is_accepting());
+        assert!(breaker.allow());
+        assert!(!breaker.allow());
+
+        breaker.record_success();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert!(breaker.allow());
+    }
+}
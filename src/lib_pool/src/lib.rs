@@ -1,41 +1,305 @@
+pub mod circuit;
+
+use log;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
 use lib_pgsqlcli::{client::PostgresClient, error::PostgresError, config::ConnectionConfig};
 
+pub use circuit::{CircuitBreaker, CircuitState};
+
+#[derive(Clone, Debug)]
+pub struct PoolConfig {
+    pub min_size: usize,
+    pub max_size: usize,
+    pub reserve_pool_size: usize,
+    pub reserve_pool_timeout: Duration,
+    pub connect_timeout: Duration,
+    pub circuit_breaker_threshold: u32,
+    pub circuit_breaker_cooldown: Duration,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            min_size: 0,
+            max_size: 100,
+            reserve_pool_size: 0,
+            reserve_pool_timeout: Duration::from_secs(5),
+            connect_timeout: Duration::from_secs(10),
+            circuit_breaker_threshold: 5,
+            circuit_breaker_cooldown: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PoolStats {
+    pub max_size: usize,
+    pub total: usize,
+    pub idle: usize,
+    pub waiting: usize,
+    pub reserve_pool_size: usize,
+    pub reserve_in_use: usize,
+    pub reserve_activations: u64,
+    pub circuit_state: CircuitState,
+    pub draining: bool,
+}
+
+struct PoolState {
+    idle: Vec<PostgresClient>,
+    total: usize,
+    waiting: usize,
+    reserve_activations: u64,
+}
+
+/// Counts the caller among the pool's waiters until dropped, including when
+/// its future is cancelled mid-wait.
+struct Waiting<'a>(&'a Mutex<PoolState>);
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.lock().unwrap().waiting -= 1;
+    }
+}
+
+/// A slot counted in `total` for a connection being opened. Dropping it frees
+/// the slot unless the connection was handed to the caller.
+struct Reservation<'a> {
+    pool: &'a Pool,
+    settled: bool,
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        if !self.settled {
+            self.pool.forget_client();
+        }
+    }
+}
+
 pub struct Pool {
-    clients: Arc<Mutex<Vec<PostgresClient>>>,
-    config: ConnectionConfig,
-    max_size: usize,
+    state: Arc<Mutex<PoolState>>,
+    available: Arc<Notify>,
+    breaker: Arc<CircuitBreaker>,
+    connection_string: String,
+    config: PoolConfig,
+    draining: AtomicBool,
 }
 
 impl Pool {
     pub async fn new(connection_string: &str, max_size: usize) -> Result<Self, PostgresError> {
-        let mut clients = Vec::new();
-        let config = ConnectionConfig::from_connection_string(connection_string)?;
+        let config = PoolConfig {
+            min_size: max_size,
+            max_size,
+            ..PoolConfig::default()
+        };
+        Self::with_config(connection_string, config).await
+    }
 
-        for _ in 0..max_size {
+    pub async fn with_config(connection_string: &str, config: PoolConfig) -> Result<Self, PostgresError> {
+        let connection_config = ConnectionConfig::from_connection_string(connection_string)?;
+        let breaker = CircuitBreaker::new(
+            &format!("{}:{}", connection_config.host, connection_config.port),
+            config.circuit_breaker_threshold,
+            config.circuit_breaker_cooldown,
+        );
+        Self::with_breaker(connection_string, config, Arc::new(breaker)).await
+    }
+
+    /// A pool whose failures count towards, and which is stopped by, an existing
+    /// circuit breaker; for several pools to the same host, e.g. per login.
+    pub async fn with_breaker(
+        connection_string: &str,
+        config: PoolConfig,
+        breaker: Arc<CircuitBreaker>,
+    ) -> Result<Self, PostgresError> {
+        let mut clients = Vec::new();
+        for _ in 0..config.min_size.min(config.max_size) {
             let client = PostgresClient::connect(connection_string).await?;
             clients.push(client);
         }
 
         Ok(Pool {
-            clients: Arc::new(Mutex::new(clients)),
+            state: Arc::new(Mutex::new(PoolState {
+                total: clients.len(),
+                idle: clients,
+                waiting: 0,
+                reserve_activations: 0,
+            })),
+            available: Arc::new(Notify::new()),
+            breaker,
+            connection_string: connection_string.to_string(),
             config,
-            max_size,
+            draining: AtomicBool::new(false),
         })
     }
 
     pub async fn get_client(&self) -> Result<PostgresClient, PostgresError> {
-        PostgresClient::connect(&self.config.to_string()).await // Adjusted the call to connect
+        let started = Instant::now();
+
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                if let Some(client) = state.idle.pop() {
+                    return Ok(client);
+                }
+
+                // The reserve only opens up once this caller has waited past the timeout
+                let mut limit = self.config.max_size;
+                if started.elapsed() >= self.config.reserve_pool_timeout {
+                    limit += self.config.reserve_pool_size;
+                }
+
+                if state.total < limit {
+                    // Fail fast instead of waiting for a connect timeout against a dead host
+                    if !self.breaker.allow() {
+                        return Err(PostgresError::Unavailable(format!(
+                            "circuit breaker open for {}",
+                            self.breaker.host()
+                        )));
+                    }
+                    if state.total >= self.config.max_size {
+                        state.reserve_activations += 1;
+                        log::warn!(
+                            "Connection pool exhausted, opening reserve connection {}/{}",
+                            state.total + 1 - self.config.max_size,
+                            self.config.reserve_pool_size
+                        );
+                    }
+                    state.total += 1;
+                    break;
+                }
+
+                state.waiting += 1;
+            }
+
+            let _waiting = Waiting(&self.state);
+            let remaining = self.config.reserve_pool_timeout.saturating_sub(started.elapsed());
+            if self.config.reserve_pool_size == 0 || remaining.is_zero() {
+                self.available.notified().await;
+            } else {
+                let _ = tokio::time::timeout(remaining, self.available.notified()).await;
+            }
+        }
+
+        let mut reservation = Reservation { pool: self, settled: false };
+        let connect = PostgresClient::connect(&self.connection_string);
+        let result = match tokio::time::timeout(self.config.connect_timeout, connect).await {
+            Ok(result) => result,
+            Err(_) => Err(PostgresError::Io(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!("connection to {} timed out", self.breaker.host()),
+            ))),
+        };
+
+        match result {
+            Ok(client) => {
+                self.breaker.record_success();
+                reservation.settled = true;
+                Ok(client)
+            }
+            // Only an unreachable host counts against it: the breaker is shared by
+            // every login, so one client's bad password must not open it for all
+            Err(e @ PostgresError::Io(_)) => {
+                self.breaker.record_failure();
+                Err(e)
+            }
+            Err(e) => {
+                self.breaker.record_success();
+                Err(e)
+            }
+        }
     }
 
     pub async fn release_client(&self, client: PostgresClient) {
-        let mut clients = self.clients.lock().unwrap();
+        {
+            let mut state = self.state.lock().unwrap();
 
-        if clients.len() < self.max_size {
-            clients.push(client);
-        } else {
-            // Log error or handle exceeding pool size gracefully (e.g., backoff)
-            eprintln!("Connection pool reached max size, discarding client");
+            if state.total > self.config.max_size {
+                // Reserve connections are closed on release so the pool shrinks back after a burst
+                state.total -= 1;
+                log::info!("Closing reserve connection, {} connections remain open", state.total);
+                drop(client);
+            } else if self.is_draining() {
+                state.total -= 1;
+                log::debug!("Closing connection to draining {}, {} connections remain open", self.breaker.host(), state.total);
+                drop(client);
+            } else {
+                state.idle.push(client);
+            }
+        }
+
+        self.available.notify_one();
+    }
+
+    /// Drops a client that is no longer usable and frees its slot in the pool.
+    pub fn discard_client(&self, client: PostgresClient) {
+        drop(client);
+        self.forget_client();
+    }
+
+    /// Closes every idle connection; connections currently checked out are untouched
+    /// and return to the pool as usual.
+    pub fn close_idle(&self) {
+        let mut state = self.state.lock().unwrap();
+        let closed = state.idle.len();
+        state.idle.clear();
+        state.total -= closed;
+        drop(state);
+
+        if closed > 0 {
+            log::info!("Closed {} idle connections to {}", closed, self.breaker.host());
+            self.available.notify_waiters();
+        }
+    }
+
+    /// While draining, connections are closed as they are released instead of
+    /// going back to the idle list, so the pool empties as its users finish.
+    pub fn set_draining(&self, draining: bool) {
+        self.draining.store(draining, Ordering::SeqCst);
+        if draining {
+            self.close_idle();
+        }
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// Returns false while the circuit breaker rejects new connections, so callers
+    /// holding several pools can pick another host instead.
+    pub fn is_available(&self) -> bool {
+        self.breaker.is_accepting() || !self.state.lock().unwrap().idle.is_empty()
+    }
+
+    /// Number of connections currently checked out of the pool.
+    pub fn in_use(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        state.total - state.idle.len()
+    }
+
+    pub fn config(&self) -> &PoolConfig {
+        &self.config
+    }
+
+    pub fn breaker(&self) -> Arc<CircuitBreaker> {
+        Arc::clone(&self.breaker)
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        let state = self.state.lock().unwrap();
+        PoolStats {
+            max_size: self.config.max_size,
+            total: state.total,
+            idle: state.idle.len(),
+            waiting: state.waiting,
+            reserve_pool_size: self.config.reserve_pool_size,
+            reserve_in_use: state.total.saturating_sub(self.config.max_size),
+            reserve_activations: state.reserve_activations,
+            circuit_state: self.breaker.state(),
+            draining: self.is_draining(),
         }
     }
 
@@ -48,4 +312,99 @@ impl Pool {
         self.release_client(client).await;
         result
     }
-}
\ No newline at end of file
+
+    fn forget_client(&self) {
+        self.state.lock().unwrap().total -= 1;
+        self.available.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// A server that accepts connections and never answers, so connecting hangs.
+    async fn silent_server() -> (TcpListener, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("postgresql://app@{}/app?sslmode=disable", listener.local_addr().unwrap());
+        (listener, url)
+    }
+
+    /// A server that rejects every login as PostgreSQL does a wrong password.
+    async fn rejecting_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("postgresql://app@{}/app?sslmode=disable", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut length = [0u8; 4];
+                stream.read_exact(&mut length).await.unwrap();
+                let mut startup = vec![0u8; u32::from_be_bytes(length) as usize - 4];
+                stream.read_exact(&mut startup).await.unwrap();
+
+                let fields = b"SFATAL\0C28P01\0Mpassword authentication failed for user \"app\"\0\0";
+                let mut error = vec![b'E'];
+                error.extend_from_slice(&(fields.len() as u32 + 4).to_be_bytes());
+                error.extend_from_slice(fields);
+                stream.write_all(&error).await.unwrap();
+            }
+        });
+        url
+    }
+
+    fn breaker_config() -> PoolConfig {
+        PoolConfig {
+            circuit_breaker_threshold: 2,
+            connect_timeout: Duration::from_secs(1),
+            ..PoolConfig::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn rejected_logins_leave_the_breaker_closed() {
+        let pool = Pool::with_config(&rejecting_server().await, breaker_config()).await.unwrap();
+        for _ in 0..5 {
+            match pool.get_client().await {
+                Err(PostgresError::Io(e)) => panic!("expected the login to be rejected, got {}", e),
+                Err(_) => {}
+                Ok(_) => panic!("expected the login to be rejected"),
+            }
+        }
+        assert_eq!(pool.breaker().state(), CircuitState::Closed);
+        assert_eq!(pool.stats().total, 0);
+    }
+
+    #[tokio::test]
+    async fn refused_connections_open_the_breaker() {
+        let (listener, url) = silent_server().await;
+        drop(listener);
+        let pool = Pool::with_config(&url, breaker_config()).await.unwrap();
+        for _ in 0..2 {
+            assert!(matches!(pool.get_client().await, Err(PostgresError::Io(_))));
+        }
+        assert_eq!(pool.breaker().state(), CircuitState::Open);
+        assert!(matches!(pool.get_client().await, Err(PostgresError::Unavailable(_))));
+    }
+
+    #[tokio::test]
+    async fn cancelled_checkouts_give_back_their_slots() {
+        let (_listener, url) = silent_server().await;
+        let config = PoolConfig { max_size: 1, ..PoolConfig::default() };
+        let pool = Pool::with_config(&url, config).await.unwrap();
+
+        // The first checkout is cancelled while connecting, the second while waiting for it
+        let connecting = pool.get_client();
+        let waiting = async {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            pool.get_client().await
+        };
+        let _ = tokio::time::timeout(Duration::from_millis(100), async { tokio::join!(connecting, waiting) }).await;
+
+        let stats = pool.stats();
+        assert_eq!(stats.total, 0);
+        assert_eq!(stats.waiting, 0);
+        assert!(tokio::time::timeout(Duration::from_millis(50), pool.get_client()).await.is_err());
+        assert_eq!(pool.stats().total, 0);
+    }
+}
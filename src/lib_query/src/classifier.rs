@@ -0,0 +1,188 @@
+use crate::lexer::{split_statements, tokenize, Token};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatementKind {
+    /// Safe to run on a replica.
+    Read,
+    /// Modifies data or schema, or may do so; must run on the primary.
+    Write,
+    Begin,
+    Commit,
+    Rollback,
+}
+
+/// Functions that write, or change session state, even when called from a
+/// plain SELECT. Advisory locks must be taken and released on the same host.
+const WRITING_FUNCTIONS: [&str; 13] = [
+    "nextval",
+    "setval",
+    "set_config",
+    "pg_advisory_lock",
+    "pg_advisory_lock_shared",
+    "pg_advisory_xact_lock",
+    "pg_try_advisory_lock",
+    "pg_try_advisory_xact_lock",
+    "pg_advisory_unlock",
+    "pg_advisory_unlock_shared",
+    "pg_advisory_unlock_all",
+    "lo_create",
+    "lo_import",
+];
+
+const WRITE_KEYWORDS: [&str; 5] = ["insert", "update", "delete", "merge", "truncate"];
+
+/// Classifies `sql`, which may hold several statements: it is a write if any
+/// of them writes, and otherwise takes the kind of its last transaction
+/// control statement, if any.
+pub fn classify(sql: &str) -> StatementKind {
+    let mut kind = StatementKind::Read;
+    for statement in split_statements(sql) {
+        match classify_tokens(&tokenize(statement)) {
+            StatementKind::Write => return StatementKind::Write,
+            StatementKind::Read => {}
+            control => kind = control,
+        }
+    }
+    kind
+}
+
+/// Classifies a single statement.
+pub fn classify_tokens(tokens: &[Token]) -> StatementKind {
+    let first = match tokens.iter().find_map(|t| match t {
+        Token::Word(w) => Some(w.as_str()),
+        _ => None,
+    }) {
+        Some(first) => first,
+        None => return StatementKind::Read,
+    };
+
+    match first {
+        "begin" | "start" => StatementKind::Begin,
+        "commit" | "end" => StatementKind::Commit,
+        "rollback" | "abort" => {
+            // ROLLBACK TO SAVEPOINT keeps the transaction open
+            if tokens.iter().any(|t| t.is_word("to")) {
+                StatementKind::Write
+            } else {
+                StatementKind::Rollback
+            }
+        }
+        "select" | "values" | "table" => {
+            if is_read_only_select(tokens) {
+                StatementKind::Read
+            } else {
+                StatementKind::Write
+            }
+        }
+        "with" => {
+            // Data-modifying CTEs make the whole statement a write
+            if tokens.iter().any(|t| WRITE_KEYWORDS.iter().any(|k| t.is_word(k))) || !is_read_only_select(tokens) {
+                StatementKind::Write
+            } else {
+                StatementKind::Read
+            }
+        }
+        "show" => StatementKind::Read,
+        "explain" => {
+            // EXPLAIN ANALYZE executes the statement
+            if tokens.iter().any(|t| t.is_word("analyze") || t.is_word("analyse")) {
+                StatementKind::Write
+            } else {
+                StatementKind::Read
+            }
+        }
+        _ => StatementKind::Write,
+    }
+}
+
+fn is_read_only_select(tokens: &[Token]) -> bool {
+    for (i, token) in tokens.iter().enumerate() {
+        // SELECT ... INTO creates a table
+        if token.is_word("into") {
+            return false;
+        }
+        // Row locks need the primary
+        if token.is_word("for") {
+            if let Some(next) = tokens.get(i + 1) {
+                if next.is_word("update") || next.is_word("share") || next.is_word("no") || next.is_word("key") {
+                    return false;
+                }
+            }
+        }
+        if let Token::Word(word) = token {
+            if WRITING_FUNCTIONS.contains(&word.as_str()) && tokens.get(i + 1).map_or(false, |t| t.is_symbol("(")) {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads() {
+        for sql in [
+            "SELECT * FROM users WHERE id = 1",
+            "  select 1;",
+            "VALUES (1), (2)",
+            "TABLE users",
+            "WITH t AS (SELECT 1) SELECT * FROM t",
+            "SHOW search_path",
+            "EXPLAIN SELECT * FROM users",
+            "SELECT 'insert into x' AS text",
+            "/* DELETE FROM users */ SELECT 1",
+            "",
+        ] {
+            assert_eq!(classify(sql), StatementKind::Read, "{}", sql);
+        }
+    }
+
+    #[test]
+    fn writes() {
+        for sql in [
+            "INSERT INTO users VALUES (1)",
+            "update users set name = 'x'",
+            "DELETE FROM users",
+            "TRUNCATE users",
+            "CREATE TABLE t (id int)",
+            "SELECT * INTO copy FROM users",
+            "SELECT * FROM users FOR UPDATE",
+            "SELECT * FROM users FOR NO KEY UPDATE",
+            "SELECT nextval('users_id_seq')",
+            "SELECT set_config('search_path', 'app', false)",
+            "SELECT pg_advisory_lock(1)",
+            "SELECT pg_advisory_unlock(1)",
+            "WITH gone AS (DELETE FROM users RETURNING *) SELECT * FROM gone",
+            "EXPLAIN ANALYZE DELETE FROM users",
+            "ROLLBACK TO SAVEPOINT before_delete",
+            "SET search_path = app",
+        ] {
+            assert_eq!(classify(sql), StatementKind::Write, "{}", sql);
+        }
+    }
+
+    #[test]
+    fn transaction_control() {
+        assert_eq!(classify("BEGIN"), StatementKind::Begin);
+        assert_eq!(classify("start transaction isolation level serializable"), StatementKind::Begin);
+        assert_eq!(classify("COMMIT"), StatementKind::Commit);
+        assert_eq!(classify("END"), StatementKind::Commit);
+        assert_eq!(classify("ROLLBACK"), StatementKind::Rollback);
+        assert_eq!(classify("abort"), StatementKind::Rollback);
+    }
+
+    #[test]
+    fn any_writing_statement_makes_a_write() {
+        assert_eq!(classify("SELECT 1; DELETE FROM users"), StatementKind::Write);
+        assert_eq!(classify("SELECT 1; SELECT pg_advisory_unlock(1);"), StatementKind::Write);
+        assert_eq!(classify("SELECT 1; SELECT 2;"), StatementKind::Read);
+        assert_eq!(classify("BEGIN; SELECT 1"), StatementKind::Begin);
+        assert_eq!(classify("SELECT 1; COMMIT"), StatementKind::Commit);
+        // Semicolons inside literals, identifiers and function bodies do not split
+        assert_eq!(classify("SELECT ';DELETE FROM users', \";drop\" FROM t"), StatementKind::Read);
+        assert_eq!(classify("SELECT $$; DELETE FROM users$$"), StatementKind::Read);
+    }
+}
@@ -0,0 +1,93 @@
+use lib_cache::{CacheScope, QueryKey};
+use lib_pgsqlcli::PostgresValue;
+
+use crate::lexer::{tokenize, Token};
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+fn render(sql: &str, keep_literals: bool) -> String {
+    let mut rendered = String::with_capacity(sql.len());
+    for token in tokenize(sql) {
+        if !rendered.is_empty() {
+            rendered.push(' ');
+        }
+        match token {
+            Token::Word(w) => rendered.push_str(&w),
+            Token::QuotedIdent(ident) => {
+                rendered.push('"');
+                rendered.push_str(&ident.replace('"', "\"\""));
+                rendered.push('"');
+            }
+            Token::String(s) if keep_literals => {
+                rendered.push('\'');
+                rendered.push_str(&s.replace('\'', "''"));
+                rendered.push('\'');
+            }
+            Token::Number(n) if keep_literals => rendered.push_str(&n),
+            Token::Param(n) if keep_literals => rendered.push_str(&format!("${}", n)),
+            Token::String(_) | Token::Number(_) | Token::Param(_) => rendered.push('?'),
+            Token::Symbol(s) => rendered.push_str(&s),
+        }
+    }
+    rendered
+}
+
+/// Statement text with comments dropped, keywords lower-cased and every literal
+/// and parameter replaced by `?`, so statements that differ only in their
+/// values normalize to the same text.
+pub fn normalize(sql: &str) -> String {
+    render(sql, false)
+}
+
+/// Statement text with comments dropped, whitespace collapsed and unquoted
+/// identifiers lower-cased, keeping literals: two statements with the same
+/// canonical text return the same rows.
+pub fn canonical(sql: &str) -> String {
+    render(sql, true)
+}
+
+/// Stable identifier of the statement's normalized form, used to correlate log
+/// lines about the same query across pgShield instances.
+pub fn fingerprint(sql: &str) -> u64 {
+    normalize(sql)
+        .bytes()
+        .fold(FNV_OFFSET, |hash, byte| (hash ^ byte as u64).wrapping_mul(FNV_PRIME))
+}
+
+/// Query cache key for `sql` run with `params` in `scope`.
+pub fn cache_key(sql: &str, params: &[PostgresValue], scope: CacheScope) -> QueryKey {
+    QueryKey {
+        query: canonical(sql),
+        // The variant keeps `1` and `'1'` apart
+        params: params.iter().map(|value| format!("{:?}", value)).collect(),
+        scope,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn canonical_text() {
+        assert_eq!(
+            canonical("SELECT  *\n FROM Orders /* note */ WHERE id = 5 AND \"Name\" = 'O''Brien'"),
+            "select * from orders where id = 5 and \"Name\" = 'O''Brien'"
+        );
+        assert_eq!(
+            normalize("select * from orders where id = 5 and name = $1"),
+            "select * from orders where id = ? and name = ?"
+        );
+        assert_eq!(fingerprint("SELECT 1"), fingerprint("select  2"));
+    }
+
+    #[test]
+    fn escapes_keep_literals_apart() {
+        let key = |sql| cache_key(sql, &[], CacheScope::default());
+        assert_ne!(key(r"SELECT E'\n'"), key("SELECT 'n'"));
+        assert_ne!(key(r"SELECT E'\\n'"), key(r"SELECT E'\n'"));
+        assert_eq!(key(r"SELECT E'\\n'"), key(r"SELECT '\n'"));
+        assert_eq!(key(r"SELECT E'it\'s'"), key("SELECT 'it''s'"));
+    }
+}
@@ -0,0 +1,183 @@
+use log;
+use std::time::Duration;
+
+const HINT_PREFIX: &str = "pgshield:";
+/// Longer durations are rejected rather than risk overflowing deadlines.
+const MAX_DURATION: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouteHint {
+    Primary,
+    Replica,
+}
+
+/// Per-statement overrides given in leading comments, for example
+/// `/* pgshield: route=primary nocache timeout=5s */ SELECT ...`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct QueryHints {
+    pub route: Option<RouteHint>,
+    pub host: Option<String>,
+    pub cache_ttl: Option<Duration>,
+    pub stale_ttl: Option<Duration>,
+    pub nocache: bool,
+    pub timeout: Option<Duration>,
+    pub shard_key: Option<String>,
+}
+
+impl QueryHints {
+    pub fn is_empty(&self) -> bool {
+        *self == QueryHints::default()
+    }
+}
+
+/// A comment at the start of a statement, with its byte range.
+struct LeadingComment<'a> {
+    body: &'a str,
+    start: usize,
+    end: usize,
+}
+
+fn leading_comments(sql: &str) -> Vec<LeadingComment<'_>> {
+    let mut comments = Vec::new();
+    let mut pos = 0;
+    loop {
+        let rest = &sql[pos..];
+        let trimmed = rest.trim_start();
+        let start = pos + (rest.len() - trimmed.len());
+
+        if let Some(body) = trimmed.strip_prefix("/*") {
+            let close = match body.find("*/") {
+                Some(close) => close,
+                None => break,
+            };
+            pos = start + 2 + close + 2;
+            comments.push(LeadingComment { body: &body[..close], start, end: pos });
+        } else if let Some(body) = trimmed.strip_prefix("--") {
+            let line_end = body.find('\n').unwrap_or(body.len());
+            pos = start + 2 + line_end;
+            comments.push(LeadingComment { body: &body[..line_end], start, end: pos });
+        } else {
+            break;
+        }
+    }
+    comments
+}
+
+fn hint_body(comment: &str) -> Option<&str> {
+    let comment = comment.trim();
+    // A bare `/* shard_key=... */` is accepted without the prefix
+    comment
+        .strip_prefix(HINT_PREFIX)
+        .or_else(|| comment.starts_with("shard_key=").then_some(comment))
+}
+
+pub fn parse_hints(sql: &str) -> QueryHints {
+    let mut hints = QueryHints::default();
+
+    for comment in leading_comments(sql) {
+        let body = match hint_body(comment.body) {
+            Some(body) => body,
+            None => continue,
+        };
+
+        for item in body.split(|c: char| c.is_whitespace() || c == ',').filter(|s| !s.is_empty()) {
+            let (key, value) = item.split_once('=').unwrap_or((item, ""));
+            match (key.to_lowercase().as_str(), value) {
+                ("route" | "target", "primary" | "master") => hints.route = Some(RouteHint::Primary),
+                ("route" | "target", "replica" | "standby") => hints.route = Some(RouteHint::Replica),
+                ("host", name) if !name.is_empty() => hints.host = Some(name.to_string()),
+                ("cache_ttl", ttl) if parse_duration(ttl).is_some() => hints.cache_ttl = parse_duration(ttl),
+                ("stale_ttl", ttl) if parse_duration(ttl).is_some() => hints.stale_ttl = parse_duration(ttl),
+                ("nocache", _) => hints.nocache = true,
+                ("shard_key", key) if !key.is_empty() => hints.shard_key = Some(key.to_string()),
+                ("timeout", timeout) if parse_duration(timeout).is_some() => hints.timeout = parse_duration(timeout),
+                _ => log::warn!("Ignoring unknown pgShield query hint {}", item),
+            }
+        }
+    }
+
+    hints
+}
+
+/// Removes pgShield hint comments from the start of `sql`, keeping any other comments.
+pub fn strip_hints(sql: &str) -> String {
+    let comments = leading_comments(sql);
+    if !comments.iter().any(|c| hint_body(c.body).is_some()) {
+        return sql.to_string();
+    }
+
+    let mut stripped = String::with_capacity(sql.len());
+    let mut pos = 0;
+    for comment in comments.iter().filter(|c| hint_body(c.body).is_some()) {
+        stripped.push_str(&sql[pos..comment.start]);
+        pos = comment.end;
+    }
+    stripped.push_str(sql[pos..].trim_start());
+    stripped
+}
+
+/// Parses `5s`, `500ms`, `2m` or a bare number of seconds, up to a year.
+fn parse_duration(value: &str) -> Option<Duration> {
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(split) => value.split_at(split),
+        None => (value, "s"),
+    };
+    let number: u64 = number.parse().ok()?;
+    let duration = match unit {
+        "ms" => Duration::from_millis(number),
+        "s" => Duration::from_secs(number),
+        "m" | "min" => Duration::from_secs(number.checked_mul(60)?),
+        _ => return None,
+    };
+    (duration <= MAX_DURATION).then_some(duration)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hints() {
+        let hints = parse_hints("/* pgshield: route=replica nocache timeout=500ms */ -- shard_key=42\nSELECT 1");
+        assert_eq!(hints.route, Some(RouteHint::Replica));
+        assert!(hints.nocache);
+        assert_eq!(hints.timeout, Some(Duration::from_millis(500)));
+        assert_eq!(hints.shard_key.as_deref(), Some("42"));
+
+        let hints = parse_hints("/* pgshield: host=replica-1, cache_ttl=2m stale_ttl=30 */ SELECT 1");
+        assert_eq!(hints.host.as_deref(), Some("replica-1"));
+        assert_eq!(hints.cache_ttl, Some(Duration::from_secs(120)));
+        assert_eq!(hints.stale_ttl, Some(Duration::from_secs(30)));
+
+        // Only leading comments carry hints
+        assert!(parse_hints("SELECT 1 /* pgshield: route=primary */").is_empty());
+        assert!(parse_hints("/* an ordinary comment */ SELECT 1").is_empty());
+    }
+
+    #[test]
+    fn out_of_range_durations_are_ignored() {
+        for sql in [
+            "/* pgshield: timeout=99999999999999999m */ SELECT 1",
+            "/* pgshield: cache_ttl=18446744073709551615m */ SELECT 1",
+            "/* pgshield: stale_ttl=18446744073709551616 */ SELECT 1",
+            "/* pgshield: timeout=400d */ SELECT 1",
+        ] {
+            assert!(parse_hints(sql).is_empty(), "{}", sql);
+        }
+        let hints = parse_hints("/* pgshield: timeout=99999999999999999ms cache_ttl=525600m */ SELECT 1");
+        assert_eq!(hints.timeout, None);
+        assert_eq!(hints.cache_ttl, Some(MAX_DURATION));
+    }
+
+    #[test]
+    fn stripping() {
+        assert_eq!(strip_hints("/* pgshield: nocache */ SELECT 1"), "SELECT 1");
+        assert_eq!(
+            strip_hints("/* app: orders */ /* pgshield: route=primary */ SELECT 1"),
+            "/* app: orders */ SELECT 1"
+        );
+        assert_eq!(strip_hints("/* shard_key=7 */\n-- pgshield: nocache\nSELECT 1"), "\nSELECT 1");
+        assert_eq!(strip_hints("/* unterminated SELECT 1"), "/* unterminated SELECT 1");
+        assert_eq!(strip_hints("SELECT 1"), "SELECT 1");
+    }
+}
@@ -0,0 +1,320 @@
+/// A lexical token of a PostgreSQL statement. Comments and whitespace are dropped.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    /// Unquoted keyword or identifier, folded to lower case like the server does.
+    Word(String),
+    /// Double-quoted identifier, case preserved.
+    QuotedIdent(String),
+    /// String literal contents with quoting removed.
+    String(String),
+    Number(String),
+    /// Positional parameter such as `$1`.
+    Param(usize),
+    Symbol(String),
+}
+
+impl Token {
+    pub fn is_word(&self, word: &str) -> bool {
+        matches!(self, Token::Word(w) if w == word)
+    }
+
+    pub fn is_symbol(&self, symbol: &str) -> bool {
+        matches!(self, Token::Symbol(s) if s == symbol)
+    }
+
+    /// Identifier name for both quoted and unquoted identifiers.
+    pub fn ident(&self) -> Option<&str> {
+        match self {
+            Token::Word(w) | Token::QuotedIdent(w) => Some(w),
+            _ => None,
+        }
+    }
+}
+
+/// A token with the byte range it covers in the statement.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Spanned {
+    pub token: Token,
+    pub start: usize,
+    pub end: usize,
+}
+
+const TWO_CHAR_SYMBOLS: [&str; 6] = ["<=", ">=", "<>", "!=", "::", "||"];
+
+pub fn tokenize(sql: &str) -> Vec<Token> {
+    tokenize_spanned(sql).into_iter().map(|spanned| spanned.token).collect()
+}
+
+pub fn tokenize_spanned(sql: &str) -> Vec<Spanned> {
+    let chars: Vec<char> = sql.chars().collect();
+    // Byte offset of every char, plus the end of the string
+    let offsets: Vec<usize> = sql.char_indices().map(|(at, _)| at).chain(std::iter::once(sql.len())).collect();
+    let mut spanned = Vec::new();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let start = i;
+        let count = tokens.len();
+
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '-' && chars.get(i + 1) == Some(&'-') {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+        } else if c == '/' && chars.get(i + 1) == Some(&'*') {
+            i = skip_block_comment(&chars, i);
+        } else if c == '\'' {
+            let (value, next) = read_quoted(&chars, i, '\'', false);
+            tokens.push(Token::String(value));
+            i = next;
+        } else if (c == 'e' || c == 'E') && chars.get(i + 1) == Some(&'\'') {
+            let (value, next) = read_quoted(&chars, i + 1, '\'', true);
+            tokens.push(Token::String(value));
+            i = next;
+        } else if c == '"' {
+            let (value, next) = read_quoted(&chars, i, '"', false);
+            tokens.push(Token::QuotedIdent(value));
+            i = next;
+        } else if c == '$' && chars.get(i + 1).map_or(false, |n| n.is_ascii_digit()) {
+            let start = i + 1;
+            i = start;
+            while i < chars.len() && chars[i].is_ascii_digit() {
+                i += 1;
+            }
+            let number: String = chars[start..i].iter().collect();
+            tokens.push(Token::Param(number.parse().unwrap_or(0)));
+        } else if c == '$' {
+            match read_dollar_quoted(&chars, i) {
+                Some((value, next)) => {
+                    tokens.push(Token::String(value));
+                    i = next;
+                }
+                None => {
+                    tokens.push(Token::Symbol(c.to_string()));
+                    i += 1;
+                }
+            }
+        } else if c.is_ascii_digit() || (c == '.' && chars.get(i + 1).map_or(false, |n| n.is_ascii_digit())) {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '.') {
+                i += 1;
+            }
+            tokens.push(Token::Number(chars[start..i].iter().collect()));
+        } else if c.is_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '$') {
+                i += 1;
+            }
+            let word: String = chars[start..i].iter().collect();
+            tokens.push(Token::Word(word.to_lowercase()));
+        } else {
+            let pair: String = chars[i..(i + 2).min(chars.len())].iter().collect();
+            if TWO_CHAR_SYMBOLS.contains(&pair.as_str()) {
+                tokens.push(Token::Symbol(pair));
+                i += 2;
+            } else {
+                tokens.push(Token::Symbol(c.to_string()));
+                i += 1;
+            }
+        }
+
+        if tokens.len() > count {
+            let token = tokens.pop().unwrap();
+            spanned.push(Spanned { token, start: offsets[start], end: offsets[i.min(chars.len())] });
+        }
+    }
+
+    spanned
+}
+
+/// Splits `sql` into its statements on the semicolons outside parentheses,
+/// quotes and comments. Statements without any token are left out.
+pub fn split_statements(sql: &str) -> Vec<&str> {
+    let mut statements = Vec::new();
+    let mut depth = 0usize;
+    let mut start = 0;
+    let mut empty = true;
+    for spanned in tokenize_spanned(sql) {
+        match &spanned.token {
+            Token::Symbol(s) if s == "(" => depth += 1,
+            Token::Symbol(s) if s == ")" => depth = depth.saturating_sub(1),
+            Token::Symbol(s) if s == ";" && depth == 0 => {
+                if !empty {
+                    statements.push(sql[start..spanned.end].trim());
+                }
+                start = spanned.end;
+                empty = true;
+                continue;
+            }
+            _ => {}
+        }
+        empty = false;
+    }
+    if !empty {
+        statements.push(sql[start..].trim());
+    }
+    statements
+}
+
+fn skip_block_comment(chars: &[char], start: usize) -> usize {
+    // Block comments nest in PostgreSQL
+    let mut depth = 0;
+    let mut i = start;
+    while i < chars.len() {
+        if chars[i] == '/' && chars.get(i + 1) == Some(&'*') {
+            depth += 1;
+            i += 2;
+        } else if chars[i] == '*' && chars.get(i + 1) == Some(&'/') {
+            depth -= 1;
+            i += 2;
+            if depth == 0 {
+                break;
+            }
+        } else {
+            i += 1;
+        }
+    }
+    i
+}
+
+fn read_quoted(chars: &[char], start: usize, quote: char, backslash_escapes: bool) -> (String, usize) {
+    let mut value = String::new();
+    let mut i = start + 1;
+    while i < chars.len() {
+        let c = chars[i];
+        if backslash_escapes && c == '\\' && i + 1 < chars.len() {
+            let (decoded, next) = read_escape(chars, i + 1);
+            value.extend(decoded);
+            i = next;
+        } else if c == quote && chars.get(i + 1) == Some(&quote) {
+            value.push(quote);
+            i += 2;
+        } else if c == quote {
+            return (value, i + 1);
+        } else {
+            value.push(c);
+            i += 1;
+        }
+    }
+    (value, i)
+}
+
+/// Decodes the backslash escape of an `E'...'` string whose first character
+/// after the backslash is at `start`, returning the character and where the
+/// escape ends. Invalid code points decode to nothing.
+fn read_escape(chars: &[char], start: usize) -> (Option<char>, usize) {
+    let digits = |radix: u32, max: usize, from: usize| {
+        let end = (from..chars.len().min(from + max))
+            .find(|&j| !chars[j].is_digit(radix))
+            .unwrap_or(chars.len().min(from + max));
+        let code = chars[from..end].iter().fold(0u32, |code, c| code * radix + c.to_digit(radix).unwrap());
+        (code, end)
+    };
+    match chars[start] {
+        'b' => (Some('\u{8}'), start + 1),
+        'f' => (Some('\u{c}'), start + 1),
+        'n' => (Some('\n'), start + 1),
+        'r' => (Some('\r'), start + 1),
+        't' => (Some('\t'), start + 1),
+        '0'..='7' => {
+            let (code, end) = digits(8, 3, start);
+            (char::from_u32(code), end)
+        }
+        'x' if chars.get(start + 1).map_or(false, |c| c.is_ascii_hexdigit()) => {
+            let (code, end) = digits(16, 2, start + 1);
+            (char::from_u32(code), end)
+        }
+        'u' | 'U' => {
+            let width = if chars[start] == 'u' { 4 } else { 8 };
+            let (code, end) = digits(16, width, start + 1);
+            (char::from_u32(code), end)
+        }
+        other => (Some(other), start + 1),
+    }
+}
+
+fn read_dollar_quoted(chars: &[char], start: usize) -> Option<(String, usize)> {
+    let mut i = start + 1;
+    while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+        i += 1;
+    }
+    if chars.get(i) != Some(&'$') {
+        return None;
+    }
+    let tag: Vec<char> = chars[start..=i].to_vec();
+    let body_start = i + 1;
+
+    let mut j = body_start;
+    while j + tag.len() <= chars.len() {
+        if chars[j..j + tag.len()] == tag[..] {
+            return Some((chars[body_start..j].iter().collect(), j + tag.len()));
+        }
+        j += 1;
+    }
+    Some((chars[body_start..].iter().collect(), chars.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_statements() {
+        assert_eq!(
+            split_statements("SELECT 1; ; INSERT INTO t VALUES (';') ;\n-- done"),
+            vec!["SELECT 1;", "INSERT INTO t VALUES (';') ;"]
+        );
+        assert_eq!(split_statements("SELECT (1; 2)"), vec!["SELECT (1; 2)"]);
+        assert!(split_statements("  -- nothing\n").is_empty());
+    }
+
+    #[test]
+    fn tokens() {
+        assert_eq!(
+            tokenize("SELECT \"Mixed\", 'it''s', E'a\\'b', $1, 1.5e3 FROM T -- comment\n/* a /* nested */ comment */ x <> $tag$ $$ $tag$"),
+            vec![
+                Token::Word("select".into()),
+                Token::QuotedIdent("Mixed".into()),
+                Token::Symbol(",".into()),
+                Token::String("it's".into()),
+                Token::Symbol(",".into()),
+                Token::String("a'b".into()),
+                Token::Symbol(",".into()),
+                Token::Param(1),
+                Token::Symbol(",".into()),
+                Token::Number("1.5e3".into()),
+                Token::Word("from".into()),
+                Token::Word("t".into()),
+                Token::Word("x".into()),
+                Token::Symbol("<>".into()),
+                Token::String(" $$ ".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn escapes() {
+        let sql = r"E'\n', e'a\tb', E'\\', E'\101\x41\u0041', E'\q', 'a\n'";
+        assert_eq!(
+            tokenize(sql).into_iter().filter(|t| matches!(t, Token::String(_))).collect::<Vec<_>>(),
+            vec![
+                Token::String("\n".into()),
+                Token::String("a\tb".into()),
+                Token::String("\\".into()),
+                Token::String("AAA".into()),
+                Token::String("q".into()),
+                Token::String("a\\n".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn spans_cover_the_source() {
+        let sql = "select  'é', x::int";
+        let spans: Vec<_> = tokenize_spanned(sql).iter().map(|t| &sql[t.start..t.end]).collect();
+        assert_eq!(spans, vec!["select", "'é'", ",", "x", "::", "int"]);
+    }
+}
@@ -1,3 +1,12 @@
+pub mod classifier;
+pub mod fingerprint;
+pub mod hints;
+pub mod lexer;
+pub mod scatter;
+pub mod settings;
+pub mod sharding;
+pub mod tables;
+
 use serde::de::DeserializeOwned;
 use serde_json::Value;
 use log;
@@ -6,6 +15,14 @@ use std::fmt;
 
 use lib_pgsqlcli::{client::PostgresClient, error::PostgresError, connection::PostgresValue};  // Adjusted the imports
 
+pub use classifier::{classify, StatementKind};
+pub use fingerprint::{cache_key, canonical, fingerprint, normalize};
+pub use hints::{parse_hints, QueryHints};
+pub use scatter::{plan_scatter, ScatterPlan};
+pub use settings::{parse_setting, SettingChange};
+pub use sharding::extract_shard_key;
+pub use tables::{tables, TableAccess};
+
 #[derive(Debug)]
 pub enum MyError {
     Postgres(PostgresError),
@@ -0,0 +1,723 @@
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+
+use lib_pgsqlcli::{PostgresValue, QueryResult, Row};
+
+use crate::lexer::{tokenize_spanned, Spanned, Token};
+
+const NUMERIC_OID: u32 = 1700;
+
+const MERGEABLE_AGGREGATES: [&str; 4] = ["count", "sum", "min", "max"];
+const UNSUPPORTED_AGGREGATES: [&str; 14] = [
+    "avg", "array_agg", "string_agg", "json_agg", "jsonb_agg", "json_object_agg", "jsonb_object_agg",
+    "bool_and", "bool_or", "every", "bit_and", "bit_or", "stddev", "variance",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Aggregate {
+    Count,
+    Sum,
+    Min,
+    Max,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum SortColumn {
+    /// 1-based output column position, as in `ORDER BY 2`.
+    Position(usize),
+    Name(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct SortKey {
+    column: SortColumn,
+    descending: bool,
+    nulls_first: bool,
+}
+
+/// How to run a read on every shard and combine the shard results into the
+/// answer a single server would have given.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScatterPlan {
+    /// Statement sent to each shard: the original with LIMIT/OFFSET rewritten.
+    pub shard_sql: String,
+    /// Aggregate computed by each output column; empty when there are none.
+    aggregates: Vec<Option<Aggregate>>,
+    distinct: bool,
+    order_by: Vec<SortKey>,
+    limit: Option<u64>,
+    offset: u64,
+}
+
+fn unsupported(what: &str) -> String {
+    format!("{} is not supported in a query spanning every shard", what)
+}
+
+/// Parenthesis depth before each token.
+fn depths(tokens: &[Spanned]) -> Vec<usize> {
+    let mut depth = 0usize;
+    tokens
+        .iter()
+        .map(|t| {
+            if t.token.is_symbol(")") {
+                depth = depth.saturating_sub(1);
+            }
+            let at = depth;
+            if t.token.is_symbol("(") {
+                depth += 1;
+            }
+            at
+        })
+        .collect()
+}
+
+/// Splits `range` of `tokens` on top-level commas.
+fn split_commas(tokens: &[Spanned], depth: &[usize], start: usize, end: usize) -> Vec<(usize, usize)> {
+    let mut items = Vec::new();
+    let mut from = start;
+    for i in start..end {
+        if depth[i] == 0 && tokens[i].token.is_symbol(",") {
+            items.push((from, i));
+            from = i + 1;
+        }
+    }
+    if from < end {
+        items.push((from, end));
+    }
+    items
+}
+
+/// Index of the `)` closing the `(` at `open`.
+fn closing_paren(tokens: &[Spanned], depth: &[usize], open: usize) -> Option<usize> {
+    (open + 1..tokens.len()).find(|&i| depth[i] == depth[open] && tokens[i].token.is_symbol(")"))
+}
+
+/// Plans running `sql` on every shard. Supports plain SELECTs with DISTINCT,
+/// ORDER BY output columns, LIMIT/OFFSET and count/sum/min/max aggregates,
+/// optionally grouped by output columns; anything else is refused with the reason.
+pub fn plan_scatter(sql: &str) -> Result<ScatterPlan, String> {
+    let tokens = tokenize_spanned(sql);
+    let depth = depths(&tokens);
+    let at_top = |i: usize, word: &str| depth[i] == 0 && tokens[i].token.is_word(word);
+
+    if !tokens.first().map_or(false, |t| t.token.is_word("select")) {
+        return Err("only SELECT statements can run on every shard".into());
+    }
+    for (i, t) in tokens.iter().enumerate() {
+        for word in ["union", "intersect", "except", "having", "window", "fetch", "into"] {
+            if at_top(i, word) {
+                return Err(unsupported(&word.to_uppercase()));
+            }
+        }
+        if t.token.is_word("over") && tokens.get(i + 1).map_or(false, |n| n.token.is_symbol("(")) {
+            return Err(unsupported("a window function"));
+        }
+    }
+
+    let mut list_start = 1;
+    let mut distinct = false;
+    if tokens.get(1).map_or(false, |t| t.token.is_word("distinct")) {
+        if tokens.get(2).map_or(false, |t| t.token.is_word("on")) {
+            return Err(unsupported("DISTINCT ON"));
+        }
+        distinct = true;
+        list_start = 2;
+    } else if tokens.get(1).map_or(false, |t| t.token.is_word("all")) {
+        list_start = 2;
+    }
+
+    let clause_end = |from: usize, words: &[&str]| {
+        (from..tokens.len())
+            .find(|&i| (depth[i] == 0 && tokens[i].token.is_symbol(";")) || words.iter().any(|w| at_top(i, w)))
+            .unwrap_or(tokens.len())
+    };
+
+    // Select list: aggregate and output name of every item
+    let list_end = clause_end(list_start, &["from", "where", "group", "order", "limit", "offset", "for"]);
+    let mut aggregates = Vec::new();
+    let mut names = Vec::new();
+    for (start, end) in split_commas(&tokens, &depth, list_start, list_end) {
+        let (aggregate, name) = select_item(&tokens, &depth, start, end)?;
+        aggregates.push(aggregate);
+        names.push(name);
+    }
+    let has_aggregates = aggregates.iter().any(Option::is_some);
+    if has_aggregates && names.iter().any(|name| name.as_deref() == Some("*")) {
+        return Err(unsupported("mixing * with aggregates"));
+    }
+
+    // Rows are regrouped by the plain output columns, so grouping columns must be among them
+    if let Some(group) = (0..tokens.len()).find(|&i| at_top(i, "group")) {
+        let end = clause_end(group + 2, &["order", "limit", "offset", "for"]);
+        for (start, item_end) in split_commas(&tokens, &depth, group + 2, end) {
+            let column = output_column(&tokens[start..item_end])
+                .ok_or_else(|| unsupported("GROUP BY on an expression"))?;
+            let selected = match &column {
+                SortColumn::Position(n) => *n >= 1 && *n <= names.len(),
+                SortColumn::Name(name) => names.iter().any(|n| n.as_deref() == Some(name.as_str())),
+            };
+            if !selected {
+                return Err(unsupported("GROUP BY on a column that is not selected"));
+            }
+        }
+        if !has_aggregates {
+            distinct = true;
+        }
+    }
+
+    let mut order_by = Vec::new();
+    if let Some(order) = (0..tokens.len()).find(|&i| at_top(i, "order")) {
+        let end = clause_end(order + 2, &["limit", "offset", "for"]);
+        for (start, item_end) in split_commas(&tokens, &depth, order + 2, end) {
+            order_by.push(sort_key(&tokens[start..item_end])?);
+        }
+    }
+
+    // LIMIT and OFFSET must close the statement so they can be cut off and re-added
+    let mut limit = None;
+    let mut offset = 0;
+    let mut cut = None;
+    let mut i = 0;
+    while i < tokens.len() {
+        if at_top(i, "limit") || at_top(i, "offset") {
+            cut.get_or_insert(tokens[i].start);
+            let value = match tokens.get(i + 1).map(|t| &t.token) {
+                Some(Token::Number(n)) => Some(n.parse::<u64>().map_err(|_| unsupported("a non-integer LIMIT"))?),
+                Some(Token::Word(w)) if w == "all" && tokens[i].token.is_word("limit") => None,
+                _ => return Err(unsupported("a LIMIT or OFFSET that is not a constant")),
+            };
+            if tokens[i].token.is_word("limit") {
+                limit = value;
+            } else {
+                offset = value.unwrap_or(0);
+            }
+            i += 2;
+            if tokens.get(i).map_or(false, |t| t.token.is_word("row") || t.token.is_word("rows")) {
+                i += 1;
+            }
+        } else if cut.is_some() && !tokens[i].token.is_symbol(";") {
+            return Err(unsupported("a clause after LIMIT or OFFSET"));
+        } else {
+            i += 1;
+        }
+    }
+
+    let mut shard_sql = match cut {
+        Some(cut) => sql[..cut].trim_end().to_string(),
+        None => sql.trim_end().trim_end_matches(';').to_string(),
+    };
+    // Each shard must return enough rows to fill the page; grouped rows are only
+    // complete after merging, so those queries fetch everything
+    if let (Some(limit), false) = (limit, has_aggregates) {
+        shard_sql.push_str(&format!(" LIMIT {}", limit + offset));
+    }
+
+    Ok(ScatterPlan {
+        shard_sql,
+        aggregates: if has_aggregates { aggregates } else { Vec::new() },
+        distinct,
+        order_by,
+        limit,
+        offset,
+    })
+}
+
+/// The aggregate computed by a select list item and its output column name.
+fn select_item(
+    tokens: &[Spanned],
+    depth: &[usize],
+    start: usize,
+    end: usize,
+) -> Result<(Option<Aggregate>, Option<String>), String> {
+    let item = &tokens[start..end];
+    let is_call = |i: usize, names: &[&str]| {
+        item[i].token.ident().map_or(false, |name| names.contains(&name))
+            && item.get(i + 1).map_or(false, |t| t.token.is_symbol("("))
+    };
+
+    if let Some(i) = (0..item.len()).find(|&i| is_call(i, &UNSUPPORTED_AGGREGATES)) {
+        return Err(unsupported(&format!("{}()", item[i].token.ident().unwrap_or_default())));
+    }
+
+    // Output name: an alias, or the column of a bare column reference
+    let alias = match item {
+        [.., t, last] if t.token.is_word("as") => last.token.ident().map(str::to_string),
+        [.., t, last] if t.token.is_symbol(")") => last.token.ident().map(str::to_string),
+        [first, last] if first.token.ident().is_some() => last.token.ident().map(str::to_string),
+        _ => None,
+    };
+    let name = alias.clone().or_else(|| match output_column(item) {
+        Some(SortColumn::Name(name)) => Some(name),
+        _ if item.last().map_or(false, |t| t.token.is_symbol("*")) => Some("*".to_string()),
+        _ => None,
+    });
+
+    if !(0..item.len()).any(|i| is_call(i, &MERGEABLE_AGGREGATES)) {
+        return Ok((None, name));
+    }
+
+    // Only an aggregate call on its own, optionally aliased, can be merged
+    let rest = match is_call(0, &MERGEABLE_AGGREGATES) {
+        true => closing_paren(tokens, depth, start + 1).map(|close| end - close - 1),
+        false => None,
+    };
+    let aliased_only = match rest {
+        Some(0) => true,
+        Some(1) => alias.is_some(),
+        Some(2) => item[item.len() - 2].token.is_word("as") && alias.is_some(),
+        _ => false,
+    };
+    if !aliased_only {
+        return Err(unsupported("an aggregate inside an expression"));
+    }
+    if item.get(2).map_or(false, |t| t.token.is_word("distinct")) {
+        return Err(unsupported("an aggregate over DISTINCT values"));
+    }
+
+    let aggregate = match item[0].token.ident() {
+        Some("count") => Aggregate::Count,
+        Some("sum") => Aggregate::Sum,
+        Some("min") => Aggregate::Min,
+        _ => Aggregate::Max,
+    };
+    // Unaliased aggregates are named after the function
+    let name = alias.or_else(|| item[0].token.ident().map(str::to_string));
+    Ok((Some(aggregate), name))
+}
+
+/// A column reference (`col`, `t.col`) or output position (`2`).
+fn output_column(item: &[Spanned]) -> Option<SortColumn> {
+    match item {
+        [Spanned { token: Token::Number(n), .. }] => n.parse().ok().map(SortColumn::Position),
+        [single] => single.token.ident().map(|name| SortColumn::Name(name.to_string())),
+        [qualifier, dot, column] if qualifier.token.ident().is_some() && dot.token.is_symbol(".") => {
+            column.token.ident().map(|name| SortColumn::Name(name.to_string()))
+        }
+        _ => None,
+    }
+}
+
+fn sort_key(item: &[Spanned]) -> Result<SortKey, String> {
+    let mut end = item.len();
+    let mut nulls_first = None;
+    if end >= 2 && item[end - 2].token.is_word("nulls") {
+        nulls_first = Some(item[end - 1].token.is_word("first"));
+        end -= 2;
+    }
+    let mut descending = false;
+    if end >= 1 && (item[end - 1].token.is_word("asc") || item[end - 1].token.is_word("desc")) {
+        descending = item[end - 1].token.is_word("desc");
+        end -= 1;
+    }
+    let column = output_column(&item[..end]).ok_or_else(|| unsupported("ORDER BY on an expression"))?;
+    Ok(SortKey {
+        column,
+        descending,
+        // PostgreSQL sorts NULLs as larger than any value
+        nulls_first: nulls_first.unwrap_or(descending),
+    })
+}
+
+impl ScatterPlan {
+    /// Combines the shard results: regroups aggregates, removes duplicates,
+    /// sorts, and applies LIMIT/OFFSET.
+    pub fn merge(&self, results: Vec<QueryResult>) -> Result<QueryResult, String> {
+        let columns = results
+            .iter()
+            .map(|result| &result.columns)
+            .find(|columns| !columns.is_empty())
+            .cloned()
+            .unwrap_or_default();
+        let types: Vec<u32> = columns.iter().map(|(_, type_oid)| *type_oid).collect();
+        let mut rows: Vec<Row> = results.into_iter().flat_map(|result| result.rows).collect();
+
+        if !self.aggregates.is_empty() {
+            rows = self.regroup(rows, &types)?;
+        }
+
+        if self.distinct {
+            let mut seen = HashSet::new();
+            rows.retain(|row| seen.insert(row_key(row.iter().map(|(_, value)| value))));
+        }
+
+        if !self.order_by.is_empty() && !rows.is_empty() {
+            let mut keys = Vec::with_capacity(self.order_by.len());
+            for key in &self.order_by {
+                let index = match &key.column {
+                    SortColumn::Position(n) => n.checked_sub(1).filter(|&i| i < rows[0].len()),
+                    SortColumn::Name(name) => rows[0].iter().position(|(column, _)| column == name),
+                };
+                keys.push((index.ok_or_else(|| unsupported("ORDER BY on a column that is not selected"))?, key));
+            }
+            rows.sort_by(|a, b| {
+                keys.iter()
+                    .map(|(i, key)| compare_sorted(&a[*i].1, &b[*i].1, type_of(&types, *i), key))
+                    .find(|ordering| *ordering != Ordering::Equal)
+                    .unwrap_or(Ordering::Equal)
+            });
+        }
+
+        let offset = (self.offset as usize).min(rows.len());
+        rows.drain(..offset);
+        if let Some(limit) = self.limit {
+            rows.truncate(limit as usize);
+        }
+
+        Ok(QueryResult {
+            columns,
+            command_tag: format!("SELECT {}", rows.len()),
+            rows,
+        })
+    }
+
+    /// Folds rows that share their plain column values into one row. `types`
+    /// are the column type OIDs.
+    fn regroup(&self, rows: Vec<Row>, types: &[u32]) -> Result<Vec<Row>, String> {
+        let mut groups: Vec<Row> = Vec::new();
+        let mut index = HashMap::new();
+
+        for row in rows {
+            if row.len() != self.aggregates.len() {
+                return Err("shards returned rows of different shapes".into());
+            }
+            let key = row_key(row.iter().zip(&self.aggregates).filter(|(_, a)| a.is_none()).map(|((_, v), _)| v));
+            match index.get(&key) {
+                None => {
+                    index.insert(key, groups.len());
+                    groups.push(row);
+                }
+                Some(&at) => {
+                    let group = &mut groups[at];
+                    for (i, (_, value)) in row.into_iter().enumerate() {
+                        if let Some(aggregate) = self.aggregates[i] {
+                            group[i].1 = fold(aggregate, &group[i].1, value, type_of(types, i))?;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(groups)
+    }
+}
+
+fn row_key<'a>(values: impl Iterator<Item = &'a PostgresValue>) -> String {
+    values.map(|value| format!("{:?}", value)).collect::<Vec<_>>().join("\u{1f}")
+}
+
+fn type_of(types: &[u32], column: usize) -> u32 {
+    types.get(column).copied().unwrap_or(0)
+}
+
+fn fold(aggregate: Aggregate, current: &PostgresValue, value: PostgresValue, type_oid: u32) -> Result<PostgresValue, String> {
+    if value == PostgresValue::Null {
+        return Ok(current.clone());
+    }
+    if *current == PostgresValue::Null {
+        return Ok(value);
+    }
+    Ok(match aggregate {
+        Aggregate::Count | Aggregate::Sum => add(current, &value)?,
+        Aggregate::Min if compare(&value, current, type_oid) == Ordering::Less => value,
+        Aggregate::Max if compare(&value, current, type_oid) == Ordering::Greater => value,
+        _ => current.clone(),
+    })
+}
+
+fn as_i64(value: &PostgresValue) -> Option<i64> {
+    match value {
+        PostgresValue::Int16(i) => Some(*i as i64),
+        PostgresValue::Int32(i) => Some(*i as i64),
+        PostgresValue::Int64(i) => Some(*i),
+        _ => None,
+    }
+}
+
+fn as_f64(value: &PostgresValue) -> Option<f64> {
+    match value {
+        PostgresValue::Float32(f) => Some(*f as f64),
+        PostgresValue::Float64(f) => Some(*f),
+        PostgresValue::String(s) => s.parse().ok(),
+        _ => as_i64(value).map(|i| i as f64),
+    }
+}
+
+fn add(a: &PostgresValue, b: &PostgresValue) -> Result<PostgresValue, String> {
+    let sum = match (a, b) {
+        (PostgresValue::Float32(x), PostgresValue::Float32(y)) => Some(PostgresValue::Float32(x + y)),
+        (PostgresValue::Float32(_) | PostgresValue::Float64(_), _) | (_, PostgresValue::Float32(_) | PostgresValue::Float64(_)) => {
+            as_f64(a).zip(as_f64(b)).map(|(x, y)| PostgresValue::Float64(x + y))
+        }
+        // numeric arrives as text; add it exactly
+        (PostgresValue::String(x), PostgresValue::String(y)) => add_decimal(x, y).map(PostgresValue::String),
+        _ => as_i64(a).zip(as_i64(b)).and_then(|(x, y)| x.checked_add(y)).map(PostgresValue::Int64),
+    };
+    sum.ok_or_else(|| format!("cannot add shard results {:?} and {:?}", a, b))
+}
+
+fn add_decimal(a: &str, b: &str) -> Option<String> {
+    let fraction = |s: &str| s.split_once('.').map_or(0, |(_, f)| f.len());
+    let scale = fraction(a).max(fraction(b));
+    let scaled = |s: &str| -> Option<i128> {
+        let (int, frac) = s.split_once('.').unwrap_or((s, ""));
+        format!("{}{:0<width$}", int, frac, width = scale).parse().ok()
+    };
+    let sum = scaled(a)?.checked_add(scaled(b)?)?;
+    if scale == 0 {
+        return Some(sum.to_string());
+    }
+    let digits = format!("{:0>width$}", sum.unsigned_abs(), width = scale + 1);
+    let (int, frac) = digits.split_at(digits.len() - scale);
+    Some(format!("{}{}.{}", if sum < 0 { "-" } else { "" }, int, frac))
+}
+
+/// Orders two non-NULL values of a column of type `type_oid`, totally so
+/// sorting is well defined. numeric columns arrive as text and compare as exact
+/// decimals; other text compares bytewise rather than by the server's collation.
+/// Like the server, NaN sorts above every other number.
+fn compare(a: &PostgresValue, b: &PostgresValue, type_oid: u32) -> Ordering {
+    match (a, b) {
+        (PostgresValue::String(x), PostgresValue::String(y)) if type_oid == NUMERIC_OID => compare_numeric(x, y),
+        (PostgresValue::String(x), PostgresValue::String(y)) => x.cmp(y),
+        (PostgresValue::Boolean(x), PostgresValue::Boolean(y)) => x.cmp(y),
+        (PostgresValue::Bytes(x), PostgresValue::Bytes(y)) => x.cmp(y),
+        _ => match (as_i64(a), as_i64(b)) {
+            (Some(x), Some(y)) => x.cmp(&y),
+            _ => match (number(a), number(b)) {
+                (Some(x), Some(y)) => compare_floats(x, y),
+                // Values of one column share a type; order mismatches by kind
+                _ => rank(a).cmp(&rank(b)),
+            },
+        },
+    }
+}
+
+/// Integers and floats as f64; text is never read as a number here.
+fn number(value: &PostgresValue) -> Option<f64> {
+    match value {
+        PostgresValue::Float32(f) => Some(*f as f64),
+        PostgresValue::Float64(f) => Some(*f),
+        _ => as_i64(value).map(|i| i as f64),
+    }
+}
+
+fn rank(value: &PostgresValue) -> u8 {
+    match value {
+        PostgresValue::Null => 0,
+        PostgresValue::Boolean(_) => 1,
+        PostgresValue::Int16(_) | PostgresValue::Int32(_) | PostgresValue::Int64(_) => 2,
+        PostgresValue::Float32(_) | PostgresValue::Float64(_) => 2,
+        PostgresValue::String(_) => 3,
+        PostgresValue::Bytes(_) => 4,
+    }
+}
+
+fn compare_floats(x: f64, y: f64) -> Ordering {
+    match (x.is_nan(), y.is_nan()) {
+        (true, true) => Ordering::Equal,
+        (true, false) => Ordering::Greater,
+        (false, true) => Ordering::Less,
+        (false, false) => x.partial_cmp(&y).unwrap_or(Ordering::Equal),
+    }
+}
+
+/// Compares numeric values in their text form exactly, with -Infinity below
+/// and Infinity, then NaN, above every finite value. Unparsable text sorts last.
+fn compare_numeric(a: &str, b: &str) -> Ordering {
+    fn class(s: &str) -> u8 {
+        match s {
+            "-Infinity" => 0,
+            "Infinity" => 2,
+            "NaN" => 3,
+            _ if parse_decimal(s).is_some() => 1,
+            _ => 4,
+        }
+    }
+    match (class(a), class(b)) {
+        (1, 1) => {}
+        (x, y) if x != y => return x.cmp(&y),
+        _ => return a.cmp(b),
+    }
+    let ((negative_a, int_a, frac_a), (negative_b, int_b, frac_b)) = match (parse_decimal(a), parse_decimal(b)) {
+        (Some(a), Some(b)) => (a, b),
+        _ => return Ordering::Equal,
+    };
+    let zero = |int: &str, frac: &str| int.is_empty() && frac.is_empty();
+    // -0 and 0 are equal
+    let negative_a = negative_a && !zero(int_a, frac_a);
+    let negative_b = negative_b && !zero(int_b, frac_b);
+    if negative_a != negative_b {
+        return if negative_a { Ordering::Less } else { Ordering::Greater };
+    }
+    let width = frac_a.len().max(frac_b.len());
+    let magnitude = int_a
+        .len()
+        .cmp(&int_b.len())
+        .then_with(|| int_a.cmp(int_b))
+        .then_with(|| format!("{:0<width$}", frac_a).cmp(&format!("{:0<width$}", frac_b)));
+    if negative_a {
+        magnitude.reverse()
+    } else {
+        magnitude
+    }
+}
+
+/// Sign, integer digits without leading zeros and fraction digits without
+/// trailing zeros of a decimal such as `-012.50`.
+fn parse_decimal(s: &str) -> Option<(bool, &str, &str)> {
+    let (negative, digits) = match s.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, s.strip_prefix('+').unwrap_or(s)),
+    };
+    let (int, frac) = digits.split_once('.').unwrap_or((digits, ""));
+    if (int.is_empty() && frac.is_empty()) || !int.bytes().chain(frac.bytes()).all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some((negative, int.trim_start_matches('0'), frac.trim_end_matches('0')))
+}
+
+fn compare_sorted(a: &PostgresValue, b: &PostgresValue, type_oid: u32, key: &SortKey) -> Ordering {
+    match (a == &PostgresValue::Null, b == &PostgresValue::Null) {
+        (true, true) => Ordering::Equal,
+        (true, false) if key.nulls_first => Ordering::Less,
+        (true, false) => Ordering::Greater,
+        (false, true) if key.nulls_first => Ordering::Greater,
+        (false, true) => Ordering::Less,
+        (false, false) if key.descending => compare(b, a, type_oid),
+        (false, false) => compare(a, b, type_oid),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INT4: u32 = 23;
+    const TEXT: u32 = 25;
+    const FLOAT8: u32 = 701;
+
+    fn shard(columns: &[(&str, u32)], rows: Vec<Vec<PostgresValue>>) -> QueryResult {
+        let columns: Vec<(String, u32)> = columns.iter().map(|(name, oid)| (name.to_string(), *oid)).collect();
+        QueryResult {
+            rows: rows
+                .into_iter()
+                .map(|row| columns.iter().map(|(name, _)| name.clone()).zip(row).collect())
+                .collect(),
+            command_tag: String::new(),
+            columns,
+        }
+    }
+
+    fn text(s: &str) -> PostgresValue {
+        PostgresValue::String(s.to_string())
+    }
+
+    fn column(result: &QueryResult, index: usize) -> Vec<PostgresValue> {
+        result.rows.iter().map(|row| row[index].1.clone()).collect()
+    }
+
+    fn merge(sql: &str, results: Vec<QueryResult>) -> QueryResult {
+        plan_scatter(sql).unwrap().merge(results).unwrap()
+    }
+
+    #[test]
+    fn sorts_and_pages_across_shards() {
+        let plan = plan_scatter("SELECT id FROM t ORDER BY id DESC LIMIT 2 OFFSET 1").unwrap();
+        assert_eq!(plan.shard_sql, "SELECT id FROM t ORDER BY id DESC LIMIT 3");
+        let merged = plan
+            .merge(vec![
+                shard(&[("id", INT4)], vec![vec![PostgresValue::Int32(7)], vec![PostgresValue::Int32(3)]]),
+                shard(&[("id", INT4)], vec![vec![PostgresValue::Int32(9)], vec![PostgresValue::Int32(5)]]),
+            ])
+            .unwrap();
+        assert_eq!(column(&merged, 0), vec![PostgresValue::Int32(7), PostgresValue::Int32(5)]);
+        assert_eq!(merged.command_tag, "SELECT 2");
+        assert_eq!(merged.columns, vec![("id".to_string(), INT4)]);
+    }
+
+    #[test]
+    fn orders_by_column_type() {
+        let values = ["10", "9", "-1.5", "NaN", "2.50", "-Infinity", "-10"];
+        let rows = || values.iter().map(|v| vec![text(v)]).collect::<Vec<_>>();
+
+        let numeric = merge("SELECT n FROM t ORDER BY n", vec![shard(&[("n", NUMERIC_OID)], rows())]);
+        let expected = ["-Infinity", "-10", "-1.5", "2.50", "9", "10", "NaN"];
+        assert_eq!(column(&numeric, 0), expected.iter().map(|v| text(v)).collect::<Vec<_>>());
+
+        // Text that looks like numbers still sorts as text
+        let sorted = merge("SELECT n FROM t ORDER BY n", vec![shard(&[("n", TEXT)], rows())]);
+        let mut expected = values.to_vec();
+        expected.sort();
+        assert_eq!(column(&sorted, 0), expected.iter().map(|v| text(v)).collect::<Vec<_>>());
+
+        let floats = merge(
+            "SELECT f FROM t ORDER BY f DESC",
+            vec![shard(
+                &[("f", FLOAT8)],
+                [1.5, f64::NAN, -2.0, f64::INFINITY].iter().map(|f| vec![PostgresValue::Float64(*f)]).collect(),
+            )],
+        );
+        let floats: Vec<String> = column(&floats, 0).iter().map(|v| format!("{:?}", v)).collect();
+        assert_eq!(floats, ["Float64(NaN)", "Float64(inf)", "Float64(1.5)", "Float64(-2.0)"]);
+    }
+
+    #[test]
+    fn nulls_sort_last_ascending() {
+        let merged = merge(
+            "SELECT id FROM t ORDER BY id",
+            vec![shard(&[("id", INT4)], vec![vec![PostgresValue::Null], vec![PostgresValue::Int32(1)]])],
+        );
+        assert_eq!(column(&merged, 0), vec![PostgresValue::Int32(1), PostgresValue::Null]);
+    }
+
+    #[test]
+    fn regroups_aggregates() {
+        let columns = [("region", TEXT), ("count", 20), ("total", NUMERIC_OID), ("low", NUMERIC_OID), ("high", INT4)];
+        let sql = "SELECT region, count(*), sum(amount) AS total, min(amount) AS low, max(qty) AS high \
+                   FROM orders GROUP BY region ORDER BY region";
+        let merged = merge(
+            sql,
+            vec![
+                shard(
+                    &columns,
+                    vec![
+                        vec![text("eu"), PostgresValue::Int64(2), text("10.25"), text("9"), PostgresValue::Int32(4)],
+                        vec![text("us"), PostgresValue::Int64(1), text("1"), text("1"), PostgresValue::Int32(1)],
+                    ],
+                ),
+                shard(
+                    &columns,
+                    vec![vec![text("eu"), PostgresValue::Int64(3), text("0.75"), text("10"), PostgresValue::Int32(2)]],
+                ),
+            ],
+        );
+        assert_eq!(merged.rows.len(), 2);
+        let eu: Vec<PostgresValue> = merged.rows[0].iter().map(|(_, value)| value.clone()).collect();
+        // min compares the numeric values, not their text
+        assert_eq!(eu, vec![text("eu"), PostgresValue::Int64(5), text("11.00"), text("9"), PostgresValue::Int32(4)]);
+    }
+
+    #[test]
+    fn removes_duplicates() {
+        let merged = merge(
+            "SELECT DISTINCT name FROM t ORDER BY name",
+            vec![
+                shard(&[("name", TEXT)], vec![vec![text("b")], vec![text("a")]]),
+                shard(&[("name", TEXT)], vec![vec![text("a")]]),
+            ],
+        );
+        assert_eq!(column(&merged, 0), vec![text("a"), text("b")]);
+    }
+
+    #[test]
+    fn refuses_what_cannot_be_merged() {
+        for sql in [
+            "SELECT avg(x) FROM t",
+            "SELECT id FROM t ORDER BY lower(name)",
+            "SELECT count(*) + 1 FROM t",
+            "SELECT DISTINCT ON (a) a FROM t",
+            "UPDATE t SET a = 1",
+        ] {
+            assert!(plan_scatter(sql).is_err(), "{}", sql);
+        }
+    }
+}
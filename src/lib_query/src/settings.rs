@@ -0,0 +1,95 @@
+use crate::lexer::{tokenize, Token};
+
+/// A change to session settings made by a statement.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SettingChange {
+    /// `SET name = value`, including `SET ROLE`, `SET TIME ZONE` and
+    /// `SET SESSION AUTHORIZATION`, which set `role`, `timezone` and
+    /// `session_authorization`.
+    Set { name: String, value: String },
+    /// `RESET name` or `SET name TO DEFAULT`.
+    Reset(String),
+    /// `RESET ALL`: every setting except the session authorization.
+    ResetAll,
+    /// `DISCARD ALL`: the session returns to its state right after login.
+    DiscardAll,
+    /// A change pgShield cannot follow, such as a `set_config()` call.
+    Unknown,
+}
+
+fn value_text(tokens: &[Token]) -> String {
+    tokens
+        .iter()
+        .map(|token| match token {
+            Token::Word(w) | Token::QuotedIdent(w) | Token::String(w) | Token::Number(w) | Token::Symbol(w) => w.clone(),
+            Token::Param(n) => format!("${}", n),
+        })
+        .collect()
+}
+
+/// The session setting change made by `sql`, if any. `SET LOCAL` and
+/// `SET TRANSACTION` only last until the end of the transaction and are ignored.
+pub fn parse_setting(sql: &str) -> Option<SettingChange> {
+    let tokens = tokenize(sql);
+    if tokens
+        .windows(2)
+        .any(|pair| pair[0].is_word("set_config") && pair[1].is_symbol("("))
+    {
+        return Some(SettingChange::Unknown);
+    }
+
+    let word = |i: usize, word: &str| tokens.get(i).map_or(false, |t| t.is_word(word));
+    match tokens.first()? {
+        t if t.is_word("set") => {
+            let mut at = 1;
+            if word(1, "local") || word(1, "transaction") || word(1, "constraints") {
+                return None;
+            }
+            if word(1, "session") && !word(2, "authorization") {
+                at = 2;
+            }
+            if word(at, "session") && word(at + 1, "characteristics") {
+                return None;
+            }
+
+            let (name, value_at) = if word(at, "role") {
+                ("role".to_string(), at + 1)
+            } else if word(at, "time") && word(at + 1, "zone") {
+                ("timezone".to_string(), at + 2)
+            } else if word(at, "session") && word(at + 1, "authorization") {
+                ("session_authorization".to_string(), at + 2)
+            } else {
+                // Custom settings are qualified, as in `app.tenant_id`
+                let end = (at..tokens.len()).find(|&i| word(i, "to") || tokens[i].is_symbol("="))?;
+                (value_text(&tokens[at..end]), end + 1)
+            };
+
+            let value = &tokens[value_at.min(tokens.len())..];
+            let value = match value.last() {
+                Some(last) if last.is_symbol(";") => &value[..value.len() - 1],
+                _ => value,
+            };
+            match value {
+                [single] if single.is_word("default") || (name == "role" && single.is_word("none")) => {
+                    Some(SettingChange::Reset(name))
+                }
+                [] => None,
+                _ => Some(SettingChange::Set { name, value: value_text(value) }),
+            }
+        }
+        t if t.is_word("reset") => {
+            if word(1, "all") {
+                Some(SettingChange::ResetAll)
+            } else if word(1, "time") && word(2, "zone") {
+                Some(SettingChange::Reset("timezone".into()))
+            } else if word(1, "session") && word(2, "authorization") {
+                Some(SettingChange::Reset("session_authorization".into()))
+            } else {
+                let end = tokens.iter().position(|t| t.is_symbol(";")).unwrap_or(tokens.len());
+                Some(SettingChange::Reset(value_text(&tokens[1..end])))
+            }
+        }
+        t if t.is_word("discard") && word(1, "all") => Some(SettingChange::DiscardAll),
+        _ => None,
+    }
+}
@@ -0,0 +1,222 @@
+use lib_pgsqlcli::PostgresValue;
+
+use crate::lexer::{tokenize, Token};
+
+/// Text form of a bound parameter, as used for shard hashing and ranges.
+pub fn value_text(value: &PostgresValue) -> Option<String> {
+    match value {
+        PostgresValue::Null => None,
+        PostgresValue::Boolean(b) => Some(b.to_string()),
+        PostgresValue::Int16(i) => Some(i.to_string()),
+        PostgresValue::Int32(i) => Some(i.to_string()),
+        PostgresValue::Int64(i) => Some(i.to_string()),
+        PostgresValue::Float32(f) => Some(f.to_string()),
+        PostgresValue::Float64(f) => Some(f.to_string()),
+        PostgresValue::String(s) => Some(s.clone()),
+        PostgresValue::Bytes(b) => Some(hex::encode(b)),
+    }
+}
+
+fn literal(tokens: &[Token], at: usize, params: &[PostgresValue]) -> Option<String> {
+    match tokens.get(at)? {
+        Token::String(s) | Token::Number(s) => Some(s.clone()),
+        Token::Param(n) => params.get(n.checked_sub(1)?).and_then(value_text),
+        Token::Symbol(s) if s == "-" => match tokens.get(at + 1)? {
+            Token::Number(n) => Some(format!("-{}", n)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn is_key(tokens: &[Token], at: usize, key: &str) -> bool {
+    tokens.get(at).and_then(Token::ident) == Some(key)
+        // Skip `key` used as a qualifier, as in `key.column`
+        && !tokens.get(at + 1).map_or(false, |t| t.is_symbol("."))
+}
+
+/// Words ending a WHERE clause at its nesting level.
+const AFTER_WHERE: [&str; 12] = [
+    "select",
+    "set",
+    "returning",
+    "group",
+    "having",
+    "order",
+    "limit",
+    "offset",
+    "window",
+    "union",
+    "intersect",
+    "except",
+];
+
+/// Finds the value of the shard key column in `sql`: an equality or single
+/// element `IN` in the WHERE clause, or the column of an INSERT whose rows all
+/// share one key value. Assignments, as in `UPDATE ... SET key = 5`, and the
+/// select list name no key.
+/// Returns None when the statement may touch more than one key value.
+pub fn extract_shard_key(sql: &str, key: &str, params: &[PostgresValue]) -> Option<String> {
+    let tokens = tokenize(sql);
+
+    // An OR can widen the match to several shards
+    if tokens.iter().any(|t| t.is_word("or")) {
+        return None;
+    }
+
+    // Whether each token is part of a WHERE clause, tracked per nesting level
+    let mut levels = vec![false];
+    let mut in_where = Vec::with_capacity(tokens.len());
+    for token in &tokens {
+        if token.is_symbol("(") {
+            levels.push(*levels.last().unwrap_or(&false));
+        } else if token.is_symbol(")") && levels.len() > 1 {
+            levels.pop();
+        } else if token.is_word("where") {
+            *levels.last_mut().unwrap() = true;
+        } else if AFTER_WHERE.iter().any(|word| token.is_word(word)) {
+            *levels.last_mut().unwrap() = false;
+        }
+        in_where.push(*levels.last().unwrap());
+    }
+
+    let mut values: Vec<String> = Vec::new();
+    for i in 0..tokens.len() {
+        if !in_where[i] || !is_key(&tokens, i, key) {
+            continue;
+        }
+
+        let next = tokens.get(i + 1);
+        if next.map_or(false, |t| t.is_symbol("=")) {
+            values.extend(literal(&tokens, i + 2, params));
+        } else if next.map_or(false, |t| t.is_word("in"))
+            && tokens.get(i + 2).map_or(false, |t| t.is_symbol("("))
+            && tokens.get(i + 4).map_or(false, |t| t.is_symbol(")"))
+        {
+            values.extend(literal(&tokens, i + 3, params));
+        } else if i >= 2 && tokens[i - 1].is_symbol("=") {
+            values.extend(literal(&tokens, i - 2, params));
+        }
+    }
+
+    if tokens.first().map_or(false, |t| t.is_word("insert")) {
+        values.extend(insert_values(&tokens, key, params)?);
+    }
+
+    values.sort();
+    values.dedup();
+    match values.len() {
+        1 => values.pop(),
+        _ => None,
+    }
+}
+
+/// Values given for `key` in `INSERT INTO t (a, key, ...) VALUES (...), (...)`.
+/// Returns None if some row gives the key as an expression.
+fn insert_values(tokens: &[Token], key: &str, params: &[PostgresValue]) -> Option<Vec<String>> {
+    let open = match tokens.iter().position(|t| t.is_symbol("(")) {
+        Some(open) => open,
+        None => return Some(Vec::new()),
+    };
+    let mut columns = Vec::new();
+    let mut i = open + 1;
+    while let Some(token) = tokens.get(i) {
+        if token.is_symbol(")") {
+            break;
+        }
+        if let Some(name) = token.ident() {
+            columns.push(name.to_string());
+        }
+        i += 1;
+    }
+    let column = match columns.iter().position(|c| c == key) {
+        Some(column) => column,
+        None => return Some(Vec::new()),
+    };
+
+    let mut values = Vec::new();
+    let mut i = match tokens.iter().position(|t| t.is_word("values")) {
+        Some(at) => at + 1,
+        None => return Some(Vec::new()),
+    };
+    while tokens.get(i).map_or(false, |t| t.is_symbol("(")) {
+        // Walk one row, counting top-level commas to find the key's position
+        let mut depth = 0;
+        let mut index = 0;
+        let mut row_value = None;
+        i += 1;
+        while let Some(token) = tokens.get(i) {
+            if token.is_symbol("(") {
+                depth += 1;
+            } else if token.is_symbol(")") {
+                if depth == 0 {
+                    break;
+                }
+                depth -= 1;
+            } else if token.is_symbol(",") && depth == 0 {
+                index += 1;
+            } else if index == column && depth == 0 && row_value.is_none() {
+                row_value = literal(tokens, i, params);
+            }
+            i += 1;
+        }
+        values.push(row_value?);
+        i += 1;
+        if tokens.get(i).map_or(false, |t| t.is_symbol(",")) {
+            i += 1;
+        }
+    }
+    Some(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(sql: &str) -> Option<String> {
+        extract_shard_key(sql, "tenant_id", &[])
+    }
+
+    #[test]
+    fn filters() {
+        assert_eq!(key("SELECT * FROM t WHERE tenant_id = 5").as_deref(), Some("5"));
+        assert_eq!(key("SELECT * FROM t WHERE 5 = tenant_id AND id = 1").as_deref(), Some("5"));
+        assert_eq!(key("SELECT * FROM t WHERE t.tenant_id IN ('a')").as_deref(), Some("a"));
+        assert_eq!(key("DELETE FROM t WHERE tenant_id = -3").as_deref(), Some("-3"));
+        let params = [PostgresValue::Int32(7)];
+        assert_eq!(extract_shard_key("SELECT * FROM t WHERE tenant_id = $1", "tenant_id", &params).as_deref(), Some("7"));
+        assert_eq!(key("SELECT * FROM t WHERE tenant_id = 5 OR tenant_id = 6"), None);
+        assert_eq!(key("SELECT * FROM t WHERE tenant_id IN (5, 6)"), None);
+        assert_eq!(key("SELECT * FROM t WHERE tenant_id = 5 AND tenant_id = 6"), None);
+        assert_eq!(key("SELECT * FROM t WHERE tenant_id > 5"), None);
+    }
+
+    #[test]
+    fn assignments_are_not_filters() {
+        assert_eq!(key("UPDATE t SET tenant_id = 5 WHERE id = 3"), None);
+        assert_eq!(key("UPDATE t SET tenant_id = 5 WHERE tenant_id = 4").as_deref(), Some("4"));
+        assert_eq!(key("UPDATE t SET name = 'x' WHERE tenant_id = 4 RETURNING tenant_id = 5").as_deref(), Some("4"));
+        assert_eq!(key("SELECT tenant_id = 5 FROM t"), None);
+        assert_eq!(
+            key("INSERT INTO t (tenant_id, id) VALUES (1, 2) ON CONFLICT (id) DO UPDATE SET tenant_id = 9").as_deref(),
+            Some("1")
+        );
+    }
+
+    #[test]
+    fn subqueries() {
+        assert_eq!(key("SELECT * FROM t WHERE id IN (SELECT id FROM u WHERE tenant_id = 2)").as_deref(), Some("2"));
+        assert_eq!(
+            key("SELECT * FROM t WHERE id IN (SELECT tenant_id = 2 FROM u) AND tenant_id = 3").as_deref(),
+            Some("3")
+        );
+    }
+
+    #[test]
+    fn inserts() {
+        assert_eq!(key("INSERT INTO t (id, tenant_id) VALUES (1, 5), (2, 5)").as_deref(), Some("5"));
+        assert_eq!(key("INSERT INTO t (id, tenant_id) VALUES (1, 5), (2, 6)"), None);
+        assert_eq!(key("INSERT INTO t (id, tenant_id) VALUES (1, now())"), None);
+        assert_eq!(key("INSERT INTO t (id) VALUES (1)"), None);
+    }
+}
@@ -0,0 +1,284 @@
+use crate::lexer::{tokenize, Token};
+
+/// Tables a statement reads from and writes to, by unqualified name.
+///
+/// Extraction is syntactic: tables reached through views, functions or
+/// triggers are not seen, and a schema-qualified `s.t` is reported as `t`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TableAccess {
+    pub reads: Vec<String>,
+    pub writes: Vec<String>,
+}
+
+/// Words that end a FROM list.
+const FROM_LIST_END: [&str; 14] = [
+    "where", "group", "order", "limit", "offset", "having", "window", "union", "intersect", "except", "for",
+    "returning", "when", "fetch",
+];
+const JOIN_WORDS: [&str; 7] = ["inner", "left", "right", "full", "cross", "natural", "outer"];
+
+/// The table named at `at`, skipping modifiers such as ONLY and IF EXISTS, and
+/// the index after it. None when `at` starts a subquery, or a function call
+/// unless `allow_call` is set (as for `INSERT INTO t (a, b)`).
+fn table_name(tokens: &[Token], mut at: usize, allow_call: bool) -> Option<(String, usize)> {
+    while tokens
+        .get(at)
+        .map_or(false, |t| ["only", "if", "not", "exists", "concurrently", "lateral"].iter().any(|w| t.is_word(w)))
+    {
+        at += 1;
+    }
+
+    let mut name = tokens.get(at)?.ident()?;
+    if ["select", "values", "with"].contains(&name) {
+        return None;
+    }
+    at += 1;
+    while tokens.get(at).map_or(false, |t| t.is_symbol(".")) {
+        name = tokens.get(at + 1)?.ident()?;
+        at += 2;
+    }
+    if !allow_call && tokens.get(at).map_or(false, |t| t.is_symbol("(")) {
+        return None;
+    }
+    Some((name.to_string(), at))
+}
+
+/// Tables of the FROM list starting at `start`, with its joins.
+fn from_list(tokens: &[Token], start: usize, tables: &mut Vec<String>) {
+    let mut depth = 0;
+    let mut expect_table = true;
+    // Inside a join condition
+    let mut condition = false;
+    let mut i = start;
+    while let Some(token) = tokens.get(i) {
+        if token.is_symbol("(") {
+            depth += 1;
+            expect_table = false;
+        } else if token.is_symbol(")") {
+            if depth == 0 {
+                return;
+            }
+            depth -= 1;
+        } else if depth > 0 {
+            // Subqueries are picked up by the scan over the whole statement
+        } else if token.is_symbol(";") || FROM_LIST_END.iter().any(|w| token.is_word(w)) {
+            return;
+        } else if token.is_symbol(",") || token.is_word("join") {
+            expect_table = true;
+            condition = false;
+        } else if token.is_word("on") || token.is_word("using") {
+            condition = true;
+        } else if JOIN_WORDS.iter().any(|w| token.is_word(w)) {
+            condition = false;
+        } else if condition {
+            // Column references of the join condition
+        } else if expect_table {
+            expect_table = false;
+            if let Some((name, next)) = table_name(tokens, i, false) {
+                tables.push(name);
+                i = next;
+                continue;
+            }
+        }
+        i += 1;
+    }
+}
+
+/// Comma separated names after TRUNCATE, DROP TABLE and the like.
+fn name_list(tokens: &[Token], mut at: usize, tables: &mut Vec<String>) {
+    while let Some((name, next)) = table_name(tokens, at, false) {
+        tables.push(name);
+        if !tokens.get(next).map_or(false, |t| t.is_symbol(",")) {
+            break;
+        }
+        at = next + 1;
+    }
+}
+
+pub fn tables(sql: &str) -> TableAccess {
+    let tokens = tokenize(sql);
+    let mut access = TableAccess::default();
+    let first = tokens.first().and_then(Token::ident).unwrap_or_default().to_string();
+    let previous = |i: usize, words: &[&str]| i > 0 && words.iter().any(|w| tokens[i - 1].is_word(w));
+
+    let mut depth = 0usize;
+    for (i, token) in tokens.iter().enumerate() {
+        let word = match token {
+            Token::Word(word) => word.as_str(),
+            Token::Symbol(s) if s == "(" => {
+                depth += 1;
+                continue;
+            }
+            Token::Symbol(s) if s == ")" => {
+                depth = depth.saturating_sub(1);
+                continue;
+            }
+            _ => continue,
+        };
+        match word {
+            "from" if previous(i, &["delete"]) => access.writes.extend(table_name(&tokens, i + 1, false).map(|t| t.0)),
+            // IS DISTINCT FROM compares values, and COPY's own FROM names a file
+            "from" if !previous(i, &["distinct"]) && !(first == "copy" && depth == 0) => {
+                from_list(&tokens, i + 1, &mut access.reads)
+            }
+            "using" if first == "delete" || first == "merge" => from_list(&tokens, i + 1, &mut access.reads),
+            // Skip FOR UPDATE, FOR NO KEY UPDATE, ON CONFLICT DO UPDATE and MERGE's THEN UPDATE
+            "update" if !previous(i, &["for", "key", "do", "then"]) => {
+                access.writes.extend(table_name(&tokens, i + 1, false).map(|t| t.0))
+            }
+            "into" => access.writes.extend(table_name(&tokens, i + 1, true).map(|t| t.0)),
+            "truncate" => {
+                let at = if tokens.get(i + 1).map_or(false, |t| t.is_word("table")) { i + 2 } else { i + 1 };
+                name_list(&tokens, at, &mut access.writes);
+            }
+            "table" if i == 0 => access.reads.extend(table_name(&tokens, 1, false).map(|t| t.0)),
+            "table" | "view" if ["alter", "drop", "create", "refresh"].contains(&first.as_str()) => {
+                name_list(&tokens, i + 1, &mut access.writes)
+            }
+            "copy" if i == 0 => {
+                if let Some((name, _)) = table_name(&tokens, 1, true) {
+                    // COPY t FROM loads data, COPY t TO exports it
+                    if tokens.iter().any(|t| t.is_word("from")) {
+                        access.writes.push(name);
+                    } else {
+                        access.reads.push(name);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    for list in [&mut access.reads, &mut access.writes] {
+        list.sort();
+        list.dedup();
+    }
+    access
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn access(reads: &[&str], writes: &[&str]) -> TableAccess {
+        TableAccess {
+            reads: reads.iter().map(|t| t.to_string()).collect(),
+            writes: writes.iter().map(|t| t.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn plain_reads() {
+        assert_eq!(tables("SELECT * FROM users WHERE id = 1"), access(&["users"], &[]));
+        assert_eq!(tables("SELECT * FROM public.Users u"), access(&["users"], &[]));
+        assert_eq!(tables("SELECT * FROM \"Users\""), access(&["Users"], &[]));
+        assert_eq!(tables("SELECT * FROM a, b AS bee, c"), access(&["a", "b", "c"], &[]));
+        assert_eq!(tables("TABLE users"), access(&["users"], &[]));
+        assert_eq!(tables("SELECT now()"), access(&[], &[]));
+        assert_eq!(tables("SELECT * FROM generate_series(1, 3)"), access(&[], &[]));
+    }
+
+    #[test]
+    fn joins() {
+        assert_eq!(
+            tables("SELECT * FROM orders o JOIN items i ON i.order_id = o.id LEFT OUTER JOIN users u USING (user_id)"),
+            access(&["items", "orders", "users"], &[])
+        );
+        assert_eq!(
+            tables("SELECT * FROM orders NATURAL JOIN items CROSS JOIN regions WHERE true"),
+            access(&["items", "orders", "regions"], &[])
+        );
+        assert_eq!(
+            tables("SELECT * FROM orders o INNER JOIN items i ON (i.order_id = o.id AND i.qty > 0), users"),
+            access(&["items", "orders", "users"], &[])
+        );
+    }
+
+    #[test]
+    fn subqueries_and_ctes() {
+        assert_eq!(
+            tables("SELECT * FROM (SELECT * FROM orders) o WHERE o.user_id IN (SELECT id FROM users)"),
+            access(&["orders", "users"], &[])
+        );
+        assert_eq!(
+            tables("SELECT * FROM orders o, LATERAL (SELECT * FROM items WHERE order_id = o.id) i"),
+            access(&["items", "orders"], &[])
+        );
+        assert_eq!(
+            tables("WITH recent AS (SELECT * FROM orders WHERE at > now()) SELECT * FROM recent JOIN users ON true"),
+            access(&["orders", "recent", "users"], &[])
+        );
+        assert_eq!(
+            tables("WITH gone AS (DELETE FROM sessions RETURNING user_id) SELECT * FROM gone"),
+            access(&["gone"], &["sessions"])
+        );
+    }
+
+    #[test]
+    fn writes() {
+        assert_eq!(tables("INSERT INTO orders (id) VALUES (1)"), access(&[], &["orders"]));
+        assert_eq!(tables("UPDATE ONLY public.orders SET total = 0"), access(&[], &["orders"]));
+        assert_eq!(tables("DELETE FROM orders WHERE id = 1"), access(&[], &["orders"]));
+        assert_eq!(tables("TRUNCATE TABLE orders, items"), access(&[], &["items", "orders"]));
+        assert_eq!(tables("DROP TABLE IF EXISTS orders"), access(&[], &["orders"]));
+        assert_eq!(tables("ALTER TABLE orders ADD COLUMN note text"), access(&[], &["orders"]));
+        assert_eq!(tables("REFRESH MATERIALIZED VIEW CONCURRENTLY totals"), access(&[], &["totals"]));
+        assert_eq!(
+            tables("UPDATE orders SET total = t.sum FROM totals t WHERE t.id = orders.id"),
+            access(&["totals"], &["orders"])
+        );
+        assert_eq!(
+            tables("INSERT INTO users (id) VALUES (1) ON CONFLICT (id) DO UPDATE SET seen = now()"),
+            access(&[], &["users"])
+        );
+        assert_eq!(tables("SELECT * FROM users FOR UPDATE"), access(&["users"], &[]));
+        assert_eq!(tables("SELECT * FROM users FOR NO KEY UPDATE"), access(&["users"], &[]));
+    }
+
+    #[test]
+    fn delete_using() {
+        assert_eq!(
+            tables("DELETE FROM orders USING users u, regions WHERE orders.user_id = u.id"),
+            access(&["regions", "users"], &["orders"])
+        );
+    }
+
+    #[test]
+    fn insert_select() {
+        assert_eq!(
+            tables("INSERT INTO archive (id, total) SELECT id, total FROM orders o JOIN items i ON i.order_id = o.id"),
+            access(&["items", "orders"], &["archive"])
+        );
+        assert_eq!(tables("INSERT INTO archive SELECT * FROM orders"), access(&["orders"], &["archive"]));
+        assert_eq!(tables("SELECT * INTO backup FROM orders"), access(&["orders"], &["backup"]));
+    }
+
+    #[test]
+    fn merge() {
+        assert_eq!(
+            tables("MERGE INTO stock s USING deliveries d ON s.item = d.item WHEN MATCHED THEN UPDATE SET qty = 1"),
+            access(&["deliveries"], &["stock"])
+        );
+    }
+
+    #[test]
+    fn is_distinct_from() {
+        assert_eq!(tables("SELECT * FROM orders WHERE status IS DISTINCT FROM 'done'"), access(&["orders"], &[]));
+        assert_eq!(
+            tables("UPDATE orders SET status = 'x' WHERE status IS NOT DISTINCT FROM note"),
+            access(&[], &["orders"])
+        );
+    }
+
+    #[test]
+    fn copy() {
+        assert_eq!(tables("COPY orders FROM STDIN"), access(&[], &["orders"]));
+        assert_eq!(tables("COPY orders (id, total) FROM '/tmp/orders.csv' WITH (FORMAT csv)"), access(&[], &["orders"]));
+        assert_eq!(tables("COPY public.orders TO STDOUT"), access(&["orders"], &[]));
+        assert_eq!(tables("COPY orders (id, total) TO STDOUT"), access(&["orders"], &[]));
+        assert_eq!(
+            tables("COPY (SELECT * FROM orders JOIN items ON true) TO STDOUT"),
+            access(&["items", "orders"], &[])
+        );
+    }
+}
@@ -0,0 +1,158 @@
+//! Admin commands operators run over an ordinary client connection:
+//!
+//! - `PGSHIELD DRAIN 'host'` stops routing new transactions to a host
+//! - `PGSHIELD RESUME 'host'` returns it to service
+//! - `PGSHIELD SHOW DRAIN ['host']` reports drain progress
+//!
+//! Each answers with the drain status of the hosts concerned. Only logins
+//! listed in `admin_users` may run them.
+
+use lib_pgsqlcli::{PostgresError, PostgresValue, QueryResult};
+use lib_query::lexer::{tokenize, Token};
+
+use crate::drain::DrainStatus;
+use crate::{Router, Session};
+
+/// SQLSTATE insufficient_privilege.
+const INSUFFICIENT_PRIVILEGE: &str = "42501";
+/// SQLSTATE syntax_error.
+const SYNTAX_ERROR: &str = "42601";
+
+const TEXT_OID: u32 = 25;
+const BOOL_OID: u32 = 16;
+const INT8_OID: u32 = 20;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Command {
+    Drain(String),
+    Resume(String),
+    Show(Option<String>),
+}
+
+/// Whether `sql` is an admin command rather than SQL for the servers.
+pub fn is_admin_command(sql: &str) -> bool {
+    tokenize(sql).first().map_or(false, |t| t.is_word("pgshield"))
+}
+
+fn parse(sql: &str) -> Result<Command, PostgresError> {
+    let tokens = tokenize(sql);
+    let words: Vec<&Token> = tokens.iter().filter(|t| !t.is_symbol(";")).collect();
+    let host = |token: &Token| match token {
+        Token::Word(name) | Token::QuotedIdent(name) | Token::String(name) => Some(name.clone()),
+        _ => None,
+    };
+    let command = match words.as_slice() {
+        [_, verb, name] if verb.is_word("drain") => host(name).map(Command::Drain),
+        [_, verb, name] if verb.is_word("resume") => host(name).map(Command::Resume),
+        [_, show, drain] if show.is_word("show") && drain.is_word("drain") => Some(Command::Show(None)),
+        [_, show, drain, name] if show.is_word("show") && drain.is_word("drain") => {
+            host(name).map(|name| Command::Show(Some(name)))
+        }
+        _ => None,
+    };
+    command.ok_or_else(|| PostgresError::Server {
+        code: SYNTAX_ERROR.to_string(),
+        message: "unknown PGSHIELD command; use DRAIN 'host', RESUME 'host' or SHOW DRAIN ['host']".to_string(),
+    })
+}
+
+fn status_result(tag: &str, statuses: Vec<DrainStatus>) -> QueryResult {
+    let columns = [
+        ("host", TEXT_OID),
+        ("draining", BOOL_OID),
+        ("connections_in_use", INT8_OID),
+        ("safe_to_remove", BOOL_OID),
+    ];
+    let rows = statuses
+        .into_iter()
+        .map(|status| {
+            let values = [
+                PostgresValue::String(status.host),
+                PostgresValue::Boolean(status.draining),
+                PostgresValue::Int64(status.connections_in_use as i64),
+                PostgresValue::Boolean(status.safe_to_remove),
+            ];
+            columns.iter().map(|(name, _)| name.to_string()).zip(values).collect()
+        })
+        .collect();
+    QueryResult {
+        columns: columns.iter().map(|(name, type_oid)| (name.to_string(), *type_oid)).collect(),
+        rows,
+        command_tag: tag.to_string(),
+    }
+}
+
+impl Router {
+    /// Runs an admin command for the session, see the module documentation.
+    pub fn admin_command(&self, session: &Session, sql: &str) -> Result<QueryResult, PostgresError> {
+        if !self.admin_users.contains(&session.login().user) {
+            return Err(PostgresError::Server {
+                code: INSUFFICIENT_PRIVILEGE.to_string(),
+                message: "must be listed in admin_users to run PGSHIELD commands".to_string(),
+            });
+        }
+        match parse(sql)? {
+            Command::Drain(name) => Ok(status_result("DRAIN", vec![self.drain(&name)?])),
+            Command::Resume(name) => Ok(status_result("RESUME", vec![self.resume(&name)?])),
+            Command::Show(Some(name)) => Ok(status_result("SHOW", vec![self.drain_status(&name)?])),
+            Command::Show(None) => Ok(status_result("SHOW", self.drain_statuses())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{config, FakeServer};
+    use crate::Login;
+
+    #[test]
+    fn commands() {
+        assert_eq!(parse("PGSHIELD DRAIN 'replica-1'").unwrap(), Command::Drain("replica-1".to_string()));
+        assert_eq!(parse("pgshield resume replica;").unwrap(), Command::Resume("replica".to_string()));
+        assert_eq!(parse("PGSHIELD SHOW DRAIN").unwrap(), Command::Show(None));
+        assert_eq!(parse("PGSHIELD SHOW DRAIN \"Replica\"").unwrap(), Command::Show(Some("Replica".to_string())));
+        assert!(parse("PGSHIELD DRAIN").is_err());
+        assert!(parse("PGSHIELD SHUTDOWN").is_err());
+        assert!(is_admin_command(" /* ops */ PGSHIELD SHOW DRAIN"));
+        assert!(!is_admin_command("SELECT 'PGSHIELD DRAIN'"));
+    }
+
+    #[tokio::test]
+    async fn operators_drain_hosts_over_a_client_connection() {
+        let server = FakeServer::start().await;
+        let router = Router::new(&config(serde_json::json!({
+            "postgresql_hosts": [{ "name": "replica", "host": server.host }],
+            "admin_users": ["ops"],
+        })))
+        .await
+        .unwrap();
+        let mut ops = Session::new(Login::new("app", "ops", None));
+        let mut app = Session::new(Login::new("app", "app", None));
+
+        match router.simple_query(&mut app, "PGSHIELD DRAIN 'replica'").await {
+            Err(PostgresError::Server { code, .. }) => assert_eq!(code, INSUFFICIENT_PRIVILEGE),
+            other => panic!("expected a privilege error, got {:?}", other.map(|result| result.command_tag)),
+        }
+        assert!(!router.backends()[0].is_draining());
+
+        // A transaction open when the drain starts may finish on the host
+        router.simple_query(&mut app, "BEGIN").await.unwrap();
+        let drained = router.simple_query(&mut ops, "PGSHIELD DRAIN 'replica'").await.unwrap();
+        assert_eq!(drained.command_tag, "DRAIN");
+        assert_eq!(drained.rows[0][1].1, PostgresValue::Boolean(true));
+        assert_eq!(drained.rows[0][3].1, PostgresValue::Boolean(false));
+        router.simple_query(&mut app, "COMMIT").await.unwrap();
+
+        let shown = router.simple_query(&mut ops, "PGSHIELD SHOW DRAIN").await.unwrap();
+        assert_eq!(shown.rows[0][0].1, PostgresValue::String("replica".to_string()));
+        assert_eq!(shown.rows[0][2].1, PostgresValue::Int64(0));
+        assert_eq!(shown.rows[0][3].1, PostgresValue::Boolean(true));
+        assert!(router.simple_query(&mut app, "SELECT 1").await.is_err());
+
+        router.simple_query(&mut ops, "PGSHIELD RESUME 'replica'").await.unwrap();
+        router.simple_query(&mut app, "SELECT 1").await.unwrap();
+        // Admin commands never reach the servers
+        assert!(server.statements().iter().all(|(_, sql)| !sql.contains("PGSHIELD")));
+    }
+}
@@ -0,0 +1,213 @@
+use rand::Rng;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use lib_config::LoadBalancingStrategy;
+
+use crate::Backend;
+
+/// Weight of the newest latency sample in the moving average.
+const EWMA_ALPHA: f64 = 0.3;
+
+pub trait LoadBalancer: Send + Sync {
+    /// Picks one backend out of the currently healthy `candidates`.
+    fn pick(&self, candidates: &[Arc<Backend>]) -> Option<Arc<Backend>>;
+
+    /// Feeds back how long a request against `host` took.
+    fn record_latency(&self, _host: &str, _latency: Duration) {}
+}
+
+pub fn from_strategy(strategy: &LoadBalancingStrategy) -> Box<dyn LoadBalancer> {
+    match strategy {
+        LoadBalancingStrategy::RoundRobin => Box::new(RoundRobin::default()),
+        LoadBalancingStrategy::WeightedRoundRobin => Box::new(WeightedRoundRobin::default()),
+        LoadBalancingStrategy::LeastOutstanding => Box::new(LeastOutstanding),
+        LoadBalancingStrategy::RandomTwoChoices => Box::new(RandomTwoChoices),
+        LoadBalancingStrategy::LatencyEwma => Box::new(LatencyEwma::default()),
+    }
+}
+
+fn weight(backend: &Backend) -> u32 {
+    backend.host.weight.unwrap_or(1).max(1)
+}
+
+/// Connections checked out across the backend's pools, scaled down by its weight.
+fn load(backend: &Backend) -> f64 {
+    backend.in_use() as f64 / weight(backend) as f64
+}
+
+#[derive(Default)]
+pub struct RoundRobin {
+    next: AtomicUsize,
+}
+
+impl LoadBalancer for RoundRobin {
+    fn pick(&self, candidates: &[Arc<Backend>]) -> Option<Arc<Backend>> {
+        if candidates.is_empty() {
+            return None;
+        }
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % candidates.len();
+        Some(Arc::clone(&candidates[index]))
+    }
+}
+
+/// Smooth weighted round-robin: every pick raises each candidate's current
+/// weight by its configured weight and lowers the winner by the total, which
+/// spreads picks of heavy hosts evenly instead of sending them in bursts.
+#[derive(Default)]
+pub struct WeightedRoundRobin {
+    current: Mutex<HashMap<String, i64>>,
+}
+
+impl LoadBalancer for WeightedRoundRobin {
+    fn pick(&self, candidates: &[Arc<Backend>]) -> Option<Arc<Backend>> {
+        let mut current = self.current.lock().unwrap();
+        let total: i64 = candidates.iter().map(|b| weight(b) as i64).sum();
+
+        let mut best: Option<(&Arc<Backend>, i64)> = None;
+        for backend in candidates {
+            let entry = current.entry(backend.host.host.clone()).or_insert(0);
+            *entry += weight(backend) as i64;
+            if best.map_or(true, |(_, score)| *entry > score) {
+                best = Some((backend, *entry));
+            }
+        }
+
+        best.map(|(backend, _)| {
+            *current.get_mut(&backend.host.host).unwrap() -= total;
+            Arc::clone(backend)
+        })
+    }
+}
+
+pub struct LeastOutstanding;
+
+impl LoadBalancer for LeastOutstanding {
+    fn pick(&self, candidates: &[Arc<Backend>]) -> Option<Arc<Backend>> {
+        candidates
+            .iter()
+            .min_by(|a, b| load(a).total_cmp(&load(b)))
+            .cloned()
+    }
+}
+
+/// Samples two random candidates and keeps the less loaded one, which avoids
+/// the herding of pure least-outstanding when stats lag behind.
+pub struct RandomTwoChoices;
+
+impl LoadBalancer for RandomTwoChoices {
+    fn pick(&self, candidates: &[Arc<Backend>]) -> Option<Arc<Backend>> {
+        match candidates.len() {
+            0 => None,
+            1 => Some(Arc::clone(&candidates[0])),
+            len => {
+                let mut rng = rand::thread_rng();
+                let first = rng.gen_range(0..len);
+                let second = (first + rng.gen_range(1..len)) % len;
+                let (a, b) = (&candidates[first], &candidates[second]);
+                Some(Arc::clone(if load(b) < load(a) { b } else { a }))
+            }
+        }
+    }
+}
+
+/// Prefers the backend with the lowest exponentially weighted moving average
+/// latency, penalised by its outstanding connections. Backends without samples
+/// score zero so they get tried.
+#[derive(Default)]
+pub struct LatencyEwma {
+    latencies: Mutex<HashMap<String, f64>>,
+}
+
+impl LoadBalancer for LatencyEwma {
+    fn pick(&self, candidates: &[Arc<Backend>]) -> Option<Arc<Backend>> {
+        let latencies = self.latencies.lock().unwrap();
+        let score = |backend: &Arc<Backend>| {
+            let latency = latencies.get(&backend.host.host).copied().unwrap_or(0.0);
+            latency * (load(backend) + 1.0)
+        };
+        candidates
+            .iter()
+            .min_by(|a, b| score(a).total_cmp(&score(b)))
+            .cloned()
+    }
+
+    fn record_latency(&self, host: &str, latency: Duration) {
+        let sample = latency.as_secs_f64() * 1000.0;
+        let mut latencies = self.latencies.lock().unwrap();
+        latencies
+            .entry(host.to_string())
+            .and_modify(|ewma| *ewma = EWMA_ALPHA * sample + (1.0 - EWMA_ALPHA) * *ewma)
+            .or_insert(sample);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{config, FakeServer};
+    use crate::{Login, Router};
+
+    /// Two backends, the first with a client connection checked out, as a
+    /// statement of a session holds it.
+    async fn busy_first(test: impl Fn(&[Arc<Backend>])) {
+        let (first, second) = (FakeServer::start().await, FakeServer::start().await);
+        let router = Router::new(&config(serde_json::json!({
+            "postgresql_hosts": [{ "host": first.host }, { "host": second.host }],
+        })))
+        .await
+        .unwrap();
+        let backends = router.backends();
+        let pool = backends[0].client_pool(&Login::new("app", "app", None)).await.unwrap();
+        let client = pool.get_client().await.unwrap();
+        assert_eq!(backends[0].in_use(), 1);
+
+        test(backends);
+        pool.release_client(client).await;
+    }
+
+    fn picks(balancer: &dyn LoadBalancer, backends: &[Arc<Backend>]) -> Arc<Backend> {
+        balancer.pick(backends).unwrap()
+    }
+
+    #[tokio::test]
+    async fn least_outstanding_avoids_busy_hosts() {
+        busy_first(|backends| {
+            for _ in 0..10 {
+                assert!(Arc::ptr_eq(&picks(&LeastOutstanding, backends), &backends[1]));
+            }
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn random_two_choices_avoids_busy_hosts() {
+        // With two candidates both are always sampled
+        busy_first(|backends| {
+            for _ in 0..10 {
+                assert!(Arc::ptr_eq(&picks(&RandomTwoChoices, backends), &backends[1]));
+            }
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn latency_ewma_penalises_busy_hosts() {
+        busy_first(|backends| {
+            let balancer = LatencyEwma::default();
+            for backend in backends {
+                balancer.record_latency(&backend.host.host, Duration::from_millis(10));
+            }
+            assert!(Arc::ptr_eq(&picks(&balancer, backends), &backends[1]));
+
+            // A much faster host wins despite its load
+            for _ in 0..10 {
+                balancer.record_latency(&backends[0].host.host, Duration::from_micros(1));
+            }
+            assert!(Arc::ptr_eq(&picks(&balancer, backends), &backends[0]));
+        })
+        .await;
+    }
+}
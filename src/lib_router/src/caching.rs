@@ -0,0 +1,369 @@
+use bytes::BytesMut;
+use log;
+use std::sync::Arc;
+use std::time::Duration;
+
+use lib_cache::{CachedResult, Expiry, Fill, Lookup};
+use lib_config::Config;
+use lib_pgsqlcli::PostgresError;
+use lib_query::hints::{parse_hints, QueryHints};
+use lib_query::{cache_key, classify, normalize, tables, StatementKind};
+
+use crate::{Backend, Login, Router, Session};
+
+/// A `query_cache_rules` entry, ready for matching.
+pub(crate) struct CacheRule {
+    /// Unqualified table names.
+    tables: Vec<String>,
+    /// Normalized query text.
+    query: Option<String>,
+    ttl: Option<Duration>,
+    stale_ttl: Option<Duration>,
+}
+
+impl CacheRule {
+    fn matches(&self, normalized: &str, reads: &[String]) -> bool {
+        (self.tables.is_empty() || self.tables.iter().any(|table| reads.contains(table)))
+            && self.query.as_deref().map_or(true, |query| query == normalized)
+    }
+}
+
+pub(crate) fn cache_rules(config: &Config) -> Vec<CacheRule> {
+    config
+        .query_cache_rules
+        .iter()
+        .flatten()
+        .map(|rule| CacheRule {
+            tables: rule
+                .tables
+                .iter()
+                .flatten()
+                .filter_map(|table| table.rsplit('.').next())
+                .map(str::to_string)
+                .collect(),
+            query: rule.query.as_deref().map(normalize),
+            ttl: rule.ttl.map(Duration::from_secs),
+            stale_ttl: rule.stale_ttl.map(Duration::from_secs),
+        })
+        .collect()
+}
+
+/// The response to a statement run through the query cache.
+pub enum CachedResponse {
+    Cached(Arc<CachedResult>),
+    /// The backend's response messages, for statements that cannot be cached.
+    Uncached(Vec<(u8, BytesMut)>),
+}
+
+impl Router {
+    /// Runs `sql` for the session, serving reads from the query cache when the
+    /// session allows it. Concurrent misses of the same query run it once and
+    /// share the result. Writes, statements with a `nocache` hint and anything
+    /// inside a transaction go straight to the backend.
+    pub async fn cached_query(&self, session: &mut Session, sql: &str) -> Result<CachedResponse, PostgresError> {
+        let hints = parse_hints(sql);
+        let scope = match session.cache_scope() {
+            Some(scope) if classify(sql) == StatementKind::Read && !hints.nocache => scope,
+            _ => return self.execute(session, sql).await.map(CachedResponse::Uncached),
+        };
+        let key = cache_key(sql, &[], scope);
+        let reads = tables(sql).reads;
+        let expiry = self.cache_expiry(sql, &reads, &hints);
+
+        match self.query_cache.lookup(&key, &reads, expiry).await {
+            Lookup::Hit(result) => Ok(CachedResponse::Cached(result)),
+            Lookup::Stale { result, refresh } => {
+                if let Some(fill) = refresh {
+                    self.refresh(fill, session.login(), sql).await;
+                }
+                Ok(CachedResponse::Cached(result))
+            }
+            Lookup::Miss => self.execute(session, sql).await.map(CachedResponse::Uncached),
+            Lookup::Fill(fill) => {
+                let messages = match self.execute(session, sql).await {
+                    Ok(messages) => messages,
+                    Err(e) => {
+                        fill.abandon();
+                        return Err(e);
+                    }
+                };
+                match CachedResult::from_messages(messages.iter().map(|(message_type, body)| (*message_type, &body[..]))) {
+                    Some(result) => Ok(CachedResponse::Cached(fill.complete(result))),
+                    None => {
+                        fill.abandon();
+                        Ok(CachedResponse::Uncached(messages))
+                    }
+                }
+            }
+        }
+    }
+
+    /// Expiry of the results of `sql`: its `cache_ttl` and `stale_ttl` hints,
+    /// else those of the first matching rule, else the cache's defaults.
+    fn cache_expiry(&self, sql: &str, reads: &[String], hints: &QueryHints) -> Expiry {
+        let defaults = self.query_cache.default_expiry();
+        let normalized = normalize(sql);
+        let rule = self.cache_rules.iter().find(|rule| rule.matches(&normalized, reads));
+        Expiry {
+            ttl: hints.cache_ttl.or(rule.and_then(|rule| rule.ttl)).unwrap_or(defaults.ttl),
+            stale_ttl: hints
+                .stale_ttl
+                .or(rule.and_then(|rule| rule.stale_ttl))
+                .unwrap_or(defaults.stale_ttl),
+        }
+    }
+
+    /// Refreshes a stale result in the background on a pooled connection, so
+    /// the lookup that found it is not kept waiting. The result is shared by
+    /// every session, so the refresh is routed for a fresh session of the same
+    /// login rather than the caller's: its shard and `host=` hint still apply,
+    /// but not the caller's pinning or read-your-writes position.
+    async fn refresh(&self, fill: Fill, login: &Login, sql: &str) {
+        let mut session = Session::new(login.clone());
+        let backend = match self.route_query(&mut session, sql, &[]).await {
+            Ok(backend) => backend,
+            Err(e) => {
+                log::debug!("No host to refresh a stale cached result on: {}", e);
+                fill.abandon();
+                return;
+            }
+        };
+        let login = login.clone();
+        let sql = self.outgoing_sql(sql);
+        tokio::spawn(async move {
+            match messages_on(&backend, &login, &sql).await {
+                Ok(messages) => {
+                    match CachedResult::from_messages(messages.iter().map(|(message_type, body)| (*message_type, &body[..]))) {
+                        Some(result) => {
+                            fill.complete(result);
+                        }
+                        None => fill.abandon(),
+                    }
+                }
+                Err(e) => {
+                    log::warn!("Refreshing a stale cached result on {} failed: {}", backend.host.host, e);
+                    fill.abandon();
+                }
+            }
+        });
+    }
+}
+
+/// Runs `sql` on a connection of `backend`'s pool for `login`.
+pub(crate) async fn messages_on(backend: &Backend, login: &Login, sql: &str) -> Result<Vec<(u8, BytesMut)>, PostgresError> {
+    let pool = backend.client_pool(login).await?;
+    let mut client = pool.get_client().await?;
+    let result = client.simple_query_messages(sql).await;
+    match &result {
+        // Server errors leave the connection usable
+        Ok(_) | Err(PostgresError::Server { .. }) => pool.release_client(client).await,
+        Err(_) => pool.discard_client(client),
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{config, FakeServer};
+    use crate::Login;
+
+    async fn router(server: &FakeServer, replication_mode: bool) -> Router {
+        Router::new(&config(serde_json::json!({
+            "postgresql_hosts": [{ "host": server.host }],
+            "replication_mode": replication_mode,
+        })))
+        .await
+        .unwrap()
+    }
+
+    fn session() -> Session {
+        Session::new(Login::new("app", "app", None))
+    }
+
+    fn is_cached(response: &CachedResponse) -> bool {
+        matches!(response, CachedResponse::Cached(_))
+    }
+
+    #[tokio::test]
+    async fn nothing_inside_a_transaction_is_cached() {
+        for replication_mode in [false, true] {
+            let server = FakeServer::start().await;
+            let router = router(&server, replication_mode).await;
+            let mut session = session();
+            let select = "SELECT * FROM orders";
+
+            router.cached_query(&mut session, "BEGIN").await.unwrap();
+            assert!(session.in_transaction());
+            for _ in 0..2 {
+                assert!(!is_cached(&router.cached_query(&mut session, select).await.unwrap()));
+            }
+            router.cached_query(&mut session, "COMMIT").await.unwrap();
+            assert!(!session.in_transaction());
+            assert_eq!(server.count(select), 2);
+
+            // The first run outside the transaction fills the cache
+            assert!(is_cached(&router.cached_query(&mut session, select).await.unwrap()));
+            assert!(is_cached(&router.cached_query(&mut session, select).await.unwrap()));
+            assert_eq!(server.count(select), 3);
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_transactions_are_not_cached_until_rolled_back() {
+        let server = FakeServer::start().await;
+        let router = router(&server, false).await;
+        let mut session = session();
+
+        router.cached_query(&mut session, "BEGIN").await.unwrap();
+        assert!(router.cached_query(&mut session, "SELECT fail()").await.is_err());
+        assert!(session.in_transaction());
+        assert!(session.cache_scope().is_none());
+        router.cached_query(&mut session, "ROLLBACK").await.unwrap();
+        assert!(session.cache_scope().is_some());
+    }
+
+    #[tokio::test]
+    async fn sessions_with_different_settings_do_not_share_results() {
+        let server = FakeServer::start().await;
+        let router = router(&server, false).await;
+        let select = "SELECT * FROM orders";
+        let run = |settings: &'static [&'static str]| {
+            let router = &router;
+            async move {
+                let mut session = session();
+                for sql in settings {
+                    router.cached_query(&mut session, sql).await.unwrap();
+                }
+                let key = cache_key(select, &[], session.cache_scope().unwrap());
+                router.cached_query(&mut session, select).await.unwrap();
+                key
+            }
+        };
+
+        let tenant_a = run(&["SET search_path TO tenant_a"]).await;
+        let tenant_b = run(&["SET search_path TO tenant_b"]).await;
+        let reporting = run(&["SET search_path TO tenant_a", "SET ROLE reporting"]).await;
+        let plain = run(&[]).await;
+        assert_ne!(tenant_a, tenant_b);
+        assert_ne!(tenant_a, reporting);
+        assert_ne!(tenant_a, plain);
+        assert_eq!(server.count(select), 4);
+
+        // The same settings share the result
+        assert_eq!(run(&["SET search_path = tenant_a"]).await, tenant_a);
+        assert_eq!(server.count(select), 4);
+    }
+
+    #[tokio::test]
+    async fn committed_writes_invalidate_cached_results() {
+        let server = FakeServer::start().await;
+        let router = router(&server, false).await;
+        let (mut reader, mut writer) = (session(), session());
+        let select = "SELECT * FROM orders o JOIN items i ON i.order_id = o.id";
+        let mut runs = 0;
+
+        router.cached_query(&mut reader, select).await.unwrap();
+        runs += 1;
+        router.cached_query(&mut reader, select).await.unwrap();
+        assert_eq!(server.count(select), runs);
+
+        // An autocommitted write invalidates at once
+        router.cached_query(&mut writer, "INSERT INTO items VALUES (1)").await.unwrap();
+        router.cached_query(&mut reader, select).await.unwrap();
+        runs += 1;
+        assert_eq!(server.count(select), runs);
+
+        // A write inside a transaction only once it commits
+        router.cached_query(&mut writer, "BEGIN").await.unwrap();
+        router.cached_query(&mut writer, "UPDATE orders SET total = 0").await.unwrap();
+        router.cached_query(&mut reader, select).await.unwrap();
+        assert_eq!(server.count(select), runs);
+        router.cached_query(&mut writer, "COMMIT").await.unwrap();
+        router.cached_query(&mut reader, select).await.unwrap();
+        runs += 1;
+        assert_eq!(server.count(select), runs);
+
+        // Rolled back writes leave the cache alone, as do writes to other tables
+        router.cached_query(&mut writer, "BEGIN").await.unwrap();
+        router.cached_query(&mut writer, "DELETE FROM orders").await.unwrap();
+        router.cached_query(&mut writer, "ROLLBACK").await.unwrap();
+        router.cached_query(&mut writer, "INSERT INTO audit VALUES (1)").await.unwrap();
+        router.cached_query(&mut reader, select).await.unwrap();
+        assert_eq!(server.count(select), runs);
+    }
+
+    #[tokio::test]
+    async fn stale_results_are_refreshed_where_the_query_was_routed() {
+        let (primary, replica) = (FakeServer::start().await, FakeServer::start().await);
+        let router = Router::new(&config(serde_json::json!({
+            "postgresql_hosts": [
+                { "name": "a", "host": primary.host, "role": "primary" },
+                { "name": "b", "host": replica.host, "role": "replica" },
+            ],
+            "replication_mode": true,
+        })))
+        .await
+        .unwrap();
+        let mut session = session();
+        let select = "/* pgshield: host=a cache_ttl=50ms stale_ttl=1m */ SELECT * FROM orders";
+
+        assert!(is_cached(&router.cached_query(&mut session, select).await.unwrap()));
+        assert_eq!(primary.count(select), 1);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // The stale result is served at once while the hinted host refreshes it
+        assert!(is_cached(&router.cached_query(&mut session, select).await.unwrap()));
+        for _ in 0..100 {
+            if primary.count(select) == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(primary.count(select), 2);
+        assert_eq!(replica.count(select), 0);
+    }
+
+    #[tokio::test]
+    async fn concurrent_misses_run_the_query_once() {
+        let server = FakeServer::start().await;
+        let router = Arc::new(router(&server, false).await);
+        let select = "SELECT * FROM orders";
+        server.set_delay(Duration::from_millis(100));
+
+        let mut queries = tokio::task::JoinSet::new();
+        for _ in 0..8 {
+            let router = Arc::clone(&router);
+            queries.spawn(async move { is_cached(&router.cached_query(&mut session(), select).await.unwrap()) });
+        }
+        while let Some(cached) = queries.join_next().await {
+            assert!(cached.unwrap());
+        }
+        assert_eq!(server.count(select), 1);
+        assert_eq!(router.query_cache.stats().coalesced, 7);
+    }
+
+    #[tokio::test]
+    async fn abandoned_fills_are_taken_over_by_a_waiter() {
+        let server = FakeServer::start().await;
+        let router = Arc::new(router(&server, false).await);
+        let select = "SELECT * FROM orders";
+        server.set_delay(Duration::from_millis(200));
+
+        let query = || {
+            let router = Arc::clone(&router);
+            tokio::spawn(async move { is_cached(&router.cached_query(&mut session(), select).await.unwrap()) })
+        };
+        let filling = query();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let waiting = query();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        // The client running the query disconnects before it completes
+        filling.abort();
+
+        assert!(waiting.await.unwrap());
+        assert_eq!(server.count(select), 2);
+        assert!(is_cached(&router.cached_query(&mut session(), select).await.unwrap()));
+        assert_eq!(server.count(select), 2);
+    }
+}
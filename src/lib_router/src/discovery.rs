@@ -0,0 +1,129 @@
+use log;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+use lib_config::PostgresqlHost;
+use lib_pgsqlcli::{PostgresClient, PostgresError, PostgresValue};
+
+use crate::{Backend, DEFAULT_DATABASE};
+
+const DEFAULT_DISCOVERY_INTERVAL: u64 = 3600;
+const LIST_DATABASES: &str =
+    "SELECT datname FROM pg_database WHERE datallowconn AND NOT datistemplate ORDER BY datname";
+
+pub async fn list_databases(host: &PostgresqlHost) -> Result<Vec<String>, PostgresError> {
+    let mut client = PostgresClient::connect(&host.connection_string(DEFAULT_DATABASE)).await?;
+    let rows = client.query(LIST_DATABASES).await?;
+    Ok(rows
+        .into_iter()
+        .filter_map(|row| match row.into_iter().next() {
+            Some((_, PostgresValue::String(name))) => Some(name),
+            _ => None,
+        })
+        .collect())
+}
+
+/// Spawns a discovery loop for every backend with `database_discovery` enabled.
+pub fn start(backends: &[Arc<Backend>]) -> Vec<JoinHandle<()>> {
+    backends
+        .iter()
+        .filter(|backend| backend.host.database_discovery == Some(true))
+        .map(|backend| {
+            let backend = Arc::clone(backend);
+            let every = Duration::from_secs(backend.host.discovery_interval.unwrap_or(DEFAULT_DISCOVERY_INTERVAL).max(1));
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(every);
+                loop {
+                    interval.tick().await;
+                    if let Err(e) = discover(&backend).await {
+                        log::warn!("Database discovery on {} failed: {}", backend.host.host, e);
+                    }
+                }
+            })
+        })
+        .collect()
+}
+
+/// Restricts `backend` to the databases it has. Sessions of a discovered
+/// database check out connections of their own login's client pool.
+async fn discover(backend: &Backend) -> Result<(), PostgresError> {
+    let found = list_databases(&backend.host).await?;
+
+    for database in backend.databases() {
+        if !found.contains(&database) {
+            backend.retire_database(&database);
+        }
+    }
+
+    let known = backend.databases();
+    for database in found {
+        if database != DEFAULT_DATABASE && !known.contains(&database) {
+            log::info!("Discovered database {} on {}", database, backend.host.host);
+            backend.register_database(&database);
+        }
+    }
+    backend.set_discovered();
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{config, FakeServer};
+    use crate::{Login, Router, Session};
+
+    #[tokio::test]
+    async fn discovered_hosts_only_serve_their_databases() {
+        let server = FakeServer::start().await;
+        let config = config(serde_json::json!({
+            "postgresql_hosts": [{ "host": server.host, "database_discovery": true }],
+        }));
+        let router = Router::new(&config).await.unwrap();
+        let backend = &router.backends()[0];
+        // Until discovery completed the host serves any database
+        assert!(backend.serves("tenant"));
+
+        backend.register_database("tenant");
+        discover(backend).await.unwrap();
+        assert_eq!(backend.databases(), vec!["app".to_string()]);
+
+        for database in ["app", DEFAULT_DATABASE] {
+            let mut session = Session::new(Login::new(database, "app", None));
+            assert!(router.route_query(&mut session, "SELECT 1", &[]).await.is_ok());
+        }
+        let mut session = Session::new(Login::new("tenant", "app", None));
+        match router.route_query(&mut session, "SELECT 1", &[]).await {
+            Err(PostgresError::Server { code, .. }) => assert_eq!(code, "3D000"),
+            other => panic!("expected an unknown database, got {:?}", other.map(|backend| backend.host.host.clone())),
+        }
+    }
+
+    #[tokio::test]
+    async fn discovered_databases_run_on_client_pools() {
+        let server = FakeServer::start().await;
+        let config = config(serde_json::json!({
+            "postgresql_hosts": [{ "host": server.host, "database_discovery": true }],
+        }));
+        let router = Router::new(&config).await.unwrap();
+        let backend = &router.backends()[0];
+        discover(backend).await.unwrap();
+        // Discovery only lists the databases, without opening pools for them
+        assert_eq!(server.count(LIST_DATABASES), 1);
+        assert!(backend.client_pools.lock().unwrap().is_empty());
+
+        let mut session = Session::new(Login::new("app", "app", None));
+        router.simple_query(&mut session, "BEGIN").await.unwrap();
+        router.simple_query(&mut session, "SELECT 1").await.unwrap();
+        assert_eq!(backend.in_use(), 1);
+        let pools: Vec<Login> = backend.client_pools.lock().unwrap().keys().cloned().collect();
+        assert_eq!(pools, vec![Login::new("app", "app", None)]);
+        router.simple_query(&mut session, "COMMIT").await.unwrap();
+        assert_eq!(backend.in_use(), 0);
+
+        backend.retire_database("app");
+        assert!(!backend.serves("app"));
+        assert!(backend.client_pools.lock().unwrap().is_empty());
+    }
+}
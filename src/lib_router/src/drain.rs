@@ -0,0 +1,63 @@
+use log;
+use std::sync::Arc;
+
+use lib_pgsqlcli::PostgresError;
+
+use crate::{Backend, Router};
+
+/// Drain progress of one host, as reported to operators.
+#[derive(Debug, Clone)]
+pub struct DrainStatus {
+    pub host: String,
+    pub draining: bool,
+    /// Connections still checked out by sessions finishing their work.
+    pub connections_in_use: usize,
+    /// Draining and no connection in use: the host can be taken down.
+    pub safe_to_remove: bool,
+}
+
+impl DrainStatus {
+    fn of(backend: &Backend) -> Self {
+        let in_use = backend.in_use();
+        DrainStatus {
+            host: backend.host.name.clone().unwrap_or_else(|| backend.host.host.clone()),
+            draining: backend.is_draining(),
+            connections_in_use: in_use,
+            safe_to_remove: backend.is_draining() && in_use == 0,
+        }
+    }
+}
+
+impl Router {
+    fn find_backend(&self, name: &str) -> Result<&Arc<Backend>, PostgresError> {
+        self.backends
+            .iter()
+            .find(|backend| backend.matches_name(name))
+            .ok_or_else(|| PostgresError::Unavailable(format!("host {} is unknown", name)))
+    }
+
+    /// Stops routing new transactions to a host. Transactions already running on
+    /// it finish, idle connections are closed now and the others when released.
+    pub fn drain(&self, name: &str) -> Result<DrainStatus, PostgresError> {
+        let backend = self.find_backend(name)?;
+        backend.set_draining(true);
+        log::info!("Draining {}, {} connections in use", backend.host.host, backend.in_use());
+        Ok(DrainStatus::of(backend))
+    }
+
+    /// Returns a drained host to service.
+    pub fn resume(&self, name: &str) -> Result<DrainStatus, PostgresError> {
+        let backend = self.find_backend(name)?;
+        backend.set_draining(false);
+        log::info!("Resumed routing to {}", backend.host.host);
+        Ok(DrainStatus::of(backend))
+    }
+
+    pub fn drain_status(&self, name: &str) -> Result<DrainStatus, PostgresError> {
+        self.find_backend(name).map(|backend| DrainStatus::of(backend))
+    }
+
+    pub fn drain_statuses(&self) -> Vec<DrainStatus> {
+        self.backends.iter().map(|backend| DrainStatus::of(backend)).collect()
+    }
+}
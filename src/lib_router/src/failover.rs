@@ -0,0 +1,154 @@
+use log;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use lib_config::HostRole;
+use lib_pgsqlcli::PostgresError;
+
+use crate::health::HealthChecker;
+use crate::Backend;
+
+/// SQLSTATE admin_shutdown; drivers treat it as a dropped connection and reconnect.
+pub const FAILOVER_SQLSTATE: &str = "57P01";
+
+/// Applies the roles found by the health checker to the backends and counts
+/// failovers. Every time a host stops being a usable primary the epoch moves
+/// on, which invalidates sessions that were served by it.
+#[derive(Default)]
+pub struct Failover {
+    epoch: AtomicU64,
+    primaries: Mutex<Vec<String>>,
+}
+
+impl Failover {
+    pub fn epoch(&self) -> u64 {
+        self.epoch.load(Ordering::SeqCst)
+    }
+
+    pub fn update(&self, backends: &[Arc<Backend>], health: &HealthChecker) {
+        for backend in backends {
+            let detected = match health.detected_role(&backend.host.host) {
+                Some(role) => role,
+                None => continue,
+            };
+            let current = backend.role();
+            if detected == current {
+                continue;
+            }
+
+            if current != HostRole::Auto {
+                log::warn!("Host {} changed role from {:?} to {:?}", backend.host.host, current, detected);
+            }
+            if current != HostRole::Replica && detected == HostRole::Replica {
+                // Pooled connections may carry session state from the old primary
+                backend.close_idle();
+            }
+            backend.set_role(detected);
+        }
+
+        let primaries: Vec<String> = backends
+            .iter()
+            .filter(|backend| backend.role() == HostRole::Primary && health.is_healthy(&backend.host.host))
+            .map(|backend| backend.host.host.clone())
+            .collect();
+
+        let mut previous = self.primaries.lock().unwrap();
+        let lost = previous.iter().filter(|host| !primaries.contains(host)).count();
+        if lost > 0 {
+            let epoch = self.epoch.fetch_add(1, Ordering::SeqCst) + 1;
+            log::warn!("Primary failover detected, current primaries: {:?} (epoch {})", primaries, epoch);
+        }
+        *previous = primaries;
+    }
+}
+
+pub fn failover_error() -> PostgresError {
+    PostgresError::Server {
+        code: FAILOVER_SQLSTATE.to_string(),
+        message: "terminating connection due to primary failover".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{config, FakeServer};
+    use crate::{Login, Router, Session};
+
+    async fn router(primary: &FakeServer, replica: &FakeServer) -> Router {
+        Router::new(&config(serde_json::json!({
+            "postgresql_hosts": [
+                { "host": primary.host, "role": "auto" },
+                { "host": replica.host, "role": "auto" },
+            ],
+            "replication_mode": true,
+        })))
+        .await
+        .unwrap()
+    }
+
+    async fn check(router: &Router) {
+        router.health.check_all().await;
+        router.failover.update(router.backends(), &router.health);
+    }
+
+    #[tokio::test]
+    async fn roles_follow_the_health_checks() {
+        let (first, second) = (FakeServer::start().await, FakeServer::start().await);
+        second.set_role(true, 0x1000000, 0.0);
+        let router = router(&first, &second).await;
+        let backends = router.backends();
+
+        check(&router).await;
+        assert_eq!(backends[0].role(), HostRole::Primary);
+        assert_eq!(backends[1].role(), HostRole::Replica);
+        assert_eq!(router.failover.epoch(), 0);
+
+        // Leave an idle connection in a client pool of the primary
+        let login = Login::new("app", "app", None);
+        router.simple_query(&mut Session::new(login.clone()), "INSERT INTO t VALUES (1)").await.unwrap();
+        let pool = backends[0].client_pool(&login).await.unwrap();
+        assert_eq!(pool.stats().idle, 1);
+
+        first.set_role(true, 0x1000000, 0.0);
+        second.set_role(false, 0x2000000, 0.0);
+        check(&router).await;
+        assert_eq!(backends[0].role(), HostRole::Replica);
+        assert_eq!(backends[1].role(), HostRole::Primary);
+        assert_eq!(router.failover.epoch(), 1);
+        assert_eq!(pool.stats().idle, 0);
+
+        // A steady state starts no new epoch
+        check(&router).await;
+        assert_eq!(router.failover.epoch(), 1);
+    }
+
+    #[tokio::test]
+    async fn sessions_on_a_failed_primary_are_terminated() {
+        let (first, second) = (FakeServer::start().await, FakeServer::start().await);
+        second.set_role(true, 0x1000000, 0.0);
+        let router = router(&first, &second).await;
+        check(&router).await;
+
+        let mut session = Session::new(Login::new("app", "app", None));
+        router.simple_query(&mut session, "BEGIN").await.unwrap();
+        router.simple_query(&mut session, "INSERT INTO t VALUES (1)").await.unwrap();
+        assert_eq!(session.connection_host(), Some(first.host.as_str()));
+
+        first.set_role(true, 0x1000000, 0.0);
+        second.set_role(false, 0x2000000, 0.0);
+        check(&router).await;
+        match router.simple_query(&mut session, "INSERT INTO t VALUES (2)").await {
+            Err(PostgresError::Server { code, .. }) => assert_eq!(code, FAILOVER_SQLSTATE),
+            other => panic!("expected the session to be terminated, got {:?}", other.map(|result| result.command_tag)),
+        }
+        assert_eq!(first.count("INSERT INTO t VALUES (2)"), 0);
+        assert!(session.connection_host().is_none());
+        assert!(!session.in_transaction());
+        assert_eq!(router.backends()[0].in_use(), 0);
+
+        // The client reconnects and carries on with the new primary
+        router.simple_query(&mut session, "INSERT INTO t VALUES (3)").await.unwrap();
+        assert_eq!(second.count("INSERT INTO t VALUES (3)"), 1);
+    }
+}
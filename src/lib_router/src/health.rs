@@ -0,0 +1,352 @@
+use log;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::task::{JoinHandle, JoinSet};
+
+use lib_config::{HostRole, PostgresqlHost};
+use lib_pgsqlcli::{PostgresClient, PostgresError, PostgresValue};
+
+use crate::balancer::LoadBalancer;
+
+const PROBE_DATABASE: &str = "postgres";
+// Doubles as primary detection and lag measurement. A replica that has replayed
+// everything it received is caught up even if its last replayed commit is old.
+const PROBE_QUERY: &str = "SELECT pg_is_in_recovery(), \
+    CASE WHEN pg_is_in_recovery() THEN pg_last_wal_replay_lsn() ELSE pg_current_wal_lsn() END, \
+    CASE WHEN pg_is_in_recovery() AND pg_last_wal_receive_lsn() IS DISTINCT FROM pg_last_wal_replay_lsn() \
+        THEN extract(epoch FROM now() - pg_last_xact_replay_timestamp()) ELSE 0 END";
+
+struct Probe {
+    role: HostRole,
+    lsn: Option<u64>,
+    replay_delay: Option<Duration>,
+}
+
+/// Parses a `pg_lsn` such as `16/B374D848` into its 64-bit position.
+pub fn parse_lsn(lsn: &str) -> Option<u64> {
+    let (high, low) = lsn.split_once('/')?;
+    let high = u64::from_str_radix(high, 16).ok()?;
+    let low = u64::from_str_radix(low, 16).ok()?;
+    Some((high << 32) | low)
+}
+
+fn as_f64(value: &PostgresValue) -> Option<f64> {
+    match value {
+        PostgresValue::Float64(f) => Some(*f),
+        PostgresValue::Float32(f) => Some(*f as f64),
+        PostgresValue::String(s) => s.parse().ok(),
+        _ => None,
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HealthState {
+    Up,
+    /// Probes are failing but fewer than `fall` in a row; still routable.
+    Degraded,
+    Down,
+}
+
+#[derive(Clone, Debug)]
+pub struct HealthCheckConfig {
+    pub interval: Duration,
+    pub timeout: Duration,
+    pub rise: u32,
+    pub fall: u32,
+}
+
+impl Default for HealthCheckConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(60),
+            timeout: Duration::from_secs(5),
+            rise: 2,
+            fall: 3,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct HostHealth {
+    pub state: HealthState,
+    pub consecutive_successes: u32,
+    pub consecutive_failures: u32,
+    pub last_check: Option<Instant>,
+    pub last_latency: Option<Duration>,
+    pub last_error: Option<String>,
+    pub detected_role: Option<HostRole>,
+    /// Current WAL position on a primary, last replayed position on a replica.
+    pub lsn: Option<u64>,
+    pub replay_delay: Option<Duration>,
+    /// How far a replica's replay position is behind the primary's WAL.
+    pub lag_bytes: Option<u64>,
+}
+
+impl HostHealth {
+    fn new() -> Self {
+        HostHealth {
+            state: HealthState::Up,
+            consecutive_successes: 0,
+            consecutive_failures: 0,
+            last_check: None,
+            last_latency: None,
+            last_error: None,
+            detected_role: None,
+            lsn: None,
+            replay_delay: None,
+            lag_bytes: None,
+        }
+    }
+}
+
+/// Periodically probes every configured host with a connect plus a trivial
+/// query over its admin credentials and tracks up/degraded/down transitions.
+pub struct HealthChecker {
+    hosts: Vec<PostgresqlHost>,
+    status: Arc<Mutex<HashMap<String, HostHealth>>>,
+    config: HealthCheckConfig,
+    balancer: Arc<dyn LoadBalancer>,
+}
+
+impl HealthChecker {
+    pub fn new(hosts: Vec<PostgresqlHost>, config: HealthCheckConfig, balancer: Arc<dyn LoadBalancer>) -> Self {
+        let status = hosts
+            .iter()
+            .map(|host| (host.host.clone(), HostHealth::new()))
+            .collect();
+
+        HealthChecker {
+            hosts,
+            status: Arc::new(Mutex::new(status)),
+            config,
+            balancer,
+        }
+    }
+
+    /// Spawns the probe loop; `after_round` runs once every host has been probed.
+    pub fn start<F>(self: &Arc<Self>, after_round: F) -> JoinHandle<()>
+    where
+        F: Fn(&HealthChecker) + Send + 'static,
+    {
+        let checker = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(checker.config.interval);
+            loop {
+                interval.tick().await;
+                checker.check_all().await;
+                after_round(&checker);
+            }
+        })
+    }
+
+    /// Probes every host at once, so a hung host delays a round by no more than `timeout`.
+    pub async fn check_all(&self) {
+        let mut probes = JoinSet::new();
+        for host in &self.hosts {
+            let (host, timeout) = (host.clone(), self.config.timeout);
+            probes.spawn(async move {
+                let started = Instant::now();
+                let result = match tokio::time::timeout(timeout, Self::probe(&host)).await {
+                    Ok(result) => result,
+                    Err(_) => Err(PostgresError::Unavailable("health check timed out".into())),
+                };
+                (host.host, result.map(|probe| (probe, started.elapsed())))
+            });
+        }
+        while let Some(probe) = probes.join_next().await {
+            match probe {
+                Ok((host, result)) => self.record(&host, result),
+                Err(e) => log::error!("Health check probe failed to run: {}", e),
+            }
+        }
+        self.update_lag();
+    }
+
+    fn update_lag(&self) {
+        let mut status = self.status.lock().unwrap();
+        let primary_lsn = status
+            .values()
+            .filter(|h| h.state != HealthState::Down && h.detected_role == Some(HostRole::Primary))
+            .filter_map(|h| h.lsn)
+            .max();
+
+        for health in status.values_mut() {
+            health.lag_bytes = match (health.detected_role, health.lsn, primary_lsn) {
+                (Some(HostRole::Replica), Some(lsn), Some(primary_lsn)) => Some(primary_lsn.saturating_sub(lsn)),
+                _ => None,
+            };
+        }
+    }
+
+    async fn probe(host: &PostgresqlHost) -> Result<Probe, PostgresError> {
+        let mut client = PostgresClient::connect(&host.connection_string(PROBE_DATABASE)).await?;
+        let rows = client.query(PROBE_QUERY).await?;
+        let row = rows
+            .first()
+            .filter(|row| row.len() == 3)
+            .ok_or_else(|| PostgresError::Protocol("unexpected health check result".into()))?;
+
+        let role = match row[0].1 {
+            PostgresValue::Boolean(true) => HostRole::Replica,
+            PostgresValue::Boolean(false) => HostRole::Primary,
+            _ => return Err(PostgresError::Protocol("unexpected pg_is_in_recovery() result".into())),
+        };
+        let lsn = match &row[1].1 {
+            PostgresValue::String(lsn) => parse_lsn(lsn),
+            _ => None,
+        };
+        let replay_delay = as_f64(&row[2].1).map(|secs| Duration::from_secs_f64(secs.max(0.0)));
+
+        Ok(Probe { role, lsn, replay_delay })
+    }
+
+    fn record(&self, host: &str, result: Result<(Probe, Duration), PostgresError>) {
+        let mut status = self.status.lock().unwrap();
+        let health = status.entry(host.to_string()).or_insert_with(HostHealth::new);
+        let previous = health.state;
+        health.last_check = Some(Instant::now());
+
+        match result {
+            Ok((probe, latency)) => {
+                // Probe round trips keep latency-aware balancing informed even for idle hosts
+                self.balancer.record_latency(host, latency);
+                health.consecutive_successes += 1;
+                health.consecutive_failures = 0;
+                health.last_latency = Some(latency);
+                health.last_error = None;
+                health.detected_role = Some(probe.role);
+                health.lsn = probe.lsn;
+                health.replay_delay = probe.replay_delay;
+                if health.state != HealthState::Down || health.consecutive_successes >= self.config.rise {
+                    health.state = HealthState::Up;
+                }
+            }
+            Err(e) => {
+                health.consecutive_failures += 1;
+                health.consecutive_successes = 0;
+                health.last_error = Some(e.to_string());
+                health.state = if health.consecutive_failures >= self.config.fall
+                    || health.state == HealthState::Down
+                {
+                    HealthState::Down
+                } else {
+                    HealthState::Degraded
+                };
+            }
+        }
+
+        if health.state != previous {
+            match health.state {
+                HealthState::Up => log::info!("Host {} is up", host),
+                HealthState::Degraded => log::warn!(
+                    "Host {} is degraded: {}",
+                    host,
+                    health.last_error.as_deref().unwrap_or("")
+                ),
+                HealthState::Down => log::error!(
+                    "Host {} is down: {}",
+                    host,
+                    health.last_error.as_deref().unwrap_or("")
+                ),
+            }
+        }
+    }
+
+    pub fn state(&self, host: &str) -> HealthState {
+        self.status
+            .lock()
+            .unwrap()
+            .get(host)
+            .map_or(HealthState::Down, |health| health.state)
+    }
+
+    pub fn is_healthy(&self, host: &str) -> bool {
+        self.state(host) != HealthState::Down
+    }
+
+    pub fn detected_role(&self, host: &str) -> Option<HostRole> {
+        self.status.lock().unwrap().get(host).and_then(|health| health.detected_role)
+    }
+
+    pub fn health(&self, host: &str) -> Option<HostHealth> {
+        self.status.lock().unwrap().get(host).cloned()
+    }
+
+    pub fn status(&self) -> HashMap<String, HostHealth> {
+        self.status.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::balancer::LeastOutstanding;
+    use crate::testing::{config, FakeServer};
+
+    fn checker(hosts: &[&str], rise: u32, fall: u32) -> HealthChecker {
+        let hosts: Vec<serde_json::Value> = hosts.iter().map(|host| serde_json::json!({ "host": host })).collect();
+        let config = config(serde_json::json!({ "postgresql_hosts": hosts }));
+        let health_config = HealthCheckConfig {
+            timeout: Duration::from_millis(200),
+            rise,
+            fall,
+            ..HealthCheckConfig::default()
+        };
+        HealthChecker::new(config.postgresql_hosts, health_config, Arc::new(LeastOutstanding))
+    }
+
+    fn succeed(checker: &HealthChecker, host: &str) -> HealthState {
+        let probe = Probe { role: HostRole::Primary, lsn: None, replay_delay: None };
+        checker.record(host, Ok((probe, Duration::from_millis(1))));
+        checker.state(host)
+    }
+
+    fn fail(checker: &HealthChecker, host: &str) -> HealthState {
+        checker.record(host, Err(PostgresError::Unavailable("refused".into())));
+        checker.state(host)
+    }
+
+    #[test]
+    fn hosts_go_down_after_fall_failures_and_up_after_rise_successes() {
+        let checker = checker(&["db"], 2, 3);
+        assert_eq!(checker.state("db"), HealthState::Up);
+
+        assert_eq!(fail(&checker, "db"), HealthState::Degraded);
+        assert_eq!(fail(&checker, "db"), HealthState::Degraded);
+        // A success before the host went down clears the failures
+        assert_eq!(succeed(&checker, "db"), HealthState::Up);
+        assert_eq!(fail(&checker, "db"), HealthState::Degraded);
+        assert_eq!(fail(&checker, "db"), HealthState::Degraded);
+        assert_eq!(fail(&checker, "db"), HealthState::Down);
+        assert!(!checker.is_healthy("db"));
+
+        assert_eq!(succeed(&checker, "db"), HealthState::Down);
+        // A failure while down restarts the count of successes
+        assert_eq!(fail(&checker, "db"), HealthState::Down);
+        assert_eq!(succeed(&checker, "db"), HealthState::Down);
+        assert_eq!(succeed(&checker, "db"), HealthState::Up);
+        assert_eq!(checker.health("db").unwrap().consecutive_successes, 2);
+    }
+
+    #[tokio::test]
+    async fn hung_hosts_do_not_delay_the_others() {
+        let hung = [FakeServer::start().await, FakeServer::start().await];
+        let healthy = FakeServer::start().await;
+        for server in &hung {
+            server.set_delay(Duration::from_secs(5));
+        }
+        let checker = checker(&[&hung[0].host, &hung[1].host, &healthy.host], 1, 1);
+
+        let started = Instant::now();
+        checker.check_all().await;
+        // One after the other, the probes would take two timeouts of 200ms
+        assert!(started.elapsed() < Duration::from_millis(350), "{:?}", started.elapsed());
+        assert_eq!(checker.state(&healthy.host), HealthState::Up);
+        for server in &hung {
+            let health = checker.health(&server.host).unwrap();
+            assert_eq!(health.state, HealthState::Down);
+            assert_eq!(health.last_error.as_deref(), Some("Host unavailable: health check timed out"));
+        }
+    }
+}
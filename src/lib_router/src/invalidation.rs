@@ -0,0 +1,511 @@
+use log;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+use lib_cache::QueryCache;
+use lib_pgsqlcli::config::ConnectionConfig;
+use lib_pgsqlcli::replication::format_lsn;
+use lib_pgsqlcli::{Connection, LogicalMessage, PostgresClient, PostgresError, ReplicationMessage, ReplicationStream};
+use lib_query::canonical;
+use lib_query::lexer::tokenize;
+
+use crate::{Backend, Failover, Router, DEFAULT_DATABASE};
+
+const DEFAULT_RECONNECT_INTERVAL: u64 = 5;
+const EPOCH_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What a NOTIFY payload on the invalidation channel asks to drop from the
+/// query cache of the database it was sent in:
+///
+/// - `table:orders,public.items`, or just `orders,items`: results that read those
+///   tables, whose names fold to lower case unless double-quoted, as in SQL
+/// - `query:SELECT ...`: results of that query, whatever its parameters and role
+/// - an empty payload or `*`: every result
+///
+/// A trigger such as `PERFORM pg_notify('pgshield', 'table:' || quote_ident(TG_TABLE_NAME))`
+/// covers writes made by clients connected directly to the database; quoting
+/// keeps the case of mixed-case table names.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Invalidation {
+    Tables(Vec<String>),
+    /// Canonical query text.
+    Query(String),
+    All,
+}
+
+impl Invalidation {
+    pub fn parse(payload: &str) -> Self {
+        let payload = payload.trim();
+        if payload.is_empty() || payload == "*" {
+            return Invalidation::All;
+        }
+        if let Some(query) = payload.strip_prefix("query:") {
+            return Invalidation::Query(canonical(query));
+        }
+        let tables = payload.strip_prefix("table:").unwrap_or(payload);
+        let tokens = tokenize(tables);
+        Invalidation::Tables(
+            tokens
+                .split(|token| token.is_symbol(","))
+                // Cache entries record unqualified names
+                .filter_map(|name| name.iter().rev().find_map(|token| token.ident()))
+                .map(str::to_string)
+                .collect(),
+        )
+    }
+
+    /// Drops the matching entries of `database` and returns how many were dropped.
+    pub fn apply(&self, cache: &QueryCache, database: &str) -> usize {
+        match self {
+            Invalidation::Tables(tables) => cache.invalidate_tables(database, tables),
+            Invalidation::Query(query) => cache.invalidate_query(database, query),
+            Invalidation::All => cache.invalidate_database(database),
+        }
+    }
+}
+
+/// Where a listener learns about writes made outside pgShield.
+#[derive(Clone)]
+enum Source {
+    Notify { channel: String },
+    /// Changes decoded from a temporary slot, dropped by the server along
+    /// with the connection so an absent pgShield never holds back WAL.
+    Replication { publication: String, slot: String },
+}
+
+impl Router {
+    pub fn query_cache(&self) -> &Arc<QueryCache> {
+        &self.query_cache
+    }
+
+    /// Spawns, for every configured database, a task holding a dedicated
+    /// connection to the primary: one LISTENing on `listen_channel` and one
+    /// streaming `publication` through logical replication, for whichever are
+    /// configured. Each applies the invalidations it receives to the query cache.
+    pub fn start_cache_invalidation(&self) -> Vec<JoinHandle<()>> {
+        let config = match &self.cache_invalidation {
+            Some(config) => config,
+            None => return Vec::new(),
+        };
+        let reconnect = Duration::from_secs(config.reconnect_interval.unwrap_or(DEFAULT_RECONNECT_INTERVAL).max(1));
+        let databases = config
+            .databases
+            .clone()
+            .unwrap_or_else(|| vec![DEFAULT_DATABASE.to_string()]);
+
+        let mut sources = Vec::new();
+        if let Some(channel) = &config.listen_channel {
+            sources.push(Source::Notify { channel: channel.clone() });
+        }
+        if let Some(publication) = &config.publication {
+            // Slot names are unique across the cluster, which other pgShield
+            // instances may share
+            sources.push(Source::Replication {
+                publication: publication.clone(),
+                slot: format!("pgshield_{:08x}", rand::random::<u32>()),
+            });
+        }
+
+        let mut handles = Vec::new();
+        for source in sources {
+            for (index, database) in databases.iter().enumerate() {
+                let source = match &source {
+                    Source::Replication { publication, slot } => Source::Replication {
+                        publication: publication.clone(),
+                        slot: format!("{}_{}", slot, index),
+                    },
+                    notify => notify.clone(),
+                };
+                let listener = Listener {
+                    backends: self.backends.clone(),
+                    failover: Arc::clone(&self.failover),
+                    cache: Arc::clone(&self.query_cache),
+                    source,
+                    database: database.clone(),
+                };
+                handles.push(tokio::spawn(async move {
+                    loop {
+                        if let Err(e) = listener.run().await {
+                            log::warn!("Cache invalidation {} for {} stopped: {}", listener, listener.database, e);
+                        }
+                        tokio::time::sleep(reconnect).await;
+                    }
+                }));
+            }
+        }
+        handles
+    }
+}
+
+struct Listener {
+    backends: Vec<Arc<Backend>>,
+    failover: Arc<Failover>,
+    cache: Arc<QueryCache>,
+    source: Source,
+    database: String,
+}
+
+impl std::fmt::Display for Listener {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.source {
+            Source::Notify { channel } => write!(f, "listener on channel {}", channel),
+            Source::Replication { publication, slot } => {
+                write!(f, "stream of publication {} through slot {}", publication, slot)
+            }
+        }
+    }
+}
+
+impl Listener {
+    /// Follows the source until the connection is lost or the primary fails over.
+    async fn run(&self) -> Result<(), PostgresError> {
+        let epoch = self.failover.epoch();
+        let mut primaries = self
+            .backends
+            .iter()
+            .filter(|b| b.is_primary() && !b.is_draining() && b.pool.is_available());
+        let mut last_error = PostgresError::Unavailable("no primary to follow".into());
+
+        loop {
+            let backend = match primaries.next() {
+                Some(backend) => backend,
+                None => return Err(last_error),
+            };
+            let connection_string = backend.host.connection_string(&self.database);
+
+            let received = match &self.source {
+                Source::Notify { channel } => {
+                    let mut client = match PostgresClient::connect(&connection_string).await {
+                        Ok(client) => client,
+                        Err(e) => {
+                            last_error = e;
+                            continue;
+                        }
+                    };
+                    client.listen(channel).await?;
+                    self.connected(backend);
+                    self.until_failover(epoch, self.receive(&mut client, channel)).await
+                }
+                Source::Replication { publication, slot } => {
+                    let mut config = ConnectionConfig::from_connection_string(&connection_string)?;
+                    config.replication = true;
+                    let mut connection = match Connection::new(&config).await {
+                        Ok(connection) => connection,
+                        Err(e) => {
+                            last_error = e;
+                            continue;
+                        }
+                    };
+                    let sql = format!("CREATE_REPLICATION_SLOT \"{}\" TEMPORARY LOGICAL pgoutput", slot);
+                    connection.simple_query(&sql).await?;
+                    let mut stream = ReplicationStream::start(connection, slot, publication).await?;
+                    self.connected(backend);
+                    self.until_failover(epoch, self.consume(&mut stream)).await
+                }
+            };
+
+            if received.is_ok() {
+                log::info!("Primary failover, moving cache invalidation {} off {}", self, backend.host.host);
+            }
+            return received;
+        }
+    }
+
+    fn connected(&self, backend: &Backend) {
+        // Writes made while nothing was following them went unnoticed
+        let dropped = self.cache.invalidate_database(&self.database);
+        log::info!(
+            "Cache invalidation {} started for {} on {}, dropped {} cached results",
+            self,
+            self.database,
+            backend.host.host,
+            dropped
+        );
+    }
+
+    /// Runs `receive` until it fails or the primary fails over, in which case
+    /// it is abandoned along with its connection.
+    async fn until_failover(
+        &self,
+        epoch: u64,
+        receive: impl std::future::Future<Output = Result<(), PostgresError>>,
+    ) -> Result<(), PostgresError> {
+        tokio::select! {
+            result = receive => result,
+            _ = async {
+                while self.failover.epoch() == epoch {
+                    tokio::time::sleep(EPOCH_POLL_INTERVAL).await;
+                }
+            } => Ok(()),
+        }
+    }
+
+    async fn receive(&self, client: &mut PostgresClient, channel: &str) -> Result<(), PostgresError> {
+        loop {
+            let notification = client.notification().await?;
+            if notification.channel != channel {
+                continue;
+            }
+            let invalidation = Invalidation::parse(&notification.payload);
+            let dropped = invalidation.apply(&self.cache, &self.database);
+            log::debug!(
+                "Invalidation {:?} from backend {} dropped {} cached results in {}",
+                invalidation,
+                notification.process_id,
+                dropped,
+                self.database
+            );
+        }
+    }
+
+    /// Invalidates the tables of each transaction as its commit streams in,
+    /// confirming the WAL behind it so the slot does not retain it.
+    async fn consume(&self, stream: &mut ReplicationStream) -> Result<(), PostgresError> {
+        let mut changes = Changes::default();
+        while let Some(message) = stream.next().await? {
+            match changes.follow(message)? {
+                Step::Committed { tables, end_lsn } => {
+                    let dropped = self.committed(tables);
+                    log::debug!(
+                        "Transaction committed at {} dropped {} cached results in {}",
+                        format_lsn(end_lsn),
+                        dropped,
+                        self.database
+                    );
+                    stream.send_status(changes.confirmed).await?;
+                }
+                Step::Reply => stream.send_status(changes.confirmed).await?,
+                Step::Continue => {}
+            }
+        }
+        Err(PostgresError::Unavailable("replication stream ended".into()))
+    }
+
+    /// Drops the results reading `tables`, or every result of the database
+    /// when they are unknown.
+    fn committed(&self, tables: Option<Vec<String>>) -> usize {
+        match tables {
+            Some(tables) => self.cache.invalidate_tables(&self.database, &tables),
+            // pgoutput describes each relation before its first change,
+            // so this is not expected; flush rather than miss a write
+            None => self.cache.invalidate_database(&self.database),
+        }
+    }
+}
+
+/// What the replication stream asks of its consumer after a message.
+#[derive(Debug, PartialEq)]
+enum Step {
+    Continue,
+    /// A transaction committed, changing `tables`; None when a relation it
+    /// changed was never described.
+    Committed { tables: Option<Vec<String>>, end_lsn: u64 },
+    /// The server wants a status update.
+    Reply,
+}
+
+/// Follows the transactions of a pgoutput stream: the tables each one
+/// changed and the WAL position that can be confirmed.
+#[derive(Default)]
+struct Changes {
+    relations: HashMap<u32, String>,
+    changed: Vec<u32>,
+    in_transaction: bool,
+    /// Only ever the end of a committed transaction, or a keepalive's end of
+    /// WAL between transactions.
+    confirmed: u64,
+}
+
+impl Changes {
+    fn follow(&mut self, message: ReplicationMessage) -> Result<Step, PostgresError> {
+        match message {
+            ReplicationMessage::XLogData { data, .. } => match LogicalMessage::parse(&data)? {
+                LogicalMessage::Begin { .. } => {
+                    self.in_transaction = true;
+                    self.changed.clear();
+                }
+                // Cache entries record unqualified names
+                LogicalMessage::Relation { oid, name, .. } => {
+                    self.relations.insert(oid, name);
+                }
+                LogicalMessage::Change { relation } => {
+                    if !self.changed.contains(&relation) {
+                        self.changed.push(relation);
+                    }
+                }
+                LogicalMessage::Truncate { relations } => self.changed.extend(relations),
+                LogicalMessage::Commit { end_lsn, .. } => {
+                    self.in_transaction = false;
+                    self.confirmed = end_lsn;
+                    let tables = self.changed.drain(..).map(|oid| self.relations.get(&oid).cloned()).collect();
+                    return Ok(Step::Committed { tables, end_lsn });
+                }
+                LogicalMessage::Other(_) => {}
+            },
+            ReplicationMessage::Keepalive { end, reply } => {
+                // Between transactions everything up to the end of WAL has been seen
+                if !self.in_transaction {
+                    self.confirmed = self.confirmed.max(end);
+                }
+                if reply {
+                    return Ok(Step::Reply);
+                }
+            }
+        }
+        Ok(Step::Continue)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tables(names: &[&str]) -> Invalidation {
+        Invalidation::Tables(names.iter().map(|name| name.to_string()).collect())
+    }
+
+    #[test]
+    fn payloads() {
+        assert_eq!(Invalidation::parse(""), Invalidation::All);
+        assert_eq!(Invalidation::parse(" * "), Invalidation::All);
+        assert_eq!(Invalidation::parse("table:orders, public.items"), tables(&["orders", "items"]));
+        assert_eq!(Invalidation::parse("orders"), tables(&["orders"]));
+        assert_eq!(
+            Invalidation::parse("query:SELECT * FROM orders WHERE id = 1"),
+            Invalidation::Query(canonical("select  *  from orders where id = 1"))
+        );
+    }
+
+    #[test]
+    fn table_names_fold_like_sql() {
+        assert_eq!(Invalidation::parse("table:Orders,PUBLIC.Items"), tables(&["orders", "items"]));
+        assert_eq!(
+            Invalidation::parse(r#"table:"Orders","Sales"."Line.Items",public."items""#),
+            tables(&["Orders", "Line.Items", "items"])
+        );
+    }
+
+    #[test]
+    fn trigger_payloads_invalidate_mixed_case_tables() {
+        let cache = QueryCache::new(Duration::from_secs(60));
+        let scope = lib_cache::CacheScope { database: "app".to_string(), ..Default::default() };
+        let result = lib_cache::CachedResult {
+            row_description: None,
+            data_rows: Vec::new(),
+            command_tag: "SELECT 0".to_string(),
+        };
+        for sql in [r#"SELECT * FROM "Orders""#, "SELECT * FROM orders"] {
+            let reads = lib_query::tables(sql).reads;
+            cache.set(lib_query::cache_key(sql, &[], scope.clone()), result.clone(), reads);
+        }
+
+        // As sent by `'table:' || quote_ident(TG_TABLE_NAME)` for each table
+        assert_eq!(Invalidation::parse(r#"table:"Orders""#).apply(&cache, "app"), 1);
+        assert_eq!(cache.stats().entries, 1);
+        assert_eq!(Invalidation::parse("table:orders").apply(&cache, "app"), 1);
+        assert_eq!(cache.stats().entries, 0);
+    }
+
+    fn xlog(message: &[u8]) -> ReplicationMessage {
+        ReplicationMessage::XLogData { start: 0, end: 0, data: bytes::BytesMut::from(message) }
+    }
+
+    fn relation(oid: u32, name: &str) -> ReplicationMessage {
+        let mut message = vec![b'R'];
+        message.extend_from_slice(&oid.to_be_bytes());
+        message.extend_from_slice(b"public\0");
+        message.extend_from_slice(name.as_bytes());
+        message.extend_from_slice(b"\0d\0\x00\x00");
+        xlog(&message)
+    }
+
+    fn change(tag: u8, oid: u32) -> ReplicationMessage {
+        let mut message = vec![tag];
+        message.extend_from_slice(&oid.to_be_bytes());
+        message.extend_from_slice(b"N\x00\x00");
+        xlog(&message)
+    }
+
+    fn commit(end_lsn: u64) -> ReplicationMessage {
+        let mut message = vec![b'C', 0];
+        message.extend_from_slice(&(end_lsn - 8).to_be_bytes());
+        message.extend_from_slice(&end_lsn.to_be_bytes());
+        message.extend_from_slice(&0i64.to_be_bytes());
+        xlog(&message)
+    }
+
+    fn begin() -> ReplicationMessage {
+        xlog(&[&[b'B'][..], &[0; 20][..]].concat())
+    }
+
+    fn keepalive(end: u64) -> ReplicationMessage {
+        ReplicationMessage::Keepalive { end, reply: true }
+    }
+
+    #[test]
+    fn commits_invalidate_the_relations_they_changed() {
+        let cache = Arc::new(QueryCache::new(Duration::from_secs(60)));
+        let listener = Listener {
+            backends: Vec::new(),
+            failover: Arc::new(Failover::default()),
+            cache: Arc::clone(&cache),
+            source: Source::Notify { channel: "pgshield".to_string() },
+            database: "app".to_string(),
+        };
+        let scope = lib_cache::CacheScope { database: "app".to_string(), ..Default::default() };
+        let result = lib_cache::CachedResult {
+            row_description: None,
+            data_rows: Vec::new(),
+            command_tag: "SELECT 0".to_string(),
+        };
+        for table in ["orders", "items", "users", "events"] {
+            let sql = format!("SELECT * FROM {}", table);
+            cache.set(lib_query::cache_key(&sql, &[], scope.clone()), result.clone(), vec![table.to_string()]);
+        }
+
+        let mut changes = Changes::default();
+        let mut follow = |message| changes.follow(message).unwrap();
+        for (oid, name) in [(1, "orders"), (2, "items"), (3, "users"), (4, "events")] {
+            assert_eq!(follow(relation(oid, name)), Step::Continue);
+        }
+        assert_eq!(follow(keepalive(100)), Step::Reply);
+        assert_eq!(follow(begin()), Step::Continue);
+        follow(change(b'I', 1));
+        follow(change(b'U', 1));
+        // Nothing inside the transaction may be confirmed before it commits
+        assert_eq!(follow(keepalive(300)), Step::Reply);
+        follow(change(b'D', 2));
+        follow(xlog(&[b'T', 0, 0, 0, 1, 0, 0, 0, 0, 3]));
+        assert_eq!(changes.confirmed, 100);
+
+        let tables = match changes.follow(commit(250)).unwrap() {
+            Step::Committed { tables, end_lsn } => {
+                assert_eq!(end_lsn, 250);
+                tables
+            }
+            other => panic!("expected a commit, got {:?}", other),
+        };
+        assert_eq!(tables.as_deref(), Some(&["orders".to_string(), "items".to_string(), "users".to_string()][..]));
+        assert_eq!(changes.confirmed, 250);
+        assert_eq!(listener.committed(tables), 3);
+        assert_eq!(cache.stats().entries, 1);
+
+        // Between transactions the end of WAL can be confirmed
+        assert_eq!(changes.follow(keepalive(400)).unwrap(), Step::Reply);
+        assert_eq!(changes.confirmed, 400);
+
+        // A change to a relation never described flushes the database
+        changes.follow(begin()).unwrap();
+        changes.follow(change(b'I', 9)).unwrap();
+        match changes.follow(commit(500)).unwrap() {
+            Step::Committed { tables, .. } => {
+                assert_eq!(tables, None);
+                assert_eq!(listener.committed(tables), 1);
+            }
+            other => panic!("expected a commit, got {:?}", other),
+        }
+        assert_eq!(cache.stats().entries, 0);
+    }
+}
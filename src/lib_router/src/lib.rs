@@ -0,0 +1,991 @@
+pub mod admin;
+pub mod balancer;
+pub mod caching;
+pub mod discovery;
+pub mod drain;
+pub mod failover;
+pub mod health;
+pub mod invalidation;
+pub mod mirror;
+pub mod scatter;
+pub mod session;
+pub mod sharding;
+mod statement;
+#[cfg(test)]
+mod testing;
+
+use log;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+
+use lib_cache::{QueryCache, QueryCacheConfig};
+use lib_config::{CacheInvalidationConfig, Config, HostRole, PostgresqlHost};
+use lib_pgsqlcli::{PostgresClient, PostgresError, PostgresValue, QueryResult, TransactionStatus};
+use lib_pool::{Pool, PoolConfig};
+use lib_query::hints::{parse_hints, strip_hints, QueryHints, RouteHint};
+use lib_query::lexer::split_statements;
+use lib_query::{classify, extract_shard_key, parse_setting, tables, StatementKind};
+
+pub use balancer::LoadBalancer;
+pub use caching::CachedResponse;
+pub use drain::DrainStatus;
+pub use failover::{Failover, FAILOVER_SQLSTATE};
+pub use health::{parse_lsn, HealthCheckConfig, HealthChecker, HealthState, HostHealth};
+pub use invalidation::Invalidation;
+pub use mirror::{Mirror, MirrorStats};
+pub use session::{Login, Session, Target};
+pub use sharding::{Shard, ShardMap};
+
+pub const DEFAULT_DATABASE: &str = "postgres";
+const CATCH_UP_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+pub struct Backend {
+    pub host: PostgresqlHost,
+    /// Admin pool for the host's default database, used for health and
+    /// replication probes rather than client statements.
+    pub pool: Arc<Pool>,
+    /// Databases found by discovery. Client statements run on `client_pools`,
+    /// so only the names are kept.
+    databases: Mutex<HashSet<String>>,
+    /// Whether `databases` holds the result of a database discovery, so the
+    /// host serves no other database.
+    discovered: AtomicBool,
+    /// Pools running client statements, one per login.
+    client_pools: Mutex<HashMap<Login, Arc<Pool>>>,
+    role: Mutex<HostRole>,
+    replay_lsn: AtomicU64,
+}
+
+impl Backend {
+    pub fn new(host: PostgresqlHost, pool: Pool) -> Self {
+        let role = host.role.unwrap_or_default();
+        pool.set_draining(host.drain == Some(true));
+        Backend {
+            host,
+            pool: Arc::new(pool),
+            databases: Mutex::new(HashSet::new()),
+            discovered: AtomicBool::new(false),
+            client_pools: Mutex::new(HashMap::new()),
+            role: Mutex::new(role),
+            replay_lsn: AtomicU64::new(0),
+        }
+    }
+
+    pub fn role(&self) -> HostRole {
+        *self.role.lock().unwrap()
+    }
+
+    pub fn set_role(&self, role: HostRole) {
+        *self.role.lock().unwrap() = role;
+    }
+
+    /// Databases registered besides the default one.
+    pub fn databases(&self) -> Vec<String> {
+        self.databases.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Whether sessions of `database` may be routed to this host. Hosts without
+    /// database discovery, or on which it has not completed yet, serve any database.
+    pub fn serves(&self, database: &str) -> bool {
+        database == DEFAULT_DATABASE
+            || !self.discovered.load(Ordering::SeqCst)
+            || self.databases.lock().unwrap().contains(database)
+    }
+
+    /// Restricts the host to its registered databases, once they were discovered.
+    pub fn set_discovered(&self) {
+        self.discovered.store(true, Ordering::SeqCst);
+    }
+
+    /// Allows routing sessions of `database` to this host.
+    pub fn register_database(&self, database: &str) {
+        self.databases.lock().unwrap().insert(database.to_string());
+    }
+
+    /// Stops routing to `database`. Sessions holding its pools keep working; idle
+    /// connections are closed now and the rest as their holders release them.
+    pub fn retire_database(&self, database: &str) {
+        if self.databases.lock().unwrap().remove(database) {
+            log::info!("Retiring database {} on {}", database, self.host.host);
+        }
+        self.client_pools.lock().unwrap().retain(|login, pool| {
+            if login.database == database {
+                pool.close_idle();
+            }
+            login.database != database
+        });
+    }
+
+    /// Pool of connections logged in as `login`, created on first use. Client
+    /// pools share the admin pool's settings and circuit breaker, so a host that
+    /// any of them cannot reach is taken out of rotation. Rejected logins do not
+    /// count against the host.
+    pub async fn client_pool(&self, login: &Login) -> Result<Arc<Pool>, PostgresError> {
+        if let Some(pool) = self.client_pools.lock().unwrap().get(login) {
+            return Ok(Arc::clone(pool));
+        }
+        let connection_string = self
+            .host
+            .connection_string_as(&login.database, &login.user, login.password.as_deref());
+        let pool = Pool::with_breaker(&connection_string, self.pool.config().clone(), self.pool.breaker()).await?;
+        pool.set_draining(self.is_draining());
+        let mut pools = self.client_pools.lock().unwrap();
+        Ok(Arc::clone(pools.entry(login.clone()).or_insert_with(|| Arc::new(pool))))
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.pool.is_draining()
+    }
+
+    /// Puts every pool of the host into or out of draining mode.
+    pub fn set_draining(&self, draining: bool) {
+        self.pool.set_draining(draining);
+        for pool in self.client_pools.lock().unwrap().values() {
+            pool.set_draining(draining);
+        }
+    }
+
+    /// Closes the idle connections of every pool of the host.
+    pub fn close_idle(&self) {
+        self.pool.close_idle();
+        for pool in self.client_pools.lock().unwrap().values() {
+            pool.close_idle();
+        }
+    }
+
+    /// Connections checked out across all of the host's pools.
+    pub fn in_use(&self) -> usize {
+        let clients: usize = self.client_pools.lock().unwrap().values().map(|pool| pool.in_use()).sum();
+        self.pool.in_use() + clients
+    }
+
+    /// Whether a `host=` query hint refers to this backend, by name or address.
+    pub fn matches_name(&self, name: &str) -> bool {
+        self.host.name.as_deref() == Some(name) || self.host.host == name
+    }
+
+    /// Hosts whose role is still `auto` are treated as primaries until detected.
+    pub fn is_primary(&self) -> bool {
+        self.role() != HostRole::Replica
+    }
+}
+
+pub struct Router {
+    backends: Vec<Arc<Backend>>,
+    health: Arc<HealthChecker>,
+    balancer: Arc<dyn LoadBalancer>,
+    failover: Arc<Failover>,
+    replication_mode: bool,
+    max_replica_lag: Option<Duration>,
+    max_replica_lag_bytes: Option<u64>,
+    read_your_writes_timeout: Option<Duration>,
+    read_retries: u32,
+    strip_query_hints: bool,
+    shards: Option<ShardMap>,
+    mirror: Option<Mirror>,
+    query_cache: Arc<QueryCache>,
+    cache_rules: Vec<caching::CacheRule>,
+    cache_invalidation: Option<CacheInvalidationConfig>,
+    admin_users: Vec<String>,
+}
+
+pub fn pool_config(config: &Config) -> PoolConfig {
+    PoolConfig {
+        min_size: 0,
+        max_size: config.max_conns,
+        reserve_pool_size: config.reserve_pool_size.unwrap_or(0),
+        reserve_pool_timeout: Duration::from_secs(config.reserve_pool_timeout.unwrap_or(5)),
+        connect_timeout: Duration::from_secs(config.connect_timeout.unwrap_or(10)),
+        circuit_breaker_threshold: config.circuit_breaker_threshold.unwrap_or(5),
+        circuit_breaker_cooldown: Duration::from_secs(config.circuit_breaker_cooldown.unwrap_or(30)),
+    }
+}
+
+pub fn query_cache_config(config: &Config) -> QueryCacheConfig {
+    let defaults = QueryCacheConfig::default();
+    QueryCacheConfig {
+        ttl: Duration::from_secs(config.query_cache_ttl),
+        max_bytes: config.query_cache_max_bytes.unwrap_or(defaults.max_bytes),
+        max_entry_bytes: config.query_cache_max_entry_bytes.unwrap_or(defaults.max_entry_bytes),
+        shards: config.query_cache_shards.unwrap_or(defaults.shards),
+    }
+}
+
+pub fn health_check_config(config: &Config) -> HealthCheckConfig {
+    HealthCheckConfig {
+        interval: Duration::from_secs(config.health_check_interval.max(1)),
+        timeout: Duration::from_secs(config.health_check_timeout.unwrap_or(5)),
+        rise: config.health_check_rise.unwrap_or(2).max(1),
+        fall: config.health_check_fall.unwrap_or(3).max(1),
+    }
+}
+
+impl Router {
+    pub async fn new(config: &Config) -> Result<Self, PostgresError> {
+        let mut backends = Vec::new();
+        for host in &config.postgresql_hosts {
+            let pool = Pool::with_config(&host.connection_string(DEFAULT_DATABASE), pool_config(config)).await?;
+            backends.push(Arc::new(Backend::new(host.clone(), pool)));
+        }
+
+        let strategy = config.load_balancing.clone().unwrap_or_default();
+        let balancer: Arc<dyn LoadBalancer> = Arc::from(balancer::from_strategy(&strategy));
+        let health = HealthChecker::new(
+            config.postgresql_hosts.clone(),
+            health_check_config(config),
+            Arc::clone(&balancer),
+        );
+
+        let shards = match &config.sharding {
+            Some(sharding) => Some(ShardMap::new(sharding, &backends)?),
+            None => None,
+        };
+        let mirror = match &config.mirror {
+            Some(mirror) => Some(Mirror::new(mirror, &pool_config(config)).await?),
+            None => None,
+        };
+
+        Ok(Router {
+            shards,
+            mirror,
+            query_cache: Arc::new(QueryCache::with_config(query_cache_config(config))),
+            cache_rules: caching::cache_rules(config),
+            cache_invalidation: config.cache_invalidation.clone(),
+            admin_users: config.admin_users.clone().unwrap_or_default(),
+            backends,
+            health: Arc::new(health),
+            balancer,
+            failover: Arc::new(Failover::default()),
+            replication_mode: config.replication_mode,
+            max_replica_lag: config.max_replica_lag.map(Duration::from_secs),
+            max_replica_lag_bytes: config.max_replica_lag_bytes,
+            read_retries: config.read_retries.unwrap_or(1),
+            strip_query_hints: config.strip_query_hints.unwrap_or(false),
+            read_your_writes_timeout: match config.read_your_writes {
+                Some(true) => Some(Duration::from_millis(config.read_your_writes_timeout_ms.unwrap_or(50))),
+                _ => None,
+            },
+        })
+    }
+
+    pub fn start_health_checks(&self) -> JoinHandle<()> {
+        let backends = self.backends.clone();
+        let failover = Arc::clone(&self.failover);
+        self.health.start(move |health| failover.update(&backends, health))
+    }
+
+    pub fn start_discovery(&self) -> Vec<JoinHandle<()>> {
+        discovery::start(&self.backends)
+    }
+
+    pub fn start_mirroring(&self) -> Option<JoinHandle<()>> {
+        self.mirror.as_ref().map(Mirror::start)
+    }
+
+    /// Every database reachable through at least one backend.
+    pub fn databases(&self) -> Vec<String> {
+        let mut databases = vec![DEFAULT_DATABASE.to_string()];
+        for backend in &self.backends {
+            for database in backend.databases() {
+                if !databases.contains(&database) {
+                    databases.push(database);
+                }
+            }
+        }
+        databases
+    }
+
+    pub fn failover(&self) -> &Failover {
+        &self.failover
+    }
+
+    pub fn health(&self) -> &HealthChecker {
+        &self.health
+    }
+
+    pub fn backends(&self) -> &[Arc<Backend>] {
+        &self.backends
+    }
+
+    pub fn shards(&self) -> Option<&ShardMap> {
+        self.shards.as_ref()
+    }
+
+    pub fn mirror(&self) -> Option<&Mirror> {
+        self.mirror.as_ref()
+    }
+
+    /// Backends that passed their health checks and whose circuit breaker is not open.
+    pub fn healthy_backends(&self) -> Vec<Arc<Backend>> {
+        self.healthy_among(&self.backends)
+    }
+
+    fn healthy_among(&self, candidates: &[Arc<Backend>]) -> Vec<Arc<Backend>> {
+        candidates
+            .iter()
+            .filter(|backend| self.health.is_healthy(&backend.host.host) && backend.pool.is_available())
+            .cloned()
+            .collect()
+    }
+
+    pub fn route(&self) -> Result<Arc<Backend>, PostgresError> {
+        self.pick_healthy(&accepting(&self.backends))
+    }
+
+    fn pick_healthy(&self, candidates: &[Arc<Backend>]) -> Result<Arc<Backend>, PostgresError> {
+        self.balancer
+            .pick(&self.healthy_among(candidates))
+            .ok_or_else(|| PostgresError::Unavailable("no healthy PostgreSQL host".into()))
+    }
+
+    /// Routes a statement for `session`. With `replication_mode` on, reads go to
+    /// replicas and everything else, including any statement of a transaction that
+    /// has already written, goes to the primary.
+    ///
+    /// `/* pgshield: ... */` hints in the statement override the decision: `host=`
+    /// pins it to a named host and `route=` forces the primary or a replica.
+    ///
+    /// With sharding configured only the hosts of the statement's shard are
+    /// considered; `params` are the bound parameter values, used to find its key.
+    pub async fn route_query(
+        &self,
+        session: &mut Session,
+        sql: &str,
+        params: &[PostgresValue],
+    ) -> Result<Arc<Backend>, PostgresError> {
+        self.route_excluding(session, sql, params, &[]).await
+    }
+
+    /// Routes a read again after it failed on the hosts in `failed`, picking
+    /// among the remaining hosts. Use after `can_retry` allowed the retry.
+    pub async fn reroute(
+        &self,
+        session: &mut Session,
+        sql: &str,
+        params: &[PostgresValue],
+        failed: &[Arc<Backend>],
+    ) -> Result<Arc<Backend>, PostgresError> {
+        self.route_excluding(session, sql, params, failed).await
+    }
+
+    async fn route_excluding(
+        &self,
+        session: &mut Session,
+        sql: &str,
+        params: &[PostgresValue],
+        excluded: &[Arc<Backend>],
+    ) -> Result<Arc<Backend>, PostgresError> {
+        let hints = parse_hints(sql);
+        let kind = classify(sql);
+        let mut candidates = match &self.shards {
+            Some(shards) => self.shard_candidates(shards, session, sql, params, &hints, kind)?,
+            None => self.backends.clone(),
+        };
+        candidates.retain(|backend| !excluded.iter().any(|failed| Arc::ptr_eq(failed, backend)));
+        let database = &session.login().database;
+        let mut candidates = serving(&candidates, database)?;
+        // Draining hosts only finish the transactions already running on them
+        if !session.in_transaction() {
+            candidates = accepting(&candidates);
+        }
+
+        if !self.replication_mode {
+            return match &hints.host {
+                Some(name) => self.route_named(name).and_then(|backend| serving_named(backend, database)),
+                None => self.pick_healthy(&candidates),
+            };
+        }
+
+        let epoch = self.check_failover(session)?;
+
+        let mut target = session.target_for(kind);
+        // The session keeps the connection its settings were changed on, which
+        // must be able to take its writes too, as must a deferred BEGIN
+        if parse_setting(sql).is_some() || session.deferred_begin().is_some() {
+            target = Target::Primary;
+        }
+        if let Some(name) = &hints.host {
+            return self.route_named(name).and_then(|backend| serving_named(backend, &session.login().database));
+        }
+        match hints.route {
+            Some(RouteHint::Primary) => target = Target::Primary,
+            Some(RouteHint::Replica) => target = Target::Replica,
+            None => {}
+        }
+
+        let backend = match (target, session.min_read_lsn(), self.read_your_writes_timeout) {
+            (Target::Replica, Some(lsn), Some(timeout)) => self.route_caught_up(&candidates, lsn, timeout).await?,
+            _ => self.route_among(&candidates, target)?,
+        };
+        if backend.is_primary() {
+            session.set_primary_epoch(epoch);
+        }
+        Ok(backend)
+    }
+
+    /// Terminates a session that used a primary which has since failed over,
+    /// with SQLSTATE 57P01: its transaction and settings lived on the old
+    /// primary and cannot continue. Returns the current failover epoch.
+    pub(crate) fn check_failover(&self, session: &mut Session) -> Result<u64, PostgresError> {
+        let epoch = self.failover.epoch();
+        if session.primary_epoch().map_or(false, |used| used != epoch) {
+            session.reset();
+            return Err(failover::failover_error());
+        }
+        Ok(epoch)
+    }
+
+    /// Hosts of the shard a statement belongs to. The shard comes from a
+    /// `shard_key` hint or the key column in the statement; statements without
+    /// one may only continue a transaction already bound to a shard; reads without
+    /// one can instead run on every shard, see `should_scatter`.
+    fn shard_candidates(
+        &self,
+        shards: &ShardMap,
+        session: &mut Session,
+        sql: &str,
+        params: &[PostgresValue],
+        hints: &QueryHints,
+        kind: StatementKind,
+    ) -> Result<Vec<Arc<Backend>>, PostgresError> {
+        let key = hints
+            .shard_key
+            .clone()
+            .or_else(|| extract_shard_key(sql, shards.key(), params));
+
+        let shard = match (key, session.shard()) {
+            (Some(key), current) => {
+                let shard = shards
+                    .shard_for(&key)
+                    .ok_or_else(|| sharding::shard_error(&format!("no shard owns {} = {}", shards.key(), key)))?;
+                if session.in_transaction() && current.map_or(false, |current| current != shard) {
+                    return Err(sharding::shard_error("a transaction cannot span several shards"));
+                }
+                shard
+            }
+            (None, Some(current)) if session.in_transaction() => current,
+            (None, _) => {
+                return Err(sharding::shard_error(&format!(
+                    "cannot determine the shard for this statement; filter on {} or add a /* shard_key=... */ hint",
+                    shards.key()
+                )))
+            }
+        };
+
+        if session.in_transaction() || kind == StatementKind::Begin {
+            session.set_shard(shard);
+        }
+        Ok(shards.shards()[shard].backends.clone())
+    }
+
+    /// Answers transaction control that cannot be routed yet, as sharded
+    /// transactions only pick their shard with their first keyed statement. A
+    /// BEGIN naming no shard is deferred until then, and a COMMIT or ROLLBACK
+    /// before it completes at once as nothing reached a server. Returns the
+    /// command tag to reply with, or None if `sql` must be routed.
+    pub(crate) fn answer_unrouted(&self, session: &mut Session, sql: &str) -> Option<&'static str> {
+        let shards = self.shards.as_ref()?;
+        if session.shard().is_some()
+            || split_statements(sql).len() != 1
+            || parse_hints(sql).shard_key.is_some()
+            || extract_shard_key(sql, shards.key(), &[]).is_some()
+        {
+            return None;
+        }
+        let tag = match classify(sql) {
+            StatementKind::Begin => {
+                // Like the server, a BEGIN inside a transaction changes nothing
+                if !session.in_transaction() {
+                    session.defer_begin(sql);
+                }
+                let start = sql.trim_start().to_lowercase().starts_with("start");
+                return Some(if start { "START TRANSACTION" } else { "BEGIN" });
+            }
+            StatementKind::Commit => "COMMIT",
+            StatementKind::Rollback => "ROLLBACK",
+            _ => return None,
+        };
+        session.statement_completed(sql, TransactionStatus::Idle);
+        Some(tag)
+    }
+
+    /// Checks that a statement run on the connection a session holds belongs
+    /// to the shard of that connection's host. Outside a transaction the
+    /// connection is only held for the session's settings, so a read of tables
+    /// without a shard key is refused rather than answered from one shard alone.
+    pub(crate) fn check_held_shard(
+        &self,
+        session: &Session,
+        sql: &str,
+        backend: &Arc<Backend>,
+    ) -> Result<(), PostgresError> {
+        let shards = match &self.shards {
+            Some(shards) => shards,
+            None => return Ok(()),
+        };
+        let key = match parse_hints(sql).shard_key.or_else(|| extract_shard_key(sql, shards.key(), &[])) {
+            Some(key) => key,
+            None if !session.in_transaction()
+                && shards.shards().len() > 1
+                && classify(sql) == StatementKind::Read
+                && !tables(sql).reads.is_empty() =>
+            {
+                return Err(sharding::shard_error(
+                    "a session with changed settings cannot read from every shard; \
+                     name the shard key or reset the settings",
+                ));
+            }
+            None => return Ok(()),
+        };
+        let shard = shards
+            .shard_for(&key)
+            .ok_or_else(|| sharding::shard_error(&format!("no shard owns {} = {}", shards.key(), key)))?;
+        if shards.shards()[shard].backends.iter().any(|member| Arc::ptr_eq(member, backend)) {
+            Ok(())
+        } else {
+            Err(sharding::shard_error(
+                "a transaction, or a session with changed settings, cannot span several shards",
+            ))
+        }
+    }
+
+    /// Whether a statement that failed with `error` on its `attempt`-th retry
+    /// (0 for the first run) may run again elsewhere: it must be a read outside
+    /// a transaction, not pinned to a host, with no rows sent to the client yet,
+    /// and within the `read_retries` budget.
+    pub fn can_retry(&self, session: &Session, sql: &str, error: &PostgresError, attempt: u32, rows_sent: bool) -> bool {
+        attempt < self.read_retries
+            && !rows_sent
+            && error.is_retryable()
+            && !session.in_transaction()
+            && classify(sql) == StatementKind::Read
+            && parse_hints(sql).host.is_none()
+    }
+
+    pub fn route_named(&self, name: &str) -> Result<Arc<Backend>, PostgresError> {
+        self.healthy_backends()
+            .into_iter()
+            .find(|backend| backend.matches_name(name) && !backend.is_draining())
+            .ok_or_else(|| PostgresError::Unavailable(format!("host {} is unknown, unhealthy or draining", name)))
+    }
+
+    /// The statement text to send to the backend: hint comments are removed when
+    /// `strip_query_hints` is set and passed through otherwise.
+    pub fn outgoing_sql(&self, sql: &str) -> String {
+        if self.strip_query_hints {
+            strip_hints(sql)
+        } else {
+            sql.to_string()
+        }
+    }
+
+    /// Healthy primaries and the healthy replicas within the configured lag.
+    fn split_backends(&self, candidates: &[Arc<Backend>]) -> (Vec<Arc<Backend>>, Vec<Arc<Backend>>) {
+        let (primaries, replicas): (Vec<_>, Vec<_>) =
+            self.healthy_among(candidates).into_iter().partition(|b| b.is_primary());
+        let replicas = replicas.into_iter().filter(|b| !self.is_lagging(b)).collect();
+        (primaries, replicas)
+    }
+
+    pub fn route_to(&self, target: Target) -> Result<Arc<Backend>, PostgresError> {
+        self.route_among(&accepting(&self.backends), target)
+    }
+
+    fn route_among(&self, candidates: &[Arc<Backend>], target: Target) -> Result<Arc<Backend>, PostgresError> {
+        let (primaries, replicas) = self.split_backends(candidates);
+
+        let picked = match target {
+            // Reads fall back to the primary when every replica is down or lagging
+            Target::Replica => self.balancer.pick(&replicas).or_else(|| self.balancer.pick(&primaries)),
+            Target::Primary => self.balancer.pick(&primaries),
+        };
+
+        picked.ok_or_else(|| match target {
+            Target::Primary => PostgresError::Unavailable("no healthy primary".into()),
+            Target::Replica => PostgresError::Unavailable("no healthy PostgreSQL host".into()),
+        })
+    }
+
+    /// Picks a replica that has replayed at least `lsn`, polling replicas for
+    /// their replay position until `timeout` passes; then falls back to the primary.
+    async fn route_caught_up(
+        &self,
+        candidates: &[Arc<Backend>],
+        lsn: u64,
+        timeout: Duration,
+    ) -> Result<Arc<Backend>, PostgresError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let (_, replicas) = self.split_backends(candidates);
+            let caught_up: Vec<_> = replicas.iter().filter(|b| self.replay_lsn(b) >= lsn).cloned().collect();
+            if let Some(backend) = self.balancer.pick(&caught_up) {
+                return Ok(backend);
+            }
+            if replicas.is_empty() || Instant::now() >= deadline {
+                return self.route_among(candidates, Target::Primary);
+            }
+
+            for replica in &replicas {
+                if let Err(e) = self.refresh_replay_lsn(replica, deadline).await {
+                    log::warn!("Failed to read replay position of {}: {}", replica.host.host, e);
+                }
+            }
+            if replicas.iter().all(|b| self.replay_lsn(b) < lsn) {
+                tokio::time::sleep(CATCH_UP_POLL_INTERVAL.min(deadline.saturating_duration_since(Instant::now()))).await;
+            }
+        }
+    }
+
+    /// Polls the replay position of a replica, giving up at `deadline` so a
+    /// slow or unreachable replica cannot hold the read past its catch-up timeout.
+    async fn refresh_replay_lsn(&self, backend: &Backend, deadline: Instant) -> Result<(), PostgresError> {
+        let timed_out = || {
+            PostgresError::Io(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!("replay position of {} not read before the catch-up deadline", backend.host.host),
+            ))
+        };
+        let mut client = match tokio::time::timeout_at(deadline.into(), backend.pool.get_client()).await {
+            Ok(client) => client?,
+            Err(_) => return Err(timed_out()),
+        };
+        let query = query_lsn(&mut client, "SELECT pg_last_wal_replay_lsn()");
+        let result = tokio::time::timeout_at(deadline.into(), query).await.unwrap_or_else(|_| Err(timed_out()));
+        match result {
+            Ok(lsn) => {
+                backend.pool.release_client(client).await;
+                backend.replay_lsn.fetch_max(lsn, Ordering::SeqCst);
+                Ok(())
+            }
+            Err(e) => {
+                // A query cut short leaves the connection mid-response
+                backend.pool.discard_client(client);
+                Err(e)
+            }
+        }
+    }
+
+    /// Best known replay position of a replica, from health checks or recent polls.
+    pub fn replay_lsn(&self, backend: &Backend) -> u64 {
+        let probed = self.health.health(&backend.host.host).and_then(|h| h.lsn).unwrap_or(0);
+        probed.max(backend.replay_lsn.load(Ordering::SeqCst))
+    }
+
+    /// After a statement routed with `route_query` completes on the primary, records
+    /// the commit LSN for the session if it committed a write. `client` must be the
+    /// primary connection the statement ran on.
+    pub async fn capture_commit_lsn(&self, session: &mut Session, client: &mut PostgresClient) -> Result<(), PostgresError> {
+        if !session.take_commit_pending() || self.read_your_writes_timeout.is_none() {
+            return Ok(());
+        }
+        let lsn = query_lsn(client, "SELECT pg_current_wal_lsn()").await?;
+        session.record_commit_lsn(lsn);
+        Ok(())
+    }
+
+    /// Whether a replica is further behind the primary than `max_replica_lag` allows.
+    /// Replicas whose lag has not been measured yet are considered current.
+    pub fn is_lagging(&self, backend: &Backend) -> bool {
+        let health = match self.health.health(&backend.host.host) {
+            Some(health) => health,
+            None => return false,
+        };
+        let behind_time = match (self.max_replica_lag, health.replay_delay) {
+            (Some(max), Some(delay)) => delay > max,
+            _ => false,
+        };
+        let behind_bytes = match (self.max_replica_lag_bytes, health.lag_bytes) {
+            (Some(max), Some(lag)) => lag > max,
+            _ => false,
+        };
+        behind_time || behind_bytes
+    }
+
+    /// Reports how long a request served by `host` took, for latency-aware balancing.
+    pub fn record_latency(&self, host: &str, latency: Duration) {
+        self.balancer.record_latency(host, latency);
+    }
+}
+
+/// The candidates that take new work, leaving out draining hosts.
+fn accepting(candidates: &[Arc<Backend>]) -> Vec<Arc<Backend>> {
+    candidates.iter().filter(|backend| !backend.is_draining()).cloned().collect()
+}
+
+/// The candidates that serve `database`. Fails with SQLSTATE 3D000, as the
+/// server would, when there were candidates but none of them serves it.
+fn serving(candidates: &[Arc<Backend>], database: &str) -> Result<Vec<Arc<Backend>>, PostgresError> {
+    let serving: Vec<_> = candidates.iter().filter(|backend| backend.serves(database)).cloned().collect();
+    if serving.is_empty() && !candidates.is_empty() {
+        return Err(unknown_database(database));
+    }
+    Ok(serving)
+}
+
+fn serving_named(backend: Arc<Backend>, database: &str) -> Result<Arc<Backend>, PostgresError> {
+    if backend.serves(database) {
+        Ok(backend)
+    } else {
+        Err(unknown_database(database))
+    }
+}
+
+fn unknown_database(database: &str) -> PostgresError {
+    PostgresError::Server {
+        code: "3D000".to_string(),
+        message: format!("database \"{}\" is not served by any host", database),
+    }
+}
+
+/// Runs `sql` on a connection of the backend's pool for `login`. Connections
+/// that failed below the server level are discarded rather than returned to the pool.
+pub(crate) async fn run_on(backend: &Backend, login: &Login, sql: &str) -> Result<QueryResult, PostgresError> {
+    let pool = backend.client_pool(login).await?;
+    let mut client = pool.get_client().await?;
+    let result = client.simple_query(sql).await;
+    match &result {
+        // Server errors leave the connection usable
+        Ok(_) | Err(PostgresError::Server { .. }) => pool.release_client(client).await,
+        Err(_) => pool.discard_client(client),
+    }
+    result
+}
+
+async fn query_lsn(client: &mut PostgresClient, sql: &str) -> Result<u64, PostgresError> {
+    let rows = client.query(sql).await?;
+    match rows.first().and_then(|row| row.first()) {
+        Some((_, PostgresValue::String(lsn))) => {
+            parse_lsn(lsn).ok_or_else(|| PostgresError::Protocol(format!("invalid LSN {}", lsn)))
+        }
+        _ => Err(PostgresError::Protocol(format!("unexpected result for {}", sql))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{config, FakeServer};
+
+    async fn sharded_router(first: &FakeServer, second: &FakeServer, replication_mode: bool) -> Router {
+        sharded_router_with(first, second, replication_mode, false).await
+    }
+
+    async fn sharded_router_with(
+        first: &FakeServer,
+        second: &FakeServer,
+        replication_mode: bool,
+        scatter_gather: bool,
+    ) -> Router {
+        Router::new(&config(serde_json::json!({
+            "postgresql_hosts": [
+                { "name": "a", "host": first.host, "role": "primary" },
+                { "name": "b", "host": second.host, "role": "primary" },
+            ],
+            "replication_mode": replication_mode,
+            "sharding": {
+                "key": "tenant_id",
+                "function": "range",
+                "shards": [
+                    { "name": "low", "hosts": ["a"], "range_start": 0, "range_end": 100 },
+                    { "name": "high", "hosts": ["b"], "range_start": 100, "range_end": 200 },
+                ],
+                "scatter_gather": scatter_gather,
+            },
+        })))
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn sharded_transactions_begin_with_their_first_keyed_statement() {
+        for replication_mode in [false, true] {
+            let (low, high) = (FakeServer::start().await, FakeServer::start().await);
+            let router = sharded_router(&low, &high, replication_mode).await;
+            let mut session = Session::new(Login::new("app", "app", None));
+
+            assert_eq!(router.simple_query(&mut session, "BEGIN").await.unwrap().command_tag, "BEGIN");
+            assert!(session.in_transaction());
+            assert!(low.statements().is_empty());
+            // Assigning a key moves no row to its shard; the filter decides
+            router.simple_query(&mut session, "UPDATE t SET tenant_id = 150 WHERE tenant_id = 5").await.unwrap();
+            assert_eq!(session.connection_host(), Some(low.host.as_str()));
+            assert_eq!(low.count("BEGIN"), 1);
+            match router.simple_query(&mut session, "SELECT * FROM t WHERE tenant_id = 150").await {
+                Err(PostgresError::Server { code, .. }) => assert_eq!(code, sharding::SHARD_UNKNOWN_SQLSTATE),
+                other => panic!("expected a shard error, got {:?}", other.map(|result| result.command_tag)),
+            }
+            router.simple_query(&mut session, "SELECT 1").await.unwrap();
+            assert_eq!(router.simple_query(&mut session, "COMMIT").await.unwrap().command_tag, "COMMIT");
+            assert!(!session.in_transaction());
+            assert!(session.connection_host().is_none());
+            assert_eq!(low.count("COMMIT"), 1);
+
+            // Transactions that never reached a shard need no server
+            router.simple_query(&mut session, "START TRANSACTION").await.unwrap();
+            assert_eq!(router.simple_query(&mut session, "ROLLBACK").await.unwrap().command_tag, "ROLLBACK");
+            assert!(!session.in_transaction());
+            router.simple_query(&mut session, "COMMIT").await.unwrap();
+            assert_eq!(low.statements().len(), 4);
+            assert!(high.statements().is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn sharded_transactions_that_time_out_fail_until_rolled_back() {
+        let (low, high) = (FakeServer::start().await, FakeServer::start().await);
+        let router = sharded_router(&low, &high, false).await;
+        let mut session = Session::new(Login::new("app", "app", None));
+
+        router.simple_query(&mut session, "BEGIN").await.unwrap();
+        let slow = "/* pgshield: timeout=50ms */ SELECT pg_sleep(5) FROM t WHERE tenant_id = 5";
+        assert!(router.simple_query(&mut session, slow).await.is_err());
+        assert_eq!(low.count("BEGIN"), 1);
+        assert!(session.in_transaction());
+        assert!(session.deferred_begin().is_none());
+
+        match router.simple_query(&mut session, "SELECT * FROM t WHERE tenant_id = 5").await {
+            Err(PostgresError::Server { code, .. }) => assert_eq!(code, "25P02"),
+            other => panic!("expected a failed transaction, got {:?}", other.map(|result| result.command_tag)),
+        }
+        assert_eq!(router.simple_query(&mut session, "ROLLBACK").await.unwrap().command_tag, "ROLLBACK");
+        assert!(!session.in_transaction());
+        assert_eq!(low.statements().len(), 2);
+        assert_eq!(router.backends()[0].in_use(), 0);
+    }
+
+    #[tokio::test]
+    async fn keyless_reads_run_on_every_shard() {
+        let (low, high) = (FakeServer::start().await, FakeServer::start().await);
+        let router = sharded_router_with(&low, &high, false, true).await;
+        let mut session = Session::new(Login::new("app", "app", None));
+
+        let sql = "SELECT connection FROM t ORDER BY connection LIMIT 5";
+        let result = router.simple_query(&mut session, sql).await.unwrap();
+        assert_eq!(result.command_tag, "SELECT 2");
+        assert_eq!(result.columns.len(), 1);
+        assert_eq!((low.count(sql), high.count(sql)), (1, 1));
+
+        // Reads with a key, and writes, still go to one shard
+        router.simple_query(&mut session, "SELECT * FROM t WHERE tenant_id = 150").await.unwrap();
+        assert_eq!(high.statements().len(), 2);
+        assert!(router.simple_query(&mut session, "DELETE FROM t").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn sessions_with_settings_do_not_read_one_shard_for_all() {
+        let (low, high) = (FakeServer::start().await, FakeServer::start().await);
+        let router = sharded_router_with(&low, &high, false, true).await;
+        let mut session = Session::new(Login::new("app", "app", None));
+
+        router.simple_query(&mut session, "/* shard_key=5 */ SET search_path TO app").await.unwrap();
+        assert_eq!(session.connection_host(), Some(low.host.as_str()));
+        match router.simple_query(&mut session, "SELECT * FROM t").await {
+            Err(PostgresError::Server { code, .. }) => assert_eq!(code, sharding::SHARD_UNKNOWN_SQLSTATE),
+            other => panic!("expected the read to be refused, got {:?}", other.map(|result| result.command_tag)),
+        }
+        assert_eq!((low.count("SELECT * FROM t"), high.count("SELECT * FROM t")), (0, 0));
+
+        // Reads of its own shard, and of no table, still run on the held connection
+        router.simple_query(&mut session, "SELECT * FROM t WHERE tenant_id = 5").await.unwrap();
+        router.simple_query(&mut session, "SELECT 1").await.unwrap();
+        assert_eq!(low.statements().len(), 3);
+        assert_eq!(session.connection_host(), Some(low.host.as_str()));
+    }
+
+    async fn lag_router(primary: &FakeServer, replicas: &[&FakeServer]) -> Router {
+        let mut hosts = vec![serde_json::json!({ "host": primary.host, "role": "primary" })];
+        hosts.extend(replicas.iter().map(|replica| serde_json::json!({ "host": replica.host, "role": "replica" })));
+        primary.set_role(false, 0x2000000, 0.0);
+        for replica in replicas {
+            replica.set_role(true, 0x2000000, 0.0);
+        }
+        Router::new(&config(serde_json::json!({
+            "postgresql_hosts": hosts,
+            "replication_mode": true,
+            "max_replica_lag": 5,
+            "max_replica_lag_bytes": 1000,
+        })))
+        .await
+        .unwrap()
+    }
+
+    async fn read_host(router: &Router) -> String {
+        router.health.check_all().await;
+        router.route_to(Target::Replica).unwrap().host.host.clone()
+    }
+
+    #[tokio::test]
+    async fn replicas_behind_in_time_are_skipped_until_they_catch_up() {
+        let (primary, replica) = (FakeServer::start().await, FakeServer::start().await);
+        let router = lag_router(&primary, &[&replica]).await;
+        assert_eq!(read_host(&router).await, replica.host);
+
+        replica.set_role(true, 0x2000000, 10.0);
+        assert_eq!(read_host(&router).await, primary.host);
+        assert!(router.is_lagging(&router.backends()[1]));
+
+        replica.set_role(true, 0x2000000, 1.0);
+        assert_eq!(read_host(&router).await, replica.host);
+        assert!(!router.is_lagging(&router.backends()[1]));
+    }
+
+    #[tokio::test]
+    async fn replicas_behind_in_bytes_are_skipped_until_they_catch_up() {
+        let (primary, replica) = (FakeServer::start().await, FakeServer::start().await);
+        let router = lag_router(&primary, &[&replica]).await;
+
+        replica.set_role(true, 0x2000000 - 5000, 0.0);
+        assert_eq!(read_host(&router).await, primary.host);
+        assert_eq!(router.health.health(&replica.host).unwrap().lag_bytes, Some(5000));
+
+        replica.set_role(true, 0x2000000 - 500, 0.0);
+        assert_eq!(read_host(&router).await, replica.host);
+    }
+
+    #[tokio::test]
+    async fn reads_fall_back_to_the_primary_when_every_replica_lags() {
+        let primary = FakeServer::start().await;
+        let (first, second) = (FakeServer::start().await, FakeServer::start().await);
+        let router = lag_router(&primary, &[&first, &second]).await;
+
+        first.set_role(true, 0x2000000, 30.0);
+        assert_eq!(read_host(&router).await, second.host);
+        second.set_role(true, 0, 0.0);
+        for _ in 0..3 {
+            assert_eq!(read_host(&router).await, primary.host);
+        }
+        let mut session = Session::new(Login::new("app", "app", None));
+        router.simple_query(&mut session, "SELECT * FROM t").await.unwrap();
+        assert_eq!(primary.count("SELECT * FROM t"), 1);
+    }
+
+    #[tokio::test]
+    async fn catch_up_polls_stop_at_the_deadline() {
+        let (primary, replica) = (FakeServer::start().await, FakeServer::start().await);
+        let router = Router::new(&config(serde_json::json!({
+            "postgresql_hosts": [
+                { "host": primary.host, "role": "primary" },
+                { "host": replica.host, "role": "replica" },
+            ],
+            "replication_mode": true,
+            "read_your_writes": true,
+            "read_your_writes_timeout_ms": 100,
+        })))
+        .await
+        .unwrap();
+        let mut session = Session::new(Login::new("app", "app", None));
+        router.simple_query(&mut session, "INSERT INTO t VALUES (1)").await.unwrap();
+        assert!(session.min_read_lsn().is_some());
+
+        // A replica that never answers must not hold the read past the timeout
+        replica.set_delay(Duration::from_secs(30));
+        let started = Instant::now();
+        let backend = router.route_query(&mut session, "SELECT * FROM t", &[]).await.unwrap();
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert_eq!(backend.host.host, primary.host);
+        assert_eq!(router.backends()[1].in_use(), 0);
+    }
+}
@@ -0,0 +1,299 @@
+use log;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::JoinHandle;
+
+use lib_config::{MirrorConfig, MirrorMode};
+use lib_pgsqlcli::{PostgresError, PostgresValue, QueryResult};
+use lib_pool::{Pool, PoolConfig};
+use lib_query::{classify, fingerprint, normalize, StatementKind};
+
+use crate::balancer::{LoadBalancer, RoundRobin};
+use crate::{run_on, Backend, Login, DEFAULT_DATABASE};
+
+const DEFAULT_QUEUE_SIZE: usize = 1024;
+const DEFAULT_MAX_CONNS: usize = 10;
+
+/// What a statement produced, reduced to what mirroring compares: the command
+/// tag and an order-insensitive checksum of the rows, or the SQLSTATE it failed with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Rows { command_tag: String, checksum: u64 },
+    Error { code: String },
+}
+
+impl Outcome {
+    /// None for connection-level failures, which say nothing about the results.
+    pub fn of(result: &Result<QueryResult, PostgresError>) -> Option<Self> {
+        match result {
+            Ok(result) => Some(Outcome::of_rows(result)),
+            Err(e) => Outcome::of_error(e),
+        }
+    }
+
+    pub fn of_rows(result: &QueryResult) -> Self {
+        Outcome::Rows {
+            command_tag: result.command_tag.clone(),
+            // Rows may legitimately come back in another order without ORDER BY
+            checksum: result.rows.iter().fold(0u64, |sum, row| {
+                let mut hasher = DefaultHasher::new();
+                for (column, value) in row {
+                    column.hash(&mut hasher);
+                    hash_value(value, &mut hasher);
+                }
+                sum.wrapping_add(hasher.finish())
+            }),
+        }
+    }
+
+    pub fn of_error(error: &PostgresError) -> Option<Self> {
+        match error {
+            PostgresError::Server { code, .. } => Some(Outcome::Error { code: code.clone() }),
+            _ => None,
+        }
+    }
+}
+
+fn hash_value(value: &PostgresValue, hasher: &mut DefaultHasher) {
+    match value {
+        PostgresValue::Null => 0u8.hash(hasher),
+        PostgresValue::Boolean(b) => b.hash(hasher),
+        PostgresValue::Int16(i) => i.hash(hasher),
+        PostgresValue::Int32(i) => i.hash(hasher),
+        PostgresValue::Int64(i) => i.hash(hasher),
+        PostgresValue::Float32(f) => f.to_bits().hash(hasher),
+        PostgresValue::Float64(f) => f.to_bits().hash(hasher),
+        PostgresValue::String(s) => s.hash(hasher),
+        PostgresValue::Bytes(b) => b.hash(hasher),
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MirrorStats {
+    /// Statements replayed on a shadow host.
+    pub mirrored: u64,
+    /// Statements not replayed: the queue was full, no shadow host was
+    /// available or the connection to it failed.
+    pub dropped: u64,
+    pub mismatches: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    mirrored: AtomicU64,
+    dropped: AtomicU64,
+    mismatches: AtomicU64,
+}
+
+struct MirrorJob {
+    login: Login,
+    sql: String,
+    primary: Option<Outcome>,
+}
+
+/// Replays production statements on a shadow host group in the background.
+///
+/// Statements are replayed one by one on pooled connections, outside of any
+/// transaction: BEGIN/COMMIT/ROLLBACK are not mirrored, so in `all` mode writes
+/// of transactions rolled back in production still apply on the shadow.
+pub struct Mirror {
+    mode: MirrorMode,
+    compare: bool,
+    max_conns: usize,
+    backends: Vec<Arc<Backend>>,
+    queue: mpsc::Sender<MirrorJob>,
+    pending: Mutex<Option<mpsc::Receiver<MirrorJob>>>,
+    counters: Arc<Counters>,
+}
+
+impl Mirror {
+    pub async fn new(config: &MirrorConfig, pool_config: &PoolConfig) -> Result<Self, PostgresError> {
+        let max_conns = config.max_conns.unwrap_or(DEFAULT_MAX_CONNS).max(1);
+        let pool_config = PoolConfig {
+            min_size: 0,
+            max_size: max_conns,
+            reserve_pool_size: 0,
+            ..pool_config.clone()
+        };
+
+        let mut backends = Vec::new();
+        for host in &config.hosts {
+            let pool = Pool::with_config(&host.connection_string(DEFAULT_DATABASE), pool_config.clone()).await?;
+            backends.push(Arc::new(Backend::new(host.clone(), pool)));
+        }
+
+        let (queue, pending) = mpsc::channel(config.queue_size.unwrap_or(DEFAULT_QUEUE_SIZE).max(1));
+        Ok(Mirror {
+            mode: config.mode.unwrap_or_default(),
+            compare: config.compare_results.unwrap_or(false),
+            max_conns,
+            backends,
+            queue,
+            pending: Mutex::new(Some(pending)),
+            counters: Arc::new(Counters::default()),
+        })
+    }
+
+    /// Spawns the task replaying queued statements on the shadow hosts.
+    pub fn start(&self) -> JoinHandle<()> {
+        let mut pending = self.pending.lock().unwrap().take().expect("mirroring is already started");
+        let backends = self.backends.clone();
+        let counters = Arc::clone(&self.counters);
+        // Writes are replayed one at a time so they reach the shadow in production order
+        let permits = Arc::new(Semaphore::new(match self.mode {
+            MirrorMode::All => 1,
+            MirrorMode::ReadOnly => self.max_conns,
+        }));
+
+        tokio::spawn(async move {
+            let balancer = RoundRobin::default();
+            while let Some(job) = pending.recv().await {
+                let permit = match Arc::clone(&permits).acquire_owned().await {
+                    Ok(permit) => permit,
+                    Err(_) => break,
+                };
+                let available: Vec<_> = backends.iter().filter(|b| b.pool.is_available()).cloned().collect();
+                let backend = match balancer.pick(&available) {
+                    Some(backend) => backend,
+                    None => {
+                        log::debug!("No shadow host available, skipping mirrored statement");
+                        counters.dropped.fetch_add(1, Ordering::Relaxed);
+                        continue;
+                    }
+                };
+                let counters = Arc::clone(&counters);
+                tokio::spawn(async move {
+                    replay(&backend, job, &counters).await;
+                    drop(permit);
+                });
+            }
+        })
+    }
+
+    /// Whether shadow results are compared with production ones, so `submit`
+    /// needs the production outcome.
+    pub fn compares_results(&self) -> bool {
+        self.compare
+    }
+
+    /// Queues a copy of `sql`, run by a client logged in as `login`, for the
+    /// shadow hosts. `primary` is the outcome of the statement in production,
+    /// compared with the shadow's when `compare_results` is set. Never waits:
+    /// statements are dropped while the queue is full.
+    pub fn submit(&self, login: &Login, sql: &str, primary: Option<Outcome>) {
+        let kind = classify(sql);
+        let mirrored = match self.mode {
+            MirrorMode::ReadOnly => kind == StatementKind::Read,
+            MirrorMode::All => !matches!(kind, StatementKind::Begin | StatementKind::Commit | StatementKind::Rollback),
+        };
+        if !mirrored {
+            return;
+        }
+
+        let job = MirrorJob {
+            login: login.clone(),
+            sql: sql.to_string(),
+            primary: if self.compare { primary } else { None },
+        };
+        if self.queue.try_send(job).is_err() {
+            self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn stats(&self) -> MirrorStats {
+        MirrorStats {
+            mirrored: self.counters.mirrored.load(Ordering::Relaxed),
+            dropped: self.counters.dropped.load(Ordering::Relaxed),
+            mismatches: self.counters.mismatches.load(Ordering::Relaxed),
+        }
+    }
+}
+
+async fn replay(backend: &Backend, job: MirrorJob, counters: &Counters) {
+    let result = run_on(backend, &job.login, &job.sql).await;
+
+    let shadow = Outcome::of(&result);
+    match (&result, &shadow) {
+        (Err(e), None) => {
+            log::debug!("Mirrored statement on {} failed: {}", backend.host.host, e);
+            counters.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        _ => {
+            counters.mirrored.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    if let (Some(primary), Some(shadow)) = (job.primary, shadow) {
+        if primary != shadow {
+            counters.mismatches.fetch_add(1, Ordering::Relaxed);
+            log::warn!(
+                "Shadow result mismatch on {} for query {:016x} ({}): primary {:?}, shadow {:?}",
+                backend.host.host,
+                fingerprint(&job.sql),
+                normalize(&job.sql),
+                primary,
+                shadow
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{config, FakeServer};
+    use crate::{Router, Session};
+    use std::time::Duration;
+
+    async fn mirrored_router(primary: &FakeServer, shadow: &str) -> Router {
+        let router = Router::new(&config(serde_json::json!({
+            "postgresql_hosts": [{ "host": primary.host }],
+            "mirror": { "hosts": [{ "host": shadow }], "mode": "read_only", "compare_results": true },
+        })))
+        .await
+        .unwrap();
+        router.start_mirroring();
+        router
+    }
+
+    /// Waits for the mirror to account for `count` statements.
+    async fn settled(mirror: &Mirror, count: u64) -> MirrorStats {
+        for _ in 0..200 {
+            let stats = mirror.stats();
+            if stats.mirrored + stats.dropped >= count {
+                return stats;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("mirror did not settle: {:?}", mirror.stats());
+    }
+
+    #[tokio::test]
+    async fn statements_run_through_the_router_are_mirrored() {
+        let (primary, shadow) = (FakeServer::start().await, FakeServer::start().await);
+        let router = mirrored_router(&primary, &shadow.host).await;
+        let mut session = Session::new(Login::new("app", "app", None));
+
+        router.simple_query(&mut session, "INSERT INTO t VALUES (1)").await.unwrap();
+        router.simple_query(&mut session, "/* pgshield: nocache */ SELECT 1").await.unwrap();
+        let stats = settled(router.mirror().unwrap(), 1).await;
+        assert_eq!((stats.mirrored, stats.dropped, stats.mismatches), (1, 0, 0));
+        // Only reads are mirrored in read_only mode
+        assert_eq!(shadow.statements().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn statements_that_never_reached_a_shadow_are_not_counted_as_mirrored() {
+        let primary = FakeServer::start().await;
+        // Nothing listens on a port just released
+        let closed = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap().to_string();
+        let router = mirrored_router(&primary, &closed).await;
+        let mut session = Session::new(Login::new("app", "app", None));
+
+        router.simple_query(&mut session, "SELECT 1").await.unwrap();
+        let stats = settled(router.mirror().unwrap(), 1).await;
+        assert_eq!((stats.mirrored, stats.dropped), (0, 1));
+    }
+}
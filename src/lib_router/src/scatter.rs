@@ -0,0 +1,70 @@
+use log;
+use std::sync::Arc;
+
+use lib_pgsqlcli::{PostgresError, PostgresValue, QueryResult};
+use lib_query::hints::parse_hints;
+use lib_query::{classify, extract_shard_key, plan_scatter, StatementKind};
+
+use crate::sharding::shard_error;
+use crate::{accepting, run_on, serving, Backend, Router, Session, Target};
+
+impl Router {
+    /// Whether `sql` should run on every shard: a read outside a transaction that
+    /// names no shard key, with `scatter_gather` enabled. Scattered statements
+    /// use the simple query protocol, so statements with parameters never qualify.
+    pub fn should_scatter(&self, session: &Session, sql: &str, params: &[PostgresValue]) -> bool {
+        let shards = match &self.shards {
+            Some(shards) if shards.scatter_gather() => shards,
+            _ => return false,
+        };
+        params.is_empty()
+            && !session.in_transaction()
+            && classify(sql) == StatementKind::Read
+            && parse_hints(sql).shard_key.is_none()
+            && extract_shard_key(sql, shards.key(), params).is_none()
+    }
+
+    /// Runs a read on one host of every shard concurrently and merges the
+    /// results as described by the statement's ORDER BY, LIMIT/OFFSET and aggregates.
+    pub async fn scatter_gather(&self, session: &Session, sql: &str) -> Result<QueryResult, PostgresError> {
+        let shards = self
+            .shards
+            .as_ref()
+            .ok_or_else(|| shard_error("sharding is not configured"))?;
+        let plan = plan_scatter(&self.outgoing_sql(sql)).map_err(|e| shard_error(&e))?;
+
+        let mut tasks = Vec::with_capacity(shards.shards().len());
+        for shard in shards.shards() {
+            let backend = self.scatter_target(session, &shard.backends).await?;
+            let shard_sql = plan.shard_sql.clone();
+            let login = session.login().clone();
+            tasks.push(tokio::spawn(async move {
+                let result = run_on(&backend, &login, &shard_sql).await;
+                if let Err(e) = &result {
+                    log::warn!("Shard query on {} failed: {}", backend.host.host, e);
+                }
+                result
+            }));
+        }
+
+        let mut results = Vec::with_capacity(tasks.len());
+        for task in tasks {
+            let result = task
+                .await
+                .map_err(|e| PostgresError::Unavailable(format!("shard query did not complete: {}", e)))??;
+            results.push(result);
+        }
+        plan.merge(results).map_err(|e| shard_error(&e))
+    }
+
+    async fn scatter_target(&self, session: &Session, candidates: &[Arc<Backend>]) -> Result<Arc<Backend>, PostgresError> {
+        let candidates = &serving(&accepting(candidates), &session.login().database)?;
+        if !self.replication_mode {
+            return self.pick_healthy(candidates);
+        }
+        match (session.min_read_lsn(), self.read_your_writes_timeout) {
+            (Some(lsn), Some(timeout)) => self.route_caught_up(candidates, lsn, timeout).await,
+            _ => self.route_among(candidates, Target::Replica),
+        }
+    }
+}
@@ -0,0 +1,428 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use lib_cache::CacheScope;
+use lib_query::lexer::tokenize;
+use lib_pgsqlcli::TransactionStatus;
+use lib_query::lexer::split_statements;
+use lib_query::{classify, parse_setting, tables, SettingChange, StatementKind};
+
+use crate::statement::ServerConnection;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Target {
+    Primary,
+    Replica,
+}
+
+/// The database and role a client logged in with. Its statements run on
+/// connections opened with these credentials, never with the admin ones.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct Login {
+    pub database: String,
+    pub user: String,
+    /// Password pgShield presents when the server asks `user` for one. The
+    /// client must already have been authenticated as `user`.
+    pub password: Option<String>,
+}
+
+impl Login {
+    pub fn new(database: &str, user: &str, password: Option<&str>) -> Self {
+        Login {
+            database: database.to_string(),
+            user: user.to_string(),
+            password: password.map(str::to_string),
+        }
+    }
+}
+
+impl fmt::Debug for Login {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Login")
+            .field("database", &self.database)
+            .field("user", &self.user)
+            .field("password", &self.password.as_ref().map(|_| "<redacted>"))
+            .finish()
+    }
+}
+
+/// Per-client routing state that keeps a transaction on the primary once it
+/// has written, so it never reads its own changes from a replica.
+#[derive(Debug)]
+pub struct Session {
+    login: Login,
+    /// As the server last reported it.
+    transaction: TransactionStatus,
+    pinned_to_primary: bool,
+    primary_epoch: Option<u64>,
+    commit_pending: bool,
+    min_read_lsn: Option<u64>,
+    shard: Option<usize>,
+    settings: BTreeMap<String, String>,
+    /// Setting changes made in the open transaction, applied when it commits.
+    pending_settings: Vec<SettingChange>,
+    settings_unknown: bool,
+    /// Tables written by the open transaction.
+    pending_writes: Vec<String>,
+    /// Server connection kept while a transaction or settings live on it.
+    connection: Option<ServerConnection>,
+    /// BEGIN held back until a statement of the transaction names its shard.
+    deferred_begin: Option<String>,
+    /// The open transaction went away with its server connection.
+    transaction_lost: bool,
+}
+
+impl Session {
+    pub fn new(login: Login) -> Self {
+        Session {
+            login,
+            transaction: TransactionStatus::Idle,
+            pinned_to_primary: false,
+            primary_epoch: None,
+            commit_pending: false,
+            min_read_lsn: None,
+            shard: None,
+            settings: BTreeMap::new(),
+            pending_settings: Vec::new(),
+            settings_unknown: false,
+            pending_writes: Vec::new(),
+            connection: None,
+            deferred_begin: None,
+            transaction_lost: false,
+        }
+    }
+
+    pub fn login(&self) -> &Login {
+        &self.login
+    }
+
+    /// Host of the server connection the session keeps between statements, if any.
+    pub fn connection_host(&self) -> Option<&str> {
+        self.connection.as_ref().map(|connection| connection.backend.host.host.as_str())
+    }
+
+    pub(crate) fn take_connection(&mut self) -> Option<ServerConnection> {
+        self.connection.take()
+    }
+
+    pub(crate) fn hold_connection(&mut self, connection: ServerConnection) {
+        self.connection = Some(connection);
+    }
+
+    /// Whether the session changed settings, which live on its server connection.
+    pub fn has_settings(&self) -> bool {
+        !self.settings.is_empty() || self.settings_unknown
+    }
+
+    pub fn in_transaction(&self) -> bool {
+        self.transaction != TransactionStatus::Idle
+    }
+
+    pub fn is_pinned(&self) -> bool {
+        self.pinned_to_primary
+    }
+
+    /// Failover epoch of the primary this session last used, if any.
+    pub fn primary_epoch(&self) -> Option<u64> {
+        self.primary_epoch
+    }
+
+    pub fn set_primary_epoch(&mut self, epoch: u64) {
+        self.primary_epoch = Some(epoch);
+    }
+
+    /// True once a write has committed and its LSN has not been captured yet.
+    /// Clears the flag.
+    pub fn take_commit_pending(&mut self) -> bool {
+        std::mem::take(&mut self.commit_pending)
+    }
+
+    /// Remembers the WAL position of this session's latest commit; replicas
+    /// must have replayed at least this far before serving its reads.
+    pub fn record_commit_lsn(&mut self, lsn: u64) {
+        self.min_read_lsn = Some(self.min_read_lsn.map_or(lsn, |current| current.max(lsn)));
+    }
+
+    pub fn min_read_lsn(&self) -> Option<u64> {
+        self.min_read_lsn
+    }
+
+    /// Shard the current transaction runs on, when sharding is enabled.
+    pub fn shard(&self) -> Option<usize> {
+        self.shard
+    }
+
+    pub fn set_shard(&mut self, shard: usize) {
+        self.shard = Some(shard);
+    }
+
+    /// Opens a transaction whose `begin` statement is only sent to the server
+    /// with its first statement, once the shard it runs on is known.
+    pub(crate) fn defer_begin(&mut self, begin: &str) {
+        self.statement_completed(begin, TransactionStatus::InTransaction);
+        self.deferred_begin = Some(begin.to_string());
+    }
+
+    pub(crate) fn deferred_begin(&self) -> Option<&str> {
+        self.deferred_begin.as_deref()
+    }
+
+    /// Records that the deferred BEGIN reached the server.
+    pub(crate) fn begin_sent(&mut self) {
+        self.deferred_begin = None;
+    }
+
+    /// Forgets the state that lived on the session's server connection, which
+    /// was closed. An open transaction is kept as failed, so the client's next
+    /// statements do not silently run outside of it before it rolls back.
+    pub(crate) fn connection_lost(&mut self) {
+        let in_transaction = self.in_transaction();
+        self.reset();
+        if in_transaction {
+            self.transaction = TransactionStatus::Failed;
+            self.transaction_lost = true;
+        }
+    }
+
+    /// Whether the open transaction was lost with its connection, see `connection_lost`.
+    pub(crate) fn transaction_lost(&self) -> bool {
+        self.transaction_lost
+    }
+
+    /// Updates session state once `sql` has succeeded, leaving the server in
+    /// transaction state `status`: follows the settings it changed and returns
+    /// the tables whose committed contents it changed, so their query cache
+    /// entries can be invalidated. Changes made inside a transaction take
+    /// effect when it commits.
+    pub fn statement_completed(&mut self, sql: &str, status: TransactionStatus) -> Vec<String> {
+        let mut committed = Vec::new();
+        for statement in split_statements(sql) {
+            committed.extend(self.completed(statement));
+        }
+        self.follow_transaction(status);
+        committed
+    }
+
+    /// Updates session state after `sql` failed, leaving the server in
+    /// transaction state `status`.
+    pub fn statement_failed(&mut self, status: TransactionStatus) {
+        self.follow_transaction(status);
+    }
+
+    /// Follows the transaction state the server reported. Once it is idle,
+    /// whatever the transaction left pending was rolled back.
+    fn follow_transaction(&mut self, status: TransactionStatus) {
+        self.transaction = status;
+        if status == TransactionStatus::Idle {
+            self.pending_settings.clear();
+            self.pending_writes.clear();
+            self.pinned_to_primary = false;
+            self.shard = None;
+            self.deferred_begin = None;
+            self.transaction_lost = false;
+        }
+    }
+
+    fn completed(&mut self, sql: &str) -> Vec<String> {
+        let mut committed = Vec::new();
+        match classify(sql) {
+            StatementKind::Begin => self.transaction = TransactionStatus::InTransaction,
+            // Committing a failed transaction rolls it back
+            StatementKind::Commit if self.transaction == TransactionStatus::Failed => {
+                self.pending_settings.clear();
+                self.pending_writes.clear();
+                self.transaction = TransactionStatus::Idle;
+            }
+            StatementKind::Commit => {
+                for change in std::mem::take(&mut self.pending_settings) {
+                    self.apply_setting(change);
+                }
+                committed = std::mem::take(&mut self.pending_writes);
+                self.transaction = TransactionStatus::Idle;
+            }
+            StatementKind::Rollback => {
+                self.pending_settings.clear();
+                self.pending_writes.clear();
+                self.transaction = TransactionStatus::Idle;
+            }
+            StatementKind::Write => {
+                let written = tables(sql).writes;
+                if self.in_transaction() {
+                    self.pending_writes.extend(written);
+                } else {
+                    committed = written;
+                }
+            }
+            _ => {}
+        }
+
+        // Rolling back to a savepoint may undo only some of the pending changes
+        let tokens = tokenize(sql);
+        if tokens.first().map_or(false, |t| t.is_word("rollback") || t.is_word("abort"))
+            && tokens.iter().any(|t| t.is_word("to"))
+            && !self.pending_settings.is_empty()
+        {
+            self.pending_settings.push(SettingChange::Unknown);
+        }
+
+        if let Some(change) = parse_setting(sql) {
+            if self.in_transaction() {
+                self.pending_settings.push(change);
+            } else {
+                self.apply_setting(change);
+            }
+        }
+        committed
+    }
+
+    fn apply_setting(&mut self, change: SettingChange) {
+        match change {
+            SettingChange::Set { name, value } => {
+                self.settings.insert(name, value);
+            }
+            SettingChange::Reset(name) => {
+                self.settings.remove(&name);
+            }
+            SettingChange::ResetAll => {
+                self.settings.retain(|name, _| name == "session_authorization");
+                self.settings_unknown = false;
+            }
+            SettingChange::DiscardAll => {
+                self.settings.clear();
+                self.settings_unknown = false;
+            }
+            SettingChange::Unknown => self.settings_unknown = true,
+        }
+    }
+
+    /// Scope for query cache entries of this session, or None when its results
+    /// must not be cached: inside a transaction, or after setting changes
+    /// pgShield could not follow.
+    pub fn cache_scope(&self) -> Option<CacheScope> {
+        if self.in_transaction() || self.settings_unknown {
+            return None;
+        }
+        let role = self
+            .settings
+            .get("role")
+            .or_else(|| self.settings.get("session_authorization"))
+            .map_or(self.login.user.as_str(), String::as_str);
+        Some(CacheScope {
+            database: self.login.database.clone(),
+            role: role.to_string(),
+            settings: self
+                .settings
+                .iter()
+                .filter(|(name, _)| name.as_str() != "role" && name.as_str() != "session_authorization")
+                .map(|(name, value)| (name.clone(), value.clone()))
+                .collect(),
+        })
+    }
+
+    /// Forgets all routing state, as after reconnecting. The login is kept.
+    pub fn reset(&mut self) {
+        let login = self.login.clone();
+        *self = Session::new(login);
+    }
+
+    /// Advances the session state for a statement and returns where it must
+    /// run. Whether a transaction is open is only learnt from the server once
+    /// the statement completed, see `statement_completed`.
+    pub fn target_for(&mut self, kind: StatementKind) -> Target {
+        match kind {
+            StatementKind::Begin => {
+                self.pinned_to_primary = false;
+                Target::Primary
+            }
+            StatementKind::Commit => {
+                self.commit_pending |= self.pinned_to_primary;
+                Target::Primary
+            }
+            StatementKind::Rollback => Target::Primary,
+            StatementKind::Write => {
+                if self.in_transaction() {
+                    self.pinned_to_primary = true;
+                } else {
+                    // Autocommit: the write is committed as soon as it completes
+                    self.commit_pending = true;
+                }
+                Target::Primary
+            }
+            StatementKind::Read if self.pinned_to_primary => Target::Primary,
+            StatementKind::Read => Target::Replica,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session() -> Session {
+        Session::new(Login::new("app", "alice", None))
+    }
+
+    fn complete(session: &mut Session, sql: &str, status: TransactionStatus) -> Vec<String> {
+        session.statement_completed(sql, status)
+    }
+
+    #[test]
+    fn cache_scope_follows_role_and_settings() {
+        let mut session = session();
+        let scope = session.cache_scope().unwrap();
+        assert_eq!((scope.database.as_str(), scope.role.as_str()), ("app", "alice"));
+        assert!(scope.settings.is_empty());
+
+        complete(&mut session, "SET search_path TO tenant_a", TransactionStatus::Idle);
+        complete(&mut session, "SET ROLE reporting", TransactionStatus::Idle);
+        let scope = session.cache_scope().unwrap();
+        assert_eq!(scope.role, "reporting");
+        assert_eq!(scope.settings, vec![("search_path".to_string(), "tenant_a".to_string())]);
+
+        complete(&mut session, "RESET ROLE", TransactionStatus::Idle);
+        assert_eq!(session.cache_scope().unwrap().role, "alice");
+        complete(&mut session, "SELECT set_config('search_path', 'x', false)", TransactionStatus::Idle);
+        assert!(session.cache_scope().is_none());
+        complete(&mut session, "DISCARD ALL", TransactionStatus::Idle);
+        assert_eq!(session.cache_scope(), self::session().cache_scope());
+    }
+
+    #[test]
+    fn settings_in_a_transaction_apply_when_it_commits() {
+        let mut session = session();
+        complete(&mut session, "BEGIN", TransactionStatus::InTransaction);
+        complete(&mut session, "SET search_path TO tenant_a", TransactionStatus::InTransaction);
+        assert!(session.cache_scope().is_none());
+        assert!(!session.has_settings());
+        complete(&mut session, "COMMIT", TransactionStatus::Idle);
+        assert!(session.has_settings());
+
+        complete(&mut session, "BEGIN", TransactionStatus::InTransaction);
+        complete(&mut session, "SET search_path TO tenant_b", TransactionStatus::InTransaction);
+        complete(&mut session, "ROLLBACK", TransactionStatus::Idle);
+        assert_eq!(session.cache_scope().unwrap().settings[0].1, "tenant_a");
+
+        // A failed transaction is rolled back by COMMIT
+        complete(&mut session, "BEGIN", TransactionStatus::InTransaction);
+        complete(&mut session, "SET search_path TO tenant_c", TransactionStatus::InTransaction);
+        session.statement_failed(TransactionStatus::Failed);
+        complete(&mut session, "COMMIT", TransactionStatus::Idle);
+        assert_eq!(session.cache_scope().unwrap().settings[0].1, "tenant_a");
+    }
+
+    #[test]
+    fn writes_commit_with_their_transaction() {
+        let mut session = session();
+        assert_eq!(complete(&mut session, "INSERT INTO orders VALUES (1)", TransactionStatus::Idle), vec!["orders"]);
+        complete(&mut session, "BEGIN", TransactionStatus::InTransaction);
+        assert!(complete(&mut session, "UPDATE items SET qty = 0", TransactionStatus::InTransaction).is_empty());
+        assert_eq!(complete(&mut session, "COMMIT", TransactionStatus::Idle), vec!["items"]);
+        assert_eq!(
+            complete(&mut session, "BEGIN; DELETE FROM orders; COMMIT", TransactionStatus::Idle),
+            vec!["orders"]
+        );
+        complete(&mut session, "BEGIN", TransactionStatus::InTransaction);
+        complete(&mut session, "DELETE FROM orders", TransactionStatus::InTransaction);
+        assert!(complete(&mut session, "ROLLBACK", TransactionStatus::Idle).is_empty());
+    }
+}
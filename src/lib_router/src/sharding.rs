@@ -0,0 +1,97 @@
+use std::sync::Arc;
+
+use lib_config::{ShardingConfig, ShardingFunction};
+use lib_pgsqlcli::PostgresError;
+
+use crate::Backend;
+
+/// SQLSTATE feature_not_supported, returned for statements pgShield cannot pin to a shard.
+pub const SHARD_UNKNOWN_SQLSTATE: &str = "0A000";
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+pub struct Shard {
+    pub name: String,
+    pub backends: Vec<Arc<Backend>>,
+    range: (Option<i64>, Option<i64>),
+}
+
+/// Maps shard key values to the host group that owns them.
+pub struct ShardMap {
+    key: String,
+    function: ShardingFunction,
+    shards: Vec<Shard>,
+    scatter_gather: bool,
+}
+
+/// FNV-1a, chosen because it is stable across processes and releases so every
+/// pgShield instance sends the same key to the same shard.
+fn shard_hash(key: &str) -> u64 {
+    key.bytes()
+        .fold(FNV_OFFSET, |hash, byte| (hash ^ byte as u64).wrapping_mul(FNV_PRIME))
+}
+
+impl ShardMap {
+    pub fn new(config: &ShardingConfig, backends: &[Arc<Backend>]) -> Result<Self, PostgresError> {
+        if config.shards.is_empty() {
+            return Err(PostgresError::Parse("sharding requires at least one shard".into()));
+        }
+
+        let mut shards = Vec::with_capacity(config.shards.len());
+        for shard in &config.shards {
+            let mut members = Vec::new();
+            for name in &shard.hosts {
+                let backend = backends
+                    .iter()
+                    .find(|backend| backend.matches_name(name))
+                    .ok_or_else(|| PostgresError::Parse(format!("shard {} references unknown host {}", shard.name, name)))?;
+                members.push(Arc::clone(backend));
+            }
+            shards.push(Shard {
+                name: shard.name.clone(),
+                backends: members,
+                range: (shard.range_start, shard.range_end),
+            });
+        }
+
+        Ok(ShardMap {
+            key: config.key.to_lowercase(),
+            function: config.function,
+            shards,
+            scatter_gather: config.scatter_gather.unwrap_or(false),
+        })
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub fn shards(&self) -> &[Shard] {
+        &self.shards
+    }
+
+    pub fn scatter_gather(&self) -> bool {
+        self.scatter_gather
+    }
+
+    /// Index of the shard owning `key`, or None if no range covers it.
+    pub fn shard_for(&self, key: &str) -> Option<usize> {
+        match self.function {
+            ShardingFunction::Hash => Some((shard_hash(key) % self.shards.len() as u64) as usize),
+            ShardingFunction::Range => {
+                let key: i64 = key.parse().ok()?;
+                self.shards.iter().position(|shard| {
+                    shard.range.0.map_or(true, |start| key >= start) && shard.range.1.map_or(true, |end| key < end)
+                })
+            }
+        }
+    }
+}
+
+pub fn shard_error(message: &str) -> PostgresError {
+    PostgresError::Server {
+        code: SHARD_UNKNOWN_SQLSTATE.to_string(),
+        message: message.to_string(),
+    }
+}
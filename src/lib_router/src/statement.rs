@@ -0,0 +1,428 @@
+use bytes::BytesMut;
+use log;
+use std::fmt;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use lib_pgsqlcli::{PostgresClient, PostgresError, QueryResult, TransactionStatus};
+use lib_pool::Pool;
+use lib_query::lexer::tokenize;
+use lib_query::{classify, StatementKind};
+use lib_query::hints::parse_hints;
+
+use crate::admin::is_admin_command;
+use crate::mirror::Outcome;
+use crate::{Backend, Login, Router, Session};
+
+/// SQLSTATE query_canceled, which the server reports for `statement_timeout` too.
+pub const QUERY_CANCELED_SQLSTATE: &str = "57014";
+/// SQLSTATE in_failed_sql_transaction.
+pub const FAILED_TRANSACTION_SQLSTATE: &str = "25P02";
+
+fn timeout_error(timeout: Duration) -> PostgresError {
+    PostgresError::Server {
+        code: QUERY_CANCELED_SQLSTATE.to_string(),
+        message: format!("canceling statement after its {}ms timeout hint", timeout.as_millis()),
+    }
+}
+
+/// Answers a statement of a transaction lost with its connection: like the
+/// server in a failed transaction, only ending it is accepted.
+fn end_lost_transaction(session: &mut Session, sql: &str) -> Result<Vec<(u8, BytesMut)>, PostgresError> {
+    let savepoint = tokenize(sql).iter().any(|token| token.is_word("to"));
+    match classify(sql) {
+        StatementKind::Commit | StatementKind::Rollback if !savepoint => {
+            session.statement_completed(sql, TransactionStatus::Idle);
+            Ok(vec![(b'C', BytesMut::from("ROLLBACK\0"))])
+        }
+        _ => Err(PostgresError::Server {
+            code: FAILED_TRANSACTION_SQLSTATE.to_string(),
+            message: "current transaction is aborted, commands ignored until end of transaction block".to_string(),
+        }),
+    }
+}
+
+/// Awaits `future` until `deadline` at the latest.
+async fn until<T>(deadline: Option<Instant>, future: impl Future<Output = T>) -> Option<T> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline.into(), future).await.ok(),
+        None => Some(future.await),
+    }
+}
+
+/// A server connection checked out for a session. The session keeps it
+/// between statements while the server holds state for it, an open
+/// transaction or session settings; dropping it closes the connection, as
+/// that state must never reach another session.
+pub(crate) struct ServerConnection {
+    pub(crate) backend: Arc<Backend>,
+    pool: Arc<Pool>,
+    client: Option<PostgresClient>,
+}
+
+impl ServerConnection {
+    async fn check_out(backend: &Arc<Backend>, login: &Login) -> Result<Self, PostgresError> {
+        let pool = backend.client_pool(login).await?;
+        let client = pool.get_client().await?;
+        Ok(ServerConnection {
+            backend: Arc::clone(backend),
+            pool,
+            client: Some(client),
+        })
+    }
+
+    fn client(&mut self) -> &mut PostgresClient {
+        self.client.as_mut().expect("connection already given back")
+    }
+
+    /// Gives the connection back to its pool. Only for connections the server
+    /// reports idle.
+    async fn release(mut self) {
+        if let Some(client) = self.client.take() {
+            self.pool.release_client(client).await;
+        }
+    }
+}
+
+impl Drop for ServerConnection {
+    fn drop(&mut self) {
+        if let Some(client) = self.client.take() {
+            self.pool.discard_client(client);
+        }
+    }
+}
+
+impl fmt::Debug for ServerConnection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ServerConnection").field("host", &self.backend.host.host).finish()
+    }
+}
+
+impl Router {
+    /// Routes and runs `sql` with the simple query protocol, retrying reads on
+    /// another host as `can_retry` allows. The result is buffered, so no rows
+    /// reach the client before the statement has completed.
+    pub async fn simple_query(&self, session: &mut Session, sql: &str) -> Result<QueryResult, PostgresError> {
+        let messages = self.execute(session, sql).await?;
+        QueryResult::from_messages(messages.iter().map(|(message_type, body)| (*message_type, &body[..])))
+    }
+
+    /// Runs `sql` for the session and returns the server's response messages.
+    ///
+    /// Statements run on a connection from the pool of the session's login,
+    /// which goes back to the pool once the server reports it idle. A
+    /// connection left inside a transaction, or on which the session changed
+    /// settings, stays with the session and runs all of its statements until
+    /// the transaction ends and the settings are reset.
+    ///
+    /// Reads that name no shard key run on every shard when `should_scatter`
+    /// allows it. A session holding a connection for its settings cannot
+    /// scatter, so its reads of tables need a shard key, see `check_held_shard`.
+    ///
+    /// A `timeout=` hint bounds the wait for a connection and the statement,
+    /// retries included. A statement that overruns it fails with SQLSTATE 57014
+    /// and its connection is closed. A transaction that was open on it fails:
+    /// its further statements are refused with SQLSTATE 25P02 until the client
+    /// rolls it back.
+    ///
+    /// With mirroring configured, the statement is then queued for the shadow
+    /// hosts. `PGSHIELD` admin commands are answered by pgShield itself.
+    pub(crate) async fn execute(&self, session: &mut Session, sql: &str) -> Result<Vec<(u8, BytesMut)>, PostgresError> {
+        if is_admin_command(sql) {
+            return self.admin_command(session, sql).map(|result| result.to_messages());
+        }
+        let result = self.run_statement(session, sql).await;
+        if let Some(mirror) = &self.mirror {
+            let primary = match &result {
+                _ if !mirror.compares_results() => None,
+                Ok(messages) => {
+                    QueryResult::from_messages(messages.iter().map(|(message_type, body)| (*message_type, &body[..])))
+                        .ok()
+                        .map(|result| Outcome::of_rows(&result))
+                }
+                Err(e) => Outcome::of_error(e),
+            };
+            mirror.submit(session.login(), &self.outgoing_sql(sql), primary);
+        }
+        result
+    }
+
+    async fn run_statement(&self, session: &mut Session, sql: &str) -> Result<Vec<(u8, BytesMut)>, PostgresError> {
+        let outgoing = self.outgoing_sql(sql);
+        let timeout = parse_hints(sql).timeout;
+        let deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+        let timed_out = || timeout_error(timeout.unwrap_or_default());
+
+        if session.transaction_lost() {
+            return end_lost_transaction(session, sql);
+        }
+        // Also drops a connection held on a primary that failed over
+        self.check_failover(session)?;
+        if let Some(connection) = session.take_connection() {
+            if let Err(e) = self.check_held_shard(session, sql, &connection.backend) {
+                session.hold_connection(connection);
+                return Err(e);
+            }
+            if self.replication_mode {
+                // Keep the transaction state in step with the statements
+                session.target_for(classify(sql));
+            }
+            return self
+                .run(session, connection, sql, &outgoing, true, deadline)
+                .await
+                .unwrap_or_else(|| Err(timed_out()));
+        }
+
+        if let Some(tag) = self.answer_unrouted(session, sql) {
+            return Ok(vec![(b'C', BytesMut::from(format!("{}\0", tag).as_str()))]);
+        }
+        if self.should_scatter(session, sql, &[]) {
+            return match until(deadline, self.scatter_gather(session, sql)).await {
+                Some(result) => result.map(|result| result.to_messages()),
+                None => Err(timed_out()),
+            };
+        }
+
+        let mut failed = Vec::new();
+        let mut backend = self.route_query(session, sql, &[]).await?;
+        loop {
+            let result = match until(deadline, ServerConnection::check_out(&backend, session.login())).await {
+                Some(Ok(mut connection)) => {
+                    match until(deadline, self.send_deferred_begin(session, &mut connection)).await {
+                        Some(Ok(())) => self
+                            .run(session, connection, sql, &outgoing, false, deadline)
+                            .await
+                            .unwrap_or_else(|| Err(timed_out())),
+                        Some(Err(e)) => Err(e),
+                        None => Err(timed_out()),
+                    }
+                }
+                Some(Err(e)) => Err(e),
+                None => Err(timed_out()),
+            };
+            if result.is_err() && session.deferred_begin().is_some() {
+                // The transaction failed before its BEGIN reached a server
+                session.connection_lost();
+            }
+            match result {
+                Err(e) if self.can_retry(session, sql, &e, failed.len() as u32, false) => {
+                    log::warn!("Read on {} failed, retrying on another host: {}", backend.host.host, e);
+                    failed.push(backend);
+                    backend = self.reroute(session, sql, &[], &failed).await?;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Sends the BEGIN a sharded session deferred until its first statement
+    /// was routed, see `answer_unrouted`.
+    async fn send_deferred_begin(
+        &self,
+        session: &mut Session,
+        connection: &mut ServerConnection,
+    ) -> Result<(), PostgresError> {
+        if let Some(begin) = session.deferred_begin() {
+            let begin = self.outgoing_sql(begin);
+            connection.client().simple_query_messages(&begin).await?;
+            session.begin_sent();
+        }
+        Ok(())
+    }
+
+    /// Runs `sql` on `connection`, then keeps the connection for the session
+    /// or gives it back. `held` tells whether the session already held it.
+    /// None when `deadline` passed first.
+    async fn run(
+        &self,
+        session: &mut Session,
+        mut connection: ServerConnection,
+        sql: &str,
+        outgoing: &str,
+        held: bool,
+        deadline: Option<Instant>,
+    ) -> Option<Result<Vec<(u8, BytesMut)>, PostgresError>> {
+        let started = Instant::now();
+        let result = match until(deadline, connection.client().simple_query_messages(outgoing)).await {
+            Some(result) => result,
+            None => {
+                // The statement may still be running; the connection goes with it
+                log::warn!("Statement on {} overran its timeout hint", connection.backend.host.host);
+                if held || session.in_transaction() {
+                    session.connection_lost();
+                }
+                return None;
+            }
+        };
+        let status = connection.client().transaction_status();
+        match &result {
+            Ok(_) => {
+                self.record_latency(&connection.backend.host.host, started.elapsed());
+                let committed = session.statement_completed(sql, status);
+                if !committed.is_empty() {
+                    self.query_cache.invalidate_tables(&session.login().database, &committed);
+                }
+                if connection.backend.is_primary() {
+                    if let Err(e) = self.capture_commit_lsn(session, connection.client()).await {
+                        log::warn!("Failed to read the commit position on {}: {}", connection.backend.host.host, e);
+                    }
+                }
+            }
+            // Server errors leave the connection usable
+            Err(PostgresError::Server { .. }) => session.statement_failed(status),
+            Err(_) => {
+                if held || session.in_transaction() {
+                    // The transaction or settings went away with the connection
+                    session.connection_lost();
+                }
+                return Some(result);
+            }
+        }
+
+        if status != TransactionStatus::Idle || session.has_settings() {
+            session.hold_connection(connection);
+        } else {
+            connection.release().await;
+        }
+        Some(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{config, FakeServer};
+    use lib_pgsqlcli::PostgresValue;
+
+    async fn router(server: &FakeServer) -> Router {
+        Router::new(&config(serde_json::json!({ "postgresql_hosts": [{ "host": server.host }] })))
+            .await
+            .unwrap()
+    }
+
+    /// The server connection a read ran on.
+    async fn connection_of(router: &Router, session: &mut Session, sql: &str) -> i32 {
+        match router.simple_query(session, sql).await.unwrap().rows[0][0].1 {
+            PostgresValue::Int32(connection) => connection,
+            ref other => panic!("unexpected value {:?}", other),
+        }
+    }
+
+    fn session() -> Session {
+        Session::new(Login::new("app", "app", None))
+    }
+
+    #[tokio::test]
+    async fn transactions_keep_their_connection() {
+        let server = FakeServer::start().await;
+        let router = router(&server).await;
+        let (mut first, mut second) = (session(), session());
+
+        router.simple_query(&mut first, "BEGIN").await.unwrap();
+        assert!(first.connection_host().is_some());
+        let inside = connection_of(&router, &mut first, "SELECT 1").await;
+        // Another session must not get the connection while the transaction is open
+        assert_ne!(connection_of(&router, &mut second, "SELECT 1").await, inside);
+        assert!(second.connection_host().is_none());
+        router.simple_query(&mut first, "INSERT INTO t VALUES (1)").await.unwrap();
+        assert_eq!(connection_of(&router, &mut first, "SELECT 2").await, inside);
+        router.simple_query(&mut first, "COMMIT").await.unwrap();
+        assert!(first.connection_host().is_none());
+
+        let backend = &router.backends()[0];
+        assert_eq!(backend.in_use(), 0);
+        // Both connections went back to the pool and serve the next reads
+        assert!([inside, connection_of(&router, &mut second, "SELECT 3").await].contains(&inside));
+    }
+
+    #[tokio::test]
+    async fn failed_transactions_keep_their_connection_until_rolled_back() {
+        let server = FakeServer::start().await;
+        let router = router(&server).await;
+        let mut session = session();
+
+        router.simple_query(&mut session, "BEGIN").await.unwrap();
+        let inside = connection_of(&router, &mut session, "SELECT 1").await;
+        assert!(router.simple_query(&mut session, "SELECT fail()").await.is_err());
+        assert!(session.connection_host().is_some());
+        assert!(router.simple_query(&mut session, "SELECT 2").await.is_err());
+        router.simple_query(&mut session, "ROLLBACK").await.unwrap();
+        assert!(session.connection_host().is_none());
+        assert_eq!(router.backends()[0].in_use(), 0);
+
+        assert_eq!(server.count("ROLLBACK"), 1);
+        assert!(server
+            .statements()
+            .iter()
+            .filter(|(_, sql)| sql != "BEGIN")
+            .all(|(connection, _)| *connection as i32 == inside));
+    }
+
+    #[tokio::test]
+    async fn timeout_hints_cancel_slow_statements() {
+        let server = FakeServer::start().await;
+        let router = router(&server).await;
+        let mut session = session();
+        let slow = "/* pgshield: timeout=50ms */ SELECT pg_sleep(5)";
+
+        let started = Instant::now();
+        match router.simple_query(&mut session, slow).await {
+            Err(PostgresError::Server { code, .. }) => assert_eq!(code, QUERY_CANCELED_SQLSTATE),
+            other => panic!("expected a timeout, got {:?}", other.map(|result| result.command_tag)),
+        }
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert_eq!(router.backends()[0].in_use(), 0);
+        assert!(router.simple_query(&mut session, "/* pgshield: timeout=5s */ SELECT 1").await.is_ok());
+    }
+
+    fn assert_failed_transaction(result: Result<QueryResult, PostgresError>) {
+        match result {
+            Err(PostgresError::Server { code, .. }) => assert_eq!(code, FAILED_TRANSACTION_SQLSTATE),
+            other => panic!("expected a failed transaction, got {:?}", other.map(|result| result.command_tag)),
+        }
+    }
+
+    #[tokio::test]
+    async fn timed_out_transactions_fail_until_rolled_back() {
+        let server = FakeServer::start().await;
+        let router = router(&server).await;
+        let mut session = session();
+
+        router.simple_query(&mut session, "BEGIN").await.unwrap();
+        router.simple_query(&mut session, "INSERT INTO t VALUES (1)").await.unwrap();
+        let slow = "/* pgshield: timeout=50ms */ SELECT pg_sleep(5)";
+        assert!(router.simple_query(&mut session, slow).await.is_err());
+        assert!(session.connection_host().is_none());
+        assert_eq!(router.backends()[0].in_use(), 0);
+
+        // The transaction went with its connection; nothing may run as if it had not
+        assert!(session.in_transaction());
+        let statements = server.statements().len();
+        assert_failed_transaction(router.simple_query(&mut session, "INSERT INTO t VALUES (2)").await);
+        assert_failed_transaction(router.simple_query(&mut session, "ROLLBACK TO SAVEPOINT s").await);
+        assert_eq!(server.statements().len(), statements);
+
+        assert_eq!(router.simple_query(&mut session, "COMMIT").await.unwrap().command_tag, "ROLLBACK");
+        assert!(!session.in_transaction());
+        router.simple_query(&mut session, "INSERT INTO t VALUES (3)").await.unwrap();
+        assert_eq!(server.count("INSERT INTO t VALUES (3)"), 1);
+    }
+
+    #[tokio::test]
+    async fn session_settings_keep_their_connection() {
+        let server = FakeServer::start().await;
+        let router = router(&server).await;
+        let (mut first, mut second) = (session(), session());
+
+        router.simple_query(&mut first, "SET search_path TO app").await.unwrap();
+        assert!(first.connection_host().is_some());
+        let pinned = connection_of(&router, &mut first, "SELECT 1").await;
+        assert_ne!(connection_of(&router, &mut second, "SELECT 1").await, pinned);
+        assert_eq!(connection_of(&router, &mut first, "SELECT 2").await, pinned);
+
+        router.simple_query(&mut first, "RESET ALL").await.unwrap();
+        assert!(first.connection_host().is_none());
+        assert_eq!(router.backends()[0].in_use(), 0);
+    }
+}
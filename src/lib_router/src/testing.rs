@@ -0,0 +1,272 @@
+//! A minimal PostgreSQL server for tests: trust authentication, the simple
+//! query protocol and transaction status tracking, with canned results.
+//!
+//! Reads return one row whose `connection` column is the number of the server
+//! connection that ran them; reads of `pg_database` list `postgres` and `app`. Statements containing `fail` raise an error and
+//! `pg_sleep(n)` waits `n` seconds before answering. `set_delay` slows down
+//! every answer. Health check probes are answered as `set_role` describes,
+//! as an idle primary by default.
+
+use bytes::{BufMut, BytesMut};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use lib_config::Config;
+use lib_query::lexer::{split_statements, tokenize};
+
+const SSL_REQUEST: u32 = 80877103;
+const BOOL_OID: u32 = 16;
+const INT4_OID: u32 = 23;
+const TEXT_OID: u32 = 25;
+const FLOAT8_OID: u32 = 701;
+
+pub(crate) struct FakeServer {
+    /// `address:port` to put in a host's configuration.
+    pub(crate) host: String,
+    statements: Arc<Mutex<Vec<(usize, String)>>>,
+    behaviour: Arc<Mutex<Behaviour>>,
+}
+
+/// How the server answers, changeable while it runs.
+#[derive(Clone, Copy)]
+struct Behaviour {
+    delay: Duration,
+    in_recovery: bool,
+    lsn: u64,
+    /// Seconds a replica's replay is behind.
+    replay_delay: f64,
+}
+
+impl FakeServer {
+    pub(crate) async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let host = listener.local_addr().unwrap().to_string();
+        let statements = Arc::new(Mutex::new(Vec::new()));
+        let behaviour = Arc::new(Mutex::new(Behaviour {
+            delay: Duration::ZERO,
+            in_recovery: false,
+            lsn: 0x1000000,
+            replay_delay: 0.0,
+        }));
+        let (log, behaviours) = (Arc::clone(&statements), Arc::clone(&behaviour));
+        tokio::spawn(async move {
+            let mut connections = 0;
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve(stream, connections, Arc::clone(&log), Arc::clone(&behaviours)));
+                connections += 1;
+            }
+        });
+        FakeServer { host, statements, behaviour }
+    }
+
+    /// Waits `delay` before answering each query from now on.
+    pub(crate) fn set_delay(&self, delay: Duration) {
+        self.behaviour.lock().unwrap().delay = delay;
+    }
+
+    /// Reports the server as a primary at WAL position `lsn`, or as a replica
+    /// that replayed up to `lsn` and is `replay_delay` seconds behind.
+    pub(crate) fn set_role(&self, in_recovery: bool, lsn: u64, replay_delay: f64) {
+        let mut behaviour = self.behaviour.lock().unwrap();
+        behaviour.in_recovery = in_recovery;
+        behaviour.lsn = lsn;
+        behaviour.replay_delay = replay_delay;
+    }
+
+    /// Queries received so far, with the number of the connection each came on.
+    pub(crate) fn statements(&self) -> Vec<(usize, String)> {
+        self.statements.lock().unwrap().clone()
+    }
+
+    /// How many times `sql` was received.
+    pub(crate) fn count(&self, sql: &str) -> usize {
+        self.statements().iter().filter(|(_, received)| received == sql).count()
+    }
+}
+
+/// A configuration for tests, with `overrides` replacing the defaults.
+pub(crate) fn config(overrides: serde_json::Value) -> Config {
+    let mut config = serde_json::json!({
+        "postgresql_hosts": [],
+        "listen_port": "0",
+        "max_conns": 4,
+        "cache_ttl": 60,
+        "health_check_interval": 60,
+        "replication_mode": false,
+        "query_cache_ttl": 60,
+        "logging": { "log_to_file": false, "log_to_console": false, "log_to_syslog": false },
+    });
+    if let (Some(config), serde_json::Value::Object(overrides)) = (config.as_object_mut(), overrides) {
+        config.extend(overrides);
+    }
+    serde_json::from_value(config).unwrap()
+}
+
+fn message(out: &mut BytesMut, message_type: u8, body: &[u8]) {
+    out.put_u8(message_type);
+    out.put_u32(body.len() as u32 + 4);
+    out.put_slice(body);
+}
+
+fn row(out: &mut BytesMut, column: &str, type_oid: u32, value: &str) {
+    rows(out, column, type_oid, &[value]);
+}
+
+fn rows(out: &mut BytesMut, column: &str, type_oid: u32, values: &[&str]) {
+    describe(out, &[(column, type_oid)]);
+    for value in values {
+        data_row(out, &[value]);
+    }
+}
+
+fn describe(out: &mut BytesMut, columns: &[(&str, u32)]) {
+    let mut description = BytesMut::new();
+    description.put_i16(columns.len() as i16);
+    for (column, type_oid) in columns {
+        description.put_slice(column.as_bytes());
+        description.put_u8(0);
+        description.put_u32(0);
+        description.put_i16(0);
+        description.put_u32(*type_oid);
+        description.put_i16(-1);
+        description.put_i32(-1);
+        description.put_i16(0);
+    }
+    message(out, b'T', &description);
+}
+
+fn data_row(out: &mut BytesMut, values: &[&str]) {
+    let mut data = BytesMut::new();
+    data.put_i16(values.len() as i16);
+    for value in values {
+        data.put_i32(value.len() as i32);
+        data.put_slice(value.as_bytes());
+    }
+    message(out, b'D', &data);
+}
+
+fn format_lsn(lsn: u64) -> String {
+    format!("{:X}/{:X}", lsn >> 32, lsn & 0xFFFF_FFFF)
+}
+
+fn error(out: &mut BytesMut, code: &str, text: &str) {
+    message(out, b'E', format!("SERROR\0C{}\0M{}\0\0", code, text).as_bytes());
+}
+
+/// Answers one statement, updating the transaction `status`. False when it failed.
+async fn respond(sql: &str, connection: usize, behaviour: Behaviour, status: &mut u8, out: &mut BytesMut) -> bool {
+    let tokens = tokenize(sql);
+    let first = tokens.first().and_then(|t| t.ident()).unwrap_or_default().to_string();
+    let lower = sql.to_lowercase();
+
+    if *status == b'E' && first != "rollback" && first != "abort" {
+        error(out, "25P02", "current transaction is aborted");
+        return false;
+    }
+    if let Some(seconds) = lower.split("pg_sleep(").nth(1).and_then(|rest| rest.split(')').next()) {
+        tokio::time::sleep(Duration::from_secs_f64(seconds.trim().parse().unwrap_or(0.0))).await;
+    }
+    if lower.contains("fail") {
+        error(out, "P0001", "failed on request");
+        if *status == b'T' {
+            *status = b'E';
+        }
+        return false;
+    }
+
+    let tag = match first.as_str() {
+        "begin" | "start" => {
+            *status = b'T';
+            "BEGIN".to_string()
+        }
+        "commit" | "end" => {
+            *status = b'I';
+            "COMMIT".to_string()
+        }
+        "rollback" | "abort" if tokens.iter().any(|t| t.is_word("to")) => {
+            *status = b'T';
+            "ROLLBACK".to_string()
+        }
+        "rollback" | "abort" => {
+            *status = b'I';
+            "ROLLBACK".to_string()
+        }
+        "select" if lower.contains("pg_is_in_recovery()") => {
+            describe(out, &[("recovery", BOOL_OID), ("lsn", TEXT_OID), ("delay", FLOAT8_OID)]);
+            let in_recovery = if behaviour.in_recovery { "t" } else { "f" };
+            data_row(out, &[in_recovery, &format_lsn(behaviour.lsn), &behaviour.replay_delay.to_string()]);
+            "SELECT 1".to_string()
+        }
+        "select" | "with" | "table" | "show" if lower.contains("_lsn()") => {
+            row(out, "lsn", TEXT_OID, &format_lsn(behaviour.lsn));
+            "SELECT 1".to_string()
+        }
+        "select" if lower.contains("from pg_database") => {
+            rows(out, "datname", TEXT_OID, &["app", "postgres"]);
+            "SELECT 2".to_string()
+        }
+        "select" | "with" | "table" | "show" => {
+            row(out, "connection", INT4_OID, &connection.to_string());
+            "SELECT 1".to_string()
+        }
+        "insert" => "INSERT 0 1".to_string(),
+        "update" | "delete" => format!("{} 1", first.to_uppercase()),
+        "discard" => "DISCARD ALL".to_string(),
+        _ => first.to_uppercase(),
+    };
+    message(out, b'C', format!("{}\0", tag).as_bytes());
+    true
+}
+
+async fn serve(
+    mut stream: TcpStream,
+    connection: usize,
+    log: Arc<Mutex<Vec<(usize, String)>>>,
+    behaviour: Arc<Mutex<Behaviour>>,
+) -> std::io::Result<()> {
+    // Startup, declining SSL
+    loop {
+        let length = stream.read_u32().await? as usize;
+        if !(8..=10_000).contains(&length) {
+            // Like the server, hang up on a malformed startup packet
+            return Ok(());
+        }
+        let mut body = vec![0; length - 4];
+        stream.read_exact(&mut body).await?;
+        if body[..4] == SSL_REQUEST.to_be_bytes() {
+            stream.write_all(b"N").await?;
+            continue;
+        }
+        break;
+    }
+    let mut out = BytesMut::new();
+    message(&mut out, b'R', &0i32.to_be_bytes());
+    message(&mut out, b'Z', b"I");
+    stream.write_all(&out).await?;
+
+    let mut status = b'I';
+    loop {
+        let message_type = stream.read_u8().await?;
+        let length = stream.read_u32().await? as usize;
+        let mut body = vec![0; length - 4];
+        stream.read_exact(&mut body).await?;
+        if message_type == b'X' {
+            return Ok(());
+        }
+
+        let sql = String::from_utf8_lossy(body.strip_suffix(&[0]).unwrap_or(&body)).into_owned();
+        log.lock().unwrap().push((connection, sql.clone()));
+        let behaviour = *behaviour.lock().unwrap();
+        tokio::time::sleep(behaviour.delay).await;
+        let mut out = BytesMut::new();
+        for statement in split_statements(&sql) {
+            if !respond(statement, connection, behaviour, &mut status, &mut out).await {
+                break;
+            }
+        }
+        message(&mut out, b'Z', &[status]);
+        stream.write_all(&out).await?;
+    }
+}